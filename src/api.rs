@@ -1,18 +1,60 @@
 use std::{borrow::Cow, marker::PhantomData, sync::Arc};
 
 use async_stream::try_stream;
-use futures::stream::BoxStream;
+use futures::{stream::BoxStream, TryStreamExt};
 use serde::{de::DeserializeOwned, Serialize};
 
 use crate::{
-    backend_api::{CuttleBackend, PutOptions},
+    backend_api::{prefixed, CuttleBackend, PutOptions, RawEntry},
     builder::find_matching_backend,
     common::{
-        cleanup::{Cleaner, CleanerOptions},
+        cleanup::{Cleaner, CleanerHandle, CleanerOptions},
         CuttlestoreError,
     },
 };
 
+/// An entry found while scanning the store with
+/// [scan_entries](crate::Cuttlestore::scan_entries).
+#[derive(Debug)]
+pub enum ScanEntry<Value> {
+    /// The value was found and decoded successfully.
+    Value(Value),
+    /// The value was found, but could not be decoded.
+    ///
+    /// This usually means that the value was stored with a different type, or
+    /// that the stored data is corrupted. The raw bytes are included as they
+    /// were found in the store.
+    Undecodable {
+        raw: Vec<u8>,
+        error: CuttlestoreError,
+    },
+    /// The value had expired, and has been removed from the store.
+    ///
+    /// Only some backends report expired values. Backends with built-in TTL
+    /// support, and backends that filter expired values out before returning
+    /// them, will never produce this.
+    Expired,
+}
+
+/// A change made to the store, reported by
+/// [watch](crate::Cuttlestore::watch).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Change {
+    /// A value was put into the store under this key.
+    Put(String),
+    /// The value under this key was deleted.
+    Delete(String),
+}
+
+impl Change {
+    /// The key that was changed.
+    pub fn key(&self) -> &str {
+        match self {
+            Change::Put(key) | Change::Delete(key) => key,
+        }
+    }
+}
+
 #[derive(Clone)]
 /// A basic key-value store. This is the primary API you'll interact with.
 ///
@@ -30,12 +72,10 @@ use crate::{
 pub struct Cuttlestore<Value: Serialize + DeserializeOwned + Send + Sync> {
     /// The actual store backend.
     pub(crate) store: Arc<Box<dyn CuttleBackend + Send + Sync>>,
-    #[allow(dead_code)]
-    /// For backends that require it, a cleaner is created which will scan the
-    /// store periodically to drop expired entries.
+    /// For backends that require it, a cleaner is created which will sweep
+    /// the store periodically to drop expired entries.
     ///
-    /// While the cleaner property is not used directly, we need to keep the
-    /// cleaner around because it will stop when dropped.
+    /// We need to keep the cleaner around because it will stop when dropped.
     pub(crate) cleaner: Option<Arc<Cleaner>>,
 
     /// A placeholder to hide that Value is not used within the struct. While
@@ -47,6 +87,10 @@ pub struct Cuttlestore<Value: Serialize + DeserializeOwned + Send + Sync> {
     /// If exists, the keys for all operations on this store will be prefix with
     /// this value .
     pub(crate) prefix: Option<String>,
+
+    /// If exists, undecodable entries found by `scan_entries` are moved under
+    /// this prefix instead of being left in place.
+    pub(crate) quarantine: Option<String>,
 }
 
 impl<Value: Serialize + DeserializeOwned + Send + Sync> std::fmt::Debug for Cuttlestore<Value> {
@@ -102,16 +146,29 @@ impl<Value: Serialize + DeserializeOwned + Send + Sync> Cuttlestore<Value> {
             store,
             cleaner,
             prefix: None,
+            quarantine: None,
             phantom: PhantomData,
         })
     }
 
-    /// Prefixes the key, if one is configured for this store.
-    fn key<'a>(&self, key: &'a str) -> Cow<'a, str> {
-        match &self.prefix {
-            Some(prefix) => Cow::Owned(format!("{prefix}:{key}")),
-            None => Cow::Borrowed(key),
-        }
+    /// The cleaner that removes expired values from the store.
+    ///
+    /// This is `None` if the backend removes expired values by itself. The
+    /// handle can start a sweep right away, and report statistics about the
+    /// sweeps so far.
+    ///
+    /// ```
+    /// use cuttlestore::Cuttlestore;
+    ///
+    /// # tokio_test::block_on(async {
+    /// let store: Cuttlestore<String> = Cuttlestore::new("in-memory").await.unwrap();
+    /// let cleaner = store.cleaner().unwrap();
+    /// cleaner.sweep_now();
+    /// println!("Removed {} values so far", cleaner.stats().total_removed);
+    /// # })
+    /// ```
+    pub fn cleaner(&self) -> Option<CleanerHandle> {
+        self.cleaner.as_ref().map(|cleaner| cleaner.handle())
     }
 
     /// Strip a prefix from the key, if one is configured for this store.
@@ -130,6 +187,38 @@ impl<Value: Serialize + DeserializeOwned + Send + Sync> Cuttlestore<Value> {
         }
     }
 
+    /// Move an undecodable entry to the dead-letter prefix, if one is
+    /// configured for this store.
+    async fn quarantine(
+        &self,
+        key: &str,
+        prefixed_key: &str,
+        raw: &[u8],
+    ) -> Result<(), CuttlestoreError> {
+        if let Some(quarantine) = &self.quarantine {
+            self.store
+                .put(
+                    Cow::Owned(format!("{quarantine}:{prefixed_key}")),
+                    raw,
+                    PutOptions::default(),
+                )
+                .await?;
+            self.store.delete_in(self.prefix.as_deref(), key).await?;
+        }
+        Ok(())
+    }
+
+    /// Checks if the key is a dead-letter entry created by `quarantine`.
+    fn is_quarantined(&self, prefixed_key: &str) -> bool {
+        match &self.quarantine {
+            Some(quarantine) => prefixed_key
+                .strip_prefix(quarantine.as_str())
+                .map(|key| key.starts_with(':'))
+                .unwrap_or(false),
+            None => false,
+        }
+    }
+
     /// Place a value into the store with the default settings.
     pub async fn put<Key: AsRef<str>>(
         &self,
@@ -148,28 +237,34 @@ impl<Value: Serialize + DeserializeOwned + Send + Sync> Cuttlestore<Value> {
     ) -> Result<(), CuttlestoreError> {
         let payload = bincode::serde::encode_to_vec(value, bincode::config::legacy())?;
         self.store
-            .put(self.key(key.as_ref()), &payload[..], options)
+            .put_in(self.prefix.as_deref(), key.as_ref(), &payload[..], options)
             .await
     }
 
     /// Remove a value from the store.
     pub async fn delete<Key: AsRef<str>>(&self, key: Key) -> Result<(), CuttlestoreError> {
-        self.store.delete(self.key(key.as_ref())).await
+        self.store
+            .delete_in(self.prefix.as_deref(), key.as_ref())
+            .await
     }
 
     /// Get a value from the store.
     ///
     /// This operation is guaranteed to never return expired values.
     pub async fn get<Key: AsRef<str>>(&self, key: Key) -> Result<Option<Value>, CuttlestoreError> {
-        let payload = self.store.get(self.key(key.as_ref())).await?;
-        let value = payload
-            .map(|payload| {
-                let (value, _): (Value, _) =
-                    bincode::serde::decode_from_slice(&payload[..], bincode::config::legacy())?;
-                Ok::<Value, CuttlestoreError>(value)
-            })
-            .transpose()?;
-        Ok(value)
+        let key = key.as_ref();
+        let payload = match self.store.get_entry_in(self.prefix.as_deref(), key).await? {
+            Some(RawEntry::Live(payload)) => payload,
+            Some(RawEntry::Corrupt { raw, .. }) => {
+                let prefixed_key = prefixed(self.prefix.as_deref(), key);
+                self.quarantine(key, &prefixed_key, &raw[..]).await?;
+                return Ok(None);
+            }
+            Some(RawEntry::Expired) | None => return Ok(None),
+        };
+        let (value, _): (Value, _) =
+            bincode::serde::decode_from_slice(&payload[..], bincode::config::legacy())?;
+        Ok(Some(value))
     }
 
     /// Get a stream of all the key and value pairs in the store.
@@ -177,17 +272,29 @@ impl<Value: Serialize + DeserializeOwned + Send + Sync> Cuttlestore<Value> {
     /// This operation is guaranteed to never return expired values.
     ///
     /// This is a very inefficient operation as it has to iterate over all the
-    /// values in the store. (for now) using multiple stores connected to the
-    /// same backing storage won't improve the performance either, the scan will
-    /// iterate over values of all stores and discard ones for other stores.
+    /// values in the store. With most backends, using multiple stores connected
+    /// to the same backing storage won't improve the performance either, the
+    /// scan will iterate over values of all stores and discard ones for other
+    /// stores. Backends that keep their keys ordered (currently redb) only read
+    /// the keys under the prefix of the store.
     pub async fn scan(
         &self,
     ) -> Result<BoxStream<'_, Result<(String, Value), CuttlestoreError>>, CuttlestoreError> {
-        let stream = self.store.scan().await?;
+        let stream = match &self.prefix {
+            Some(prefix) => {
+                self.store
+                    .scan_prefix(Cow::Owned(format!("{prefix}:")))
+                    .await?
+            }
+            None => self.store.scan().await?,
+        };
 
         Ok(Box::pin(try_stream! {
             for await pair in stream {
                 let (key, payload) = pair?;
+                if self.is_quarantined(&key) {
+                    continue;
+                }
                 if let Some(key) = self.strip_prefix(key) {
                     let (value, _): (Value, _) =
                         bincode::serde::decode_from_slice(&payload[..], bincode::config::legacy())?;
@@ -197,4 +304,134 @@ impl<Value: Serialize + DeserializeOwned + Send + Sync> Cuttlestore<Value> {
             }
         }))
     }
+
+    /// Count the values in the store.
+    ///
+    /// If the store was made with a prefix, only the values under that prefix
+    /// are counted. Most backends have to scan the store to count the values,
+    /// so this is as slow as `scan`. Redis with `storage=hash` counts the
+    /// values of a store with a prefix directly.
+    ///
+    /// Entries moved aside by
+    /// [quarantine_undecodable](crate::CuttlestoreBuilder::quarantine_undecodable)
+    /// are not counted.
+    pub async fn count(&self) -> Result<u64, CuttlestoreError> {
+        match (&self.prefix, &self.quarantine) {
+            // The quarantined entries are mixed in with the values
+            (None, Some(quarantine)) => {
+                let all = self.store.count(None).await?;
+                let quarantined = self.store.count(Some(quarantine)).await?;
+                Ok(all.saturating_sub(quarantined))
+            }
+            (prefix, _) => self.store.count(prefix.as_deref()).await,
+        }
+    }
+
+    /// Remove all the values from the store.
+    ///
+    /// If the store was made with a prefix, only the values under that prefix
+    /// are removed. Otherwise everything in the backing storage is removed,
+    /// including the values of other stores sharing it. Entries moved aside by
+    /// [quarantine_undecodable](crate::CuttlestoreBuilder::quarantine_undecodable)
+    /// are kept.
+    pub async fn clear(&self) -> Result<(), CuttlestoreError> {
+        if self.prefix.is_some() || self.quarantine.is_none() {
+            return self.store.clear(self.prefix.as_deref()).await;
+        }
+        // The quarantined entries are mixed in with the values, so they have
+        // to be picked out one at a time. Collect the keys first, some
+        // backends can't delete while a scan is still running.
+        let keys: Vec<String> = self
+            .store
+            .scan()
+            .await?
+            .map_ok(|(key, _)| key)
+            .try_filter(|key| futures::future::ready(!self.is_quarantined(key)))
+            .try_collect()
+            .await?;
+        for key in keys {
+            self.store.delete_in(None, &key).await?;
+        }
+        Ok(())
+    }
+
+    /// Get a stream of the changes made to the store, by this process or any
+    /// other one using the same backing storage.
+    ///
+    /// Only changes made after the stream is created are reported. Values that
+    /// expire are not reported as deleted.
+    ///
+    /// This is only supported by some backends, others return a
+    /// `CuttlestoreError::Unsupported` error. Currently only Postgres supports
+    /// it, and only if it's enabled in the connection string.
+    pub async fn watch(
+        &self,
+    ) -> Result<BoxStream<'_, Result<Change, CuttlestoreError>>, CuttlestoreError> {
+        let stream = self.store.watch().await?;
+
+        Ok(Box::pin(try_stream! {
+            for await change in stream {
+                let change = match change? {
+                    Change::Put(key) => self.strip_prefix(key).map(Change::Put),
+                    Change::Delete(key) => self.strip_prefix(key).map(Change::Delete),
+                };
+                if let Some(change) = change {
+                    yield change;
+                }
+            }
+        }))
+    }
+
+    /// Get a stream of all the entries in the store, without failing on
+    /// entries that can't be decoded.
+    ///
+    /// Unlike [scan](crate::Cuttlestore::scan), a value that fails to decode
+    /// does not produce an error. Instead it is reported as
+    /// [ScanEntry::Undecodable] along with the raw bytes, and the scan carries
+    /// on. Some backends will also report the expired values they encounter
+    /// as [ScanEntry::Expired].
+    ///
+    /// If the store was configured with
+    /// [quarantine_undecodable](crate::CuttlestoreBuilder::quarantine_undecodable),
+    /// undecodable entries are moved to the dead-letter prefix as they are
+    /// found.
+    ///
+    /// This has the same performance characteristics as `scan`.
+    pub async fn scan_entries(
+        &self,
+    ) -> Result<BoxStream<'_, Result<(String, ScanEntry<Value>), CuttlestoreError>>, CuttlestoreError>
+    {
+        let stream = self.store.scan_entries().await?;
+
+        Ok(Box::pin(try_stream! {
+            for await pair in stream {
+                let (prefixed_key, entry) = pair?;
+                if self.is_quarantined(&prefixed_key) {
+                    continue;
+                }
+                let key = match self.strip_prefix(prefixed_key.clone()) {
+                    Some(key) => key,
+                    None => continue,
+                };
+                let entry = match entry {
+                    RawEntry::Live(payload) => {
+                        match bincode::serde::decode_from_slice(&payload[..], bincode::config::legacy()) {
+                            Ok((value, _)) => ScanEntry::Value(value),
+                            Err(error) => {
+                                self.quarantine(&key, &prefixed_key, &payload[..]).await?;
+                                ScanEntry::Undecodable { raw: payload, error: error.into() }
+                            }
+                        }
+                    }
+                    RawEntry::Corrupt { raw, error } => {
+                        self.quarantine(&key, &prefixed_key, &raw[..]).await?;
+                        ScanEntry::Undecodable { raw, error }
+                    }
+                    RawEntry::Expired => ScanEntry::Expired,
+                };
+
+                yield (key, entry);
+            }
+        }))
+    }
 }
@@ -4,9 +4,12 @@ use std::{
 };
 
 use async_trait::async_trait;
-use futures::stream::BoxStream;
+use futures::{stream::BoxStream, StreamExt, TryStreamExt};
 
-use crate::common::{get_system_time, CuttlestoreError};
+use crate::{
+    common::{get_system_time, CuttlestoreError},
+    Change,
+};
 
 /// Options to use when placing a value into a store.
 ///
@@ -63,6 +66,35 @@ impl Default for PutOptions {
     }
 }
 
+/// A single entry found by the backend while scanning the store.
+///
+/// Unlike `scan`, which only yields live values, this lets the backend report
+/// entries it had to skip so the caller can decide what to do with them.
+// Not every backend reports expired or corrupt entries, so depending on the
+// enabled features some variants may never be constructed.
+#[allow(dead_code)]
+pub(crate) enum RawEntry {
+    /// A live value.
+    Live(Vec<u8>),
+    /// The entry had expired. The backend has already removed it.
+    Expired,
+    /// The backend was unable to decode its own storage format for this entry.
+    /// The raw bytes are passed along as they were found, and the entry is left
+    /// in place.
+    Corrupt {
+        raw: Vec<u8>,
+        error: CuttlestoreError,
+    },
+}
+
+/// The key for `key` in the store with this prefix.
+pub(crate) fn prefixed<'a>(prefix: Option<&str>, key: &'a str) -> Cow<'a, str> {
+    match prefix {
+        Some(prefix) => Cow::Owned(format!("{prefix}:{key}")),
+        None => Cow::Borrowed(key),
+    }
+}
+
 /// The common API for Cuttlestore backends.
 ///
 /// This API defines the contract between Cuttlestore and the backends. Backends
@@ -110,6 +142,206 @@ pub(crate) trait CuttleBackend {
     async fn scan(
         &self,
     ) -> Result<BoxStream<Result<(String, Vec<u8>), CuttlestoreError>>, CuttlestoreError>;
+    /// Walk through the key-value pairs with keys that start with `prefix`.
+    ///
+    /// This has the same requirements as `scan`. The default implementation
+    /// scans the whole store and filters the keys. Backends that keep their
+    /// keys ordered SHOULD override this to only read the matching range.
+    async fn scan_prefix<'a>(
+        &self,
+        prefix: Cow<'a, str>,
+    ) -> Result<BoxStream<Result<(String, Vec<u8>), CuttlestoreError>>, CuttlestoreError> {
+        let prefix = prefix.into_owned();
+        let stream = self.scan().await?;
+        Ok(Box::pin(stream.try_filter(move |(key, _)| {
+            futures::future::ready(key.starts_with(&prefix))
+        })))
+    }
+    /// Get a value out of the store with this prefix.
+    ///
+    /// Stores go through `get_in`, `put_in` and `delete_in` rather than `get`,
+    /// `put` and `delete`. The default implementations use `prefix:key` as
+    /// the key. Backends that keep each prefix separately MAY override all
+    /// three, and then MUST still return these pairs from `scan` and
+    /// `scan_prefix` with `prefix:key` as the key.
+    async fn get_in<'a>(
+        &self,
+        prefix: Option<&'a str>,
+        key: &'a str,
+    ) -> Result<Option<Vec<u8>>, CuttlestoreError> {
+        self.get(prefixed(prefix, key)).await
+    }
+    /// Put a value into the store with this prefix. See `get_in`.
+    async fn put_in<'a>(
+        &self,
+        prefix: Option<&'a str>,
+        key: &'a str,
+        value: &[u8],
+        options: PutOptions,
+    ) -> Result<(), CuttlestoreError> {
+        self.put(prefixed(prefix, key), value, options).await
+    }
+    /// Delete a value from the store with this prefix. See `get_in`.
+    async fn delete_in<'a>(
+        &self,
+        prefix: Option<&'a str>,
+        key: &'a str,
+    ) -> Result<(), CuttlestoreError> {
+        self.delete(prefixed(prefix, key)).await
+    }
+    /// Get the entry for a key out of the store with this prefix, including
+    /// a corrupt one that `get_in` would skip.
+    ///
+    /// Backends that can detect corrupt entries SHOULD override this to
+    /// report them, and leave them in place. The default implementation only
+    /// reports the live value that `get_in` returns.
+    async fn get_entry_in<'a>(
+        &self,
+        prefix: Option<&'a str>,
+        key: &'a str,
+    ) -> Result<Option<RawEntry>, CuttlestoreError> {
+        Ok(self.get_in(prefix, key).await?.map(RawEntry::Live))
+    }
+    /// Count the pairs with this prefix, or all the pairs in the store if
+    /// there is no prefix.
+    ///
+    /// The default implementation scans the pairs. Backends that can count
+    /// them directly SHOULD override this.
+    async fn count<'a>(&self, prefix: Option<&'a str>) -> Result<u64, CuttlestoreError> {
+        count_by_scan(self, prefix).await
+    }
+    /// Delete the pairs with this prefix, or all the pairs in the store if
+    /// there is no prefix.
+    ///
+    /// The default implementation scans for the keys, then deletes them one
+    /// at a time. Backends that can delete them all at once SHOULD override
+    /// this.
+    async fn clear<'a>(&self, prefix: Option<&'a str>) -> Result<(), CuttlestoreError> {
+        clear_by_scan(self, prefix).await
+    }
+    /// Walk through all the entries in the store, including the ones that
+    /// `scan` would silently skip.
+    ///
+    /// Backends that can detect expired or corrupt entries during a scan
+    /// SHOULD override this to report them. The default implementation only
+    /// reports the live values that `scan` returns.
+    async fn scan_entries(
+        &self,
+    ) -> Result<BoxStream<Result<(String, RawEntry), CuttlestoreError>>, CuttlestoreError> {
+        let stream = self.scan().await?;
+        Ok(Box::pin(stream.map(|pair| {
+            pair.map(|(key, value)| (key, RawEntry::Live(value)))
+        })))
+    }
+    /// Get a stream of the changes made to the store.
+    ///
+    /// Backends that can't be watched for changes MUST return an
+    /// `Unsupported` error, which is what the default implementation does.
+    async fn watch(
+        &self,
+    ) -> Result<BoxStream<'static, Result<Change, CuttlestoreError>>, CuttlestoreError> {
+        Err(CuttlestoreError::Unsupported(
+            self.name(),
+            "watching for changes",
+        ))
+    }
+    /// Delete the expired pairs in the store, returning how many were
+    /// deleted.
+    ///
+    /// This is what the external cleaner runs. If there is a limit, the
+    /// backend SHOULD stop once it has deleted that many pairs, leaving the
+    /// rest for the next sweep. Backends that delete pairs in batches MAY go
+    /// over the limit by up to one batch.
+    ///
+    /// Backends that require an external cleaner MUST override this. The
+    /// default implementation returns an `Unsupported` error, since only the
+    /// backend knows which of its pairs have expired.
+    async fn purge_expired(&self, _limit: Option<u64>) -> Result<u64, CuttlestoreError> {
+        Err(CuttlestoreError::Unsupported(
+            self.name(),
+            "purging expired pairs",
+        ))
+    }
+    /// Take the lease stored under `key` for `holder`, or renew it if
+    /// `holder` already has it, so that it lasts for another `ttl` seconds.
+    /// Returns whether `holder` has the lease now.
+    ///
+    /// The cleaners sharing a store use this to pick which one sweeps it. A
+    /// lease that has expired can be taken by anyone. The lease MUST be kept
+    /// apart from the pairs in the store, so that it doesn't show up in scans
+    /// and isn't removed by `clear`.
+    ///
+    /// Backends that can be shared by several processes MUST override this.
+    /// Their lease MUST only be replaced if it has expired or `holder`
+    /// already has it, checked in the same step as the write, so that only
+    /// one holder can win it. The default implementation always gives the
+    /// lease to `holder`, which is only right for backends that a single
+    /// process has open.
+    async fn take_lease<'a>(
+        &self,
+        _key: Cow<'a, str>,
+        _holder: &'a str,
+        _ttl: u64,
+    ) -> Result<bool, CuttlestoreError> {
+        Ok(true)
+    }
+}
+
+/// Count the pairs with this prefix by scanning them. This is the default
+/// `count`, for backends that only count some prefixes directly.
+pub(crate) async fn count_by_scan<B: CuttleBackend + Sync + ?Sized>(
+    backend: &B,
+    prefix: Option<&str>,
+) -> Result<u64, CuttlestoreError> {
+    let mut pairs = match prefix {
+        Some(prefix) => {
+            backend
+                .scan_prefix(Cow::Owned(format!("{prefix}:")))
+                .await?
+        }
+        None => backend.scan().await?,
+    };
+    let mut count = 0;
+    while let Some(pair) = pairs.next().await {
+        pair?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+/// Delete the pairs with this prefix by scanning for them. This is the
+/// default `clear`, for backends that only clear some prefixes directly.
+pub(crate) async fn clear_by_scan<B: CuttleBackend + Sync + ?Sized>(
+    backend: &B,
+    prefix: Option<&str>,
+) -> Result<(), CuttlestoreError> {
+    // Collect the keys first, some backends can't delete while a scan is
+    // still running.
+    let keys: Vec<String> = match prefix {
+        Some(prefix) => {
+            backend
+                .scan_prefix(Cow::Owned(format!("{prefix}:")))
+                .await?
+        }
+        None => backend.scan().await?,
+    }
+    .map_ok(|(key, _)| key)
+    .try_collect()
+    .await?;
+    for key in keys {
+        let key = match prefix {
+            Some(prefix) => match key
+                .strip_prefix(prefix)
+                .and_then(|key| key.strip_prefix(':'))
+            {
+                Some(key) => key.to_string(),
+                None => continue,
+            },
+            None => key,
+        };
+        backend.delete_in(prefix, &key).await?;
+    }
+    Ok(())
 }
 
 #[cfg(test)]
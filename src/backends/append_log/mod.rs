@@ -0,0 +1,555 @@
+//! An embedded store that keeps everything in a single append-only log file.
+//!
+//! Every `put` and `delete` appends a record to the end of the file, and an
+//! in-memory index maps each key to the offset of its latest record. Writes
+//! are a single append, and reads are a single positioned read, which keeps
+//! both fast.
+//!
+//! Records that have been overwritten, deleted or have expired are left behind
+//! in the file as garbage. Once enough garbage builds up, the live records are
+//! copied into a fresh file in the background, which then replaces the log.
+
+use async_stream::try_stream;
+use async_trait::async_trait;
+use futures::stream::BoxStream;
+use lazy_regex::regex_captures;
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    fs::TryLockError,
+    io::{ErrorKind, SeekFrom},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use tokio::{
+    fs::{File, OpenOptions},
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt, BufReader, BufWriter},
+    sync::Mutex,
+};
+
+use crate::{
+    backend_api::{CuttleBackend, PutOptions},
+    common::{fnv1a, get_system_time, CuttlestoreError},
+};
+
+mod record;
+
+use record::{Record, HEADER_LEN};
+
+/// Don't bother compacting until there is at least this much garbage.
+const COMPACTION_MIN_GARBAGE: u64 = 1024 * 1024;
+
+/// Where the latest record for a key is in the log.
+#[derive(Debug, Clone, Copy)]
+struct Entry {
+    offset: u64,
+    /// The length of the whole record, including the frame header.
+    len: u64,
+    live_until: Option<u64>,
+}
+
+impl Entry {
+    fn is_expired(&self, now: u64) -> bool {
+        self.live_until.is_some_and(|live_until| live_until < now)
+    }
+}
+
+/// Maps keys to their records, and keeps track of how much of the log is
+/// garbage.
+#[derive(Default)]
+struct Index {
+    entries: HashMap<String, Entry>,
+    garbage: u64,
+}
+
+impl Index {
+    /// Update the index with a record found at this offset.
+    fn apply(&mut self, record: &Record, entry: Entry, now: u64) {
+        if let Some(old) = self.entries.remove(record.key) {
+            self.garbage += old.len;
+        }
+        if record.payload.is_none() || entry.is_expired(now) {
+            // Deletes are only needed until the log is compacted
+            self.garbage += entry.len;
+        } else {
+            self.entries.insert(record.key.to_string(), entry);
+        }
+    }
+
+    /// Drop expired keys from the index, returning how many there were.
+    fn drop_expired(&mut self, now: u64, limit: Option<u64>) -> u64 {
+        let mut removed = 0;
+        let mut garbage = 0;
+        self.entries.retain(|_, entry| {
+            if entry.is_expired(now) && limit.is_none_or(|limit| removed < limit) {
+                removed += 1;
+                garbage += entry.len;
+                false
+            } else {
+                true
+            }
+        });
+        self.garbage += garbage;
+        removed
+    }
+}
+
+struct State {
+    /// The log, opened for reading and appending.
+    file: File,
+    /// The log again, for `get` to read from without holding the lock.
+    reader: Arc<std::fs::File>,
+    /// The length of the log.
+    len: u64,
+    index: Index,
+    compacting: bool,
+}
+
+struct Inner {
+    path: PathBuf,
+    state: Mutex<State>,
+    /// Held while the store is open, so other processes can't use the same
+    /// log at the same time.
+    #[allow(dead_code)]
+    lock: std::fs::File,
+}
+
+pub(crate) struct AppendLogBackend {
+    inner: Arc<Inner>,
+}
+
+impl AppendLogBackend {
+    async fn new(path: &str) -> Result<Self, CuttlestoreError> {
+        let path = PathBuf::from(path);
+        if let Some(parent) = path.parent() {
+            if !parent.as_os_str().is_empty() {
+                tokio::fs::create_dir_all(parent).await?;
+            }
+        }
+
+        let lock_path = lock_path(&path);
+        let lock = std::fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&lock_path)?;
+        match lock.try_lock() {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) => {
+                return Err(CuttlestoreError::StoreLocked(
+                    lock_path.to_string_lossy().to_string(),
+                ))
+            }
+            Err(TryLockError::Error(error)) => Err(error)?,
+        }
+
+        // A compaction that was interrupted leaves its file behind, the log
+        // itself is still intact.
+        tokio::fs::remove_file(compaction_path(&path)).await.ok();
+
+        let mut file = open_log(&path).await?;
+        let file_len = file.metadata().await?.len();
+        let mut index = Index::default();
+        let len = replay(&mut file, 0, file_len, 0, &mut index).await?;
+        if len < file_len {
+            // The last record was cut short, most likely because we crashed
+            // while it was being written. It was never acknowledged, so it's
+            // safe to drop it.
+            #[cfg(feature = "logging-log")]
+            log::warn!(
+                "Dropping a partially written record at the end of {}",
+                path.to_string_lossy()
+            );
+            #[cfg(feature = "logging-tracing")]
+            tracing::warn!(
+                "Dropping a partially written record at the end of {}",
+                path.to_string_lossy()
+            );
+            file.set_len(len).await?;
+            file.sync_all().await?;
+        }
+
+        let reader = open_reader(&path)?;
+        Ok(AppendLogBackend {
+            inner: Arc::new(Inner {
+                path,
+                state: Mutex::new(State {
+                    file,
+                    reader,
+                    len,
+                    index,
+                    compacting: false,
+                }),
+                lock,
+            }),
+        })
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, CuttlestoreError> {
+        // Only the lookup happens under the lock, so reads don't wait for
+        // each other. The reader is taken along with the entry, so the offset
+        // still matches even if the log gets compacted in the meantime.
+        let (entry, reader) = {
+            let mut state = self.inner.state.lock().await;
+            let entry = match state.index.entries.get(key) {
+                Some(entry) => *entry,
+                None => return Ok(None),
+            };
+            if entry.is_expired(get_system_time()) {
+                state.index.entries.remove(key);
+                state.index.garbage += entry.len;
+                return Ok(None);
+            }
+            (entry, state.reader.clone())
+        };
+        let frame = read_frame_at(reader, entry).await?;
+        let record = record::decode(&frame[HEADER_LEN..])?;
+        Ok(record.payload.map(|payload| payload.to_vec()))
+    }
+
+    async fn write(&self, record: Record<'_>) -> Result<(), CuttlestoreError> {
+        let frame = record.encode()?;
+        let mut state = self.inner.state.lock().await;
+        let offset = state.len;
+        let result = async {
+            state.file.write_all(&frame).await?;
+            state.file.flush().await?;
+            state.file.sync_data().await
+        }
+        .await;
+        if let Err(error) = result {
+            // Don't leave a partial record behind, later records would be
+            // appended after it.
+            state.file.set_len(offset).await.ok();
+            Err(error)?;
+        }
+        state.len += frame.len() as u64;
+        state.index.apply(
+            &record,
+            Entry {
+                offset,
+                len: frame.len() as u64,
+                live_until: record.live_until,
+            },
+            get_system_time(),
+        );
+        self.compact_if_needed(&mut state);
+        Ok(())
+    }
+
+    /// Start compacting the log in the background, if enough of it is
+    /// garbage.
+    fn compact_if_needed(&self, state: &mut State) {
+        if state.compacting
+            || state.index.garbage < COMPACTION_MIN_GARBAGE
+            || state.index.garbage * 2 < state.len
+        {
+            return;
+        }
+        state.compacting = true;
+        let inner = self.inner.clone();
+        tokio::spawn(async move {
+            if let Err(_error) = inner.compact().await {
+                #[cfg(feature = "logging-log")]
+                log::error!("Failed to compact the log: {_error:?}");
+                #[cfg(feature = "logging-tracing")]
+                tracing::error!("Failed to compact the log: {_error:?}");
+            }
+            inner.state.lock().await.compacting = false;
+        });
+    }
+}
+
+impl Inner {
+    /// Copy the live records into a new file, and replace the log with it.
+    ///
+    /// The bulk of the copying happens without holding the lock, so reads and
+    /// writes can carry on in the meantime. Anything written during that time
+    /// is then copied over at the end.
+    async fn compact(&self) -> Result<(), CuttlestoreError> {
+        let (entries, copied_until, mut reader) = {
+            let mut state = self.state.lock().await;
+            state.index.drop_expired(get_system_time(), None);
+            let entries: Vec<(String, Entry)> = state
+                .index
+                .entries
+                .iter()
+                .map(|(key, entry)| (key.clone(), *entry))
+                .collect();
+            (entries, state.len, File::open(&self.path).await?)
+        };
+
+        let compaction_path = compaction_path(&self.path);
+        let result = async {
+            let mut writer = BufWriter::new(File::create(&compaction_path).await?);
+            let mut index = Index::default();
+            let mut len = 0;
+            for (key, entry) in entries {
+                let frame = read_frame(&mut reader, entry).await?;
+                writer.write_all(&frame).await?;
+                index.entries.insert(
+                    key,
+                    Entry {
+                        offset: len,
+                        ..entry
+                    },
+                );
+                len += entry.len;
+            }
+
+            let mut state = self.state.lock().await;
+            // Catch up with the writes that happened while we were copying
+            let tail = replay(&mut reader, copied_until, state.len, len, &mut index).await?;
+            if tail != state.len {
+                return Err(CuttlestoreError::FileError(std::io::Error::new(
+                    ErrorKind::InvalidData,
+                    "The log was changed during compaction",
+                )));
+            }
+            reader.seek(SeekFrom::Start(copied_until)).await?;
+            let mut tail = (&mut reader).take(state.len - copied_until);
+            tokio::io::copy(&mut tail, &mut writer).await?;
+            writer.flush().await?;
+            writer.get_ref().sync_all().await?;
+            drop(writer);
+
+            tokio::fs::rename(&compaction_path, &self.path).await?;
+            sync_parent(&self.path).await?;
+            state.file = open_log(&self.path).await?;
+            state.reader = open_reader(&self.path)?;
+            state.len = len + (state.len - copied_until);
+            state.index = index;
+            Ok(())
+        }
+        .await;
+        if result.is_err() {
+            tokio::fs::remove_file(&compaction_path).await.ok();
+        }
+        result
+    }
+}
+
+#[async_trait]
+impl CuttleBackend for AppendLogBackend {
+    async fn new(conn: &str) -> Option<Result<Box<Self>, CuttlestoreError>> {
+        let (_, path) = regex_captures!(r#"^log://(.+)$"#, conn)?;
+        Some(AppendLogBackend::new(path).await.map(Box::new))
+    }
+
+    fn requires_cleaner(&self) -> bool {
+        true
+    }
+
+    fn name(&self) -> &'static str {
+        "log"
+    }
+
+    async fn get<'a>(&self, key: Cow<'a, str>) -> Result<Option<Vec<u8>>, CuttlestoreError> {
+        self.get(key.as_ref()).await
+    }
+
+    async fn put<'a>(
+        &self,
+        key: Cow<'a, str>,
+        value: &[u8],
+        options: PutOptions,
+    ) -> Result<(), CuttlestoreError> {
+        self.write(Record {
+            key: key.as_ref(),
+            payload: Some(value),
+            live_until: options.ttl.map(|ttl| get_system_time() + ttl),
+        })
+        .await
+    }
+
+    async fn delete<'a>(&self, key: Cow<'a, str>) -> Result<(), CuttlestoreError> {
+        if !self
+            .inner
+            .state
+            .lock()
+            .await
+            .index
+            .entries
+            .contains_key(key.as_ref())
+        {
+            // Nothing to delete, no need to grow the log
+            return Ok(());
+        }
+        self.write(Record {
+            key: key.as_ref(),
+            payload: None,
+            live_until: None,
+        })
+        .await
+    }
+
+    async fn scan(
+        &self,
+    ) -> Result<BoxStream<Result<(String, Vec<u8>), CuttlestoreError>>, CuttlestoreError> {
+        let (entries, mut reader) = {
+            let mut state = self.inner.state.lock().await;
+            state.index.drop_expired(get_system_time(), None);
+            self.compact_if_needed(&mut state);
+            let entries: Vec<(String, Entry)> = state
+                .index
+                .entries
+                .iter()
+                .map(|(key, entry)| (key.clone(), *entry))
+                .collect();
+            // Opened while holding the lock, so that the offsets match the
+            // file even if the log gets compacted during the scan.
+            (entries, File::open(&self.inner.path).await?)
+        };
+
+        Ok(Box::pin(try_stream! {
+            for (key, entry) in entries {
+                let frame = read_frame(&mut reader, entry).await?;
+                let record = record::decode(&frame[HEADER_LEN..])?;
+                if let Some(payload) = record.payload {
+                    yield (key, payload.to_vec());
+                }
+            }
+        }))
+    }
+
+    async fn purge_expired(&self, limit: Option<u64>) -> Result<u64, CuttlestoreError> {
+        let mut state = self.inner.state.lock().await;
+        let removed = state.index.drop_expired(get_system_time(), limit);
+        self.compact_if_needed(&mut state);
+        Ok(removed)
+    }
+}
+
+async fn open_log(path: &Path) -> Result<File, CuttlestoreError> {
+    Ok(OpenOptions::new()
+        .create(true)
+        .read(true)
+        .append(true)
+        .open(path)
+        .await?)
+}
+
+fn open_reader(path: &Path) -> Result<Arc<std::fs::File>, CuttlestoreError> {
+    Ok(Arc::new(std::fs::File::open(path)?))
+}
+
+fn lock_path(path: &Path) -> PathBuf {
+    let mut lock_path = path.as_os_str().to_owned();
+    lock_path.push(".lock");
+    PathBuf::from(lock_path)
+}
+
+fn compaction_path(path: &Path) -> PathBuf {
+    let mut compaction_path = path.as_os_str().to_owned();
+    compaction_path.push(".compacting");
+    PathBuf::from(compaction_path)
+}
+
+/// Read the whole record, including the frame header.
+async fn read_frame(file: &mut File, entry: Entry) -> Result<Vec<u8>, CuttlestoreError> {
+    let mut frame = vec![0; entry.len as usize];
+    file.seek(SeekFrom::Start(entry.offset)).await?;
+    file.read_exact(&mut frame).await?;
+    Ok(frame)
+}
+
+/// Read the whole record at its offset, without moving the file's cursor.
+/// This lets many reads share the same file at once.
+async fn read_frame_at(
+    file: Arc<std::fs::File>,
+    entry: Entry,
+) -> Result<Vec<u8>, CuttlestoreError> {
+    tokio::task::spawn_blocking(move || {
+        let mut frame = vec![0; entry.len as usize];
+        read_exact_at(&file, &mut frame, entry.offset)?;
+        Ok(frame)
+    })
+    .await
+    .expect("Reading from the log panicked")
+}
+
+#[cfg(unix)]
+fn read_exact_at(file: &std::fs::File, buf: &mut [u8], offset: u64) -> std::io::Result<()> {
+    std::os::unix::fs::FileExt::read_exact_at(file, buf, offset)
+}
+
+#[cfg(windows)]
+fn read_exact_at(file: &std::fs::File, buf: &mut [u8], offset: u64) -> std::io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    let mut read = 0;
+    while read < buf.len() {
+        match file.seek_read(&mut buf[read..], offset + read as u64)? {
+            0 => return Err(ErrorKind::UnexpectedEof.into()),
+            n => read += n,
+        }
+    }
+    Ok(())
+}
+
+/// Read the records between `from` and `to`, adding them to the index as if
+/// the first record was at `base` instead of `from`.
+///
+/// Returns where the last complete record ends. If the final record is
+/// incomplete, this is before `to`.
+async fn replay(
+    file: &mut File,
+    from: u64,
+    to: u64,
+    base: u64,
+    index: &mut Index,
+) -> Result<u64, CuttlestoreError> {
+    file.seek(SeekFrom::Start(from)).await?;
+    let mut reader = BufReader::new(file);
+    let now = get_system_time();
+    let mut offset = from;
+    let mut body = Vec::new();
+    while to - offset >= HEADER_LEN as u64 {
+        let mut header = [0; HEADER_LEN];
+        reader.read_exact(&mut header).await?;
+        let (body_len, checksum) = record::parse_header(&header);
+        let len = HEADER_LEN as u64 + body_len as u64;
+        if offset + len > to {
+            break;
+        }
+        body.resize(body_len as usize, 0);
+        reader.read_exact(&mut body).await?;
+        if fnv1a(&body) != checksum {
+            if offset + len == to {
+                // The record at the end was cut short
+                break;
+            }
+            return Err(CuttlestoreError::FileError(std::io::Error::new(
+                ErrorKind::InvalidData,
+                format!("The log is corrupted at offset {offset}"),
+            )));
+        }
+        let record = record::decode(&body)?;
+        index.apply(
+            &record,
+            Entry {
+                offset: base + (offset - from),
+                len,
+                live_until: record.live_until,
+            },
+            now,
+        );
+        offset += len;
+    }
+    Ok(offset)
+}
+
+/// The rename is only durable once the folder itself is synced.
+#[cfg(unix)]
+async fn sync_parent(path: &Path) -> Result<(), CuttlestoreError> {
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    File::open(parent).await?.sync_all().await?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+async fn sync_parent(_path: &Path) -> Result<(), CuttlestoreError> {
+    Ok(())
+}
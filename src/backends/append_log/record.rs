@@ -0,0 +1,91 @@
+//! The format of the records in the log file.
+//!
+//! Every record is framed as:
+//!
+//! ```text
+//! [body length: u32 LE][checksum of body: u64 LE][body]
+//! ```
+//!
+//! The body is a bincode encoded `Record`. The checksum lets us tell a record
+//! that was only partially written, because the process crashed while it was
+//! being appended, apart from a complete one.
+
+use serde::{Deserialize, Serialize};
+
+use crate::common::fnv1a;
+
+/// The size of the frame header that comes before every record body.
+pub(crate) const HEADER_LEN: usize = 4 + 8;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct Record<'t> {
+    pub(crate) key: &'t str,
+    /// The value, or `None` if this record deletes the key.
+    #[serde(borrow)]
+    pub(crate) payload: Option<&'t [u8]>,
+    pub(crate) live_until: Option<u64>,
+}
+
+impl Record<'_> {
+    /// Encode the record, along with the frame header.
+    pub(crate) fn encode(&self) -> Result<Vec<u8>, bincode::error::EncodeError> {
+        let body = bincode::serde::encode_to_vec(self, bincode::config::legacy())?;
+        let mut frame = Vec::with_capacity(HEADER_LEN + body.len());
+        frame.extend_from_slice(&(body.len() as u32).to_le_bytes());
+        frame.extend_from_slice(&fnv1a(&body).to_le_bytes());
+        frame.extend_from_slice(&body);
+        Ok(frame)
+    }
+}
+
+/// Decode a record body, borrowing from it.
+pub(crate) fn decode(body: &[u8]) -> Result<Record<'_>, bincode::error::DecodeError> {
+    let (record, _) = bincode::serde::borrow_decode_from_slice(body, bincode::config::legacy())?;
+    Ok(record)
+}
+
+/// Parse a frame header, returning the length of the body and its checksum.
+pub(crate) fn parse_header(header: &[u8; HEADER_LEN]) -> (u32, u64) {
+    let (len, sum) = header.split_at(4);
+    (
+        u32::from_le_bytes(len.try_into().unwrap()),
+        u64::from_le_bytes(sum.try_into().unwrap()),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let record = Record {
+            key: "foo",
+            payload: Some(b"bar"),
+            live_until: Some(42),
+        };
+        let frame = record.encode().unwrap();
+        let (len, sum) = parse_header(frame[..HEADER_LEN].try_into().unwrap());
+        let body = &frame[HEADER_LEN..];
+        assert_eq!(len as usize, body.len());
+        assert_eq!(sum, fnv1a(body));
+
+        let decoded = decode(body).unwrap();
+        assert_eq!(decoded.key, "foo");
+        assert_eq!(decoded.payload, Some(&b"bar"[..]));
+        assert_eq!(decoded.live_until, Some(42));
+    }
+
+    #[test]
+    fn checksum_catches_truncation() {
+        let frame = Record {
+            key: "foo",
+            payload: Some(b"bar"),
+            live_until: None,
+        }
+        .encode()
+        .unwrap();
+        let (_, sum) = parse_header(frame[..HEADER_LEN].try_into().unwrap());
+        assert_ne!(sum, fnv1a(&frame[HEADER_LEN..frame.len() - 1]));
+    }
+}
@@ -1,12 +1,19 @@
-use std::{borrow::Cow, collections::HashMap};
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
 
 use async_stream::try_stream;
 use async_trait::async_trait;
-use futures::stream::BoxStream;
+use futures::{stream::BoxStream, StreamExt};
 use lazy_regex::regex_captures;
 use reqwest::{
-    header::{ACCEPT, CONTENT_TYPE},
-    Client, StatusCode, Url,
+    header::{ACCEPT, CONTENT_TYPE, COOKIE, SET_COOKIE},
+    Client, ClientBuilder, RequestBuilder, Response, StatusCode, Url,
 };
 use serde::{Deserialize, Serialize};
 
@@ -15,6 +22,10 @@ use crate::{
     common::{get_system_time, CuttlestoreError},
 };
 
+mod options;
+
+use options::{Auth, CouchdbOptions};
+
 /// The fixed name we give the single binary attachment that holds the
 /// Cuttlestore value on each CouchDB document.
 const ATTACHMENT_NAME: &str = "v";
@@ -23,10 +34,28 @@ const ATTACHMENT_NAME: &str = "v";
 /// random-looking constant is good enough since attachment payloads are
 /// arbitrary user bytes — the chance of accidental collision is negligible.
 const PUT_BOUNDARY: &str = "cuttlestore-couchdb-J9Q3kpv7zxLm5sN8WrYbHcD2";
+/// How many expired documents the cleaner looks up and deletes at a time.
+const PURGE_BATCH: usize = 500;
+/// How many documents a scan lists at a time.
+const SCAN_PAGE: usize = 500;
+/// How many values a scan downloads at the same time.
+const SCAN_CONCURRENCY: usize = 16;
+/// The design document and name of the Mango index on `live_until`.
+const INDEX_DDOC: &str = "cuttlestore";
+const INDEX_NAME: &str = "live_until";
+/// Deleted documents leave tombstones behind, so the database is compacted
+/// once the cleaner has deleted this many documents.
+const COMPACT_AFTER: u64 = 10_000;
 
 pub(crate) struct CouchdbBackend {
     client: Client,
     base: Url,
+    auth: Auth,
+    /// The session cookie, with `auth=cookie`.
+    session: Mutex<Option<String>>,
+    /// How many documents the cleaner deleted since it last compacted the
+    /// database.
+    purged_since_compaction: AtomicU64,
 }
 
 #[derive(Serialize, Deserialize, Default)]
@@ -67,6 +96,48 @@ struct AllDocsResponse {
 #[derive(Deserialize)]
 struct AllDocsRow {
     id: String,
+    /// The document without its attachments, with `include_docs=true`.
+    #[serde(default)]
+    doc: Option<StoredDoc>,
+}
+
+#[derive(Deserialize)]
+struct FindResponse {
+    docs: Vec<FoundDoc>,
+}
+
+#[derive(Deserialize)]
+struct FoundDoc {
+    #[serde(rename = "_id")]
+    id: String,
+    #[serde(rename = "_rev")]
+    rev: String,
+}
+
+#[derive(Serialize)]
+struct DeletedDoc<'a> {
+    #[serde(rename = "_id")]
+    id: &'a str,
+    #[serde(rename = "_rev")]
+    rev: &'a str,
+    #[serde(rename = "_deleted")]
+    deleted: bool,
+}
+
+/// The lease of the coordinated cleaners. It's a local document, which isn't
+/// listed with the documents of the store or replicated.
+#[derive(Serialize, Deserialize)]
+struct LeaseDoc {
+    #[serde(rename = "_rev", default, skip_serializing_if = "Option::is_none")]
+    rev: Option<String>,
+    holder: String,
+    live_until: u64,
+}
+
+#[derive(Deserialize)]
+struct BulkDocsResult {
+    #[serde(default)]
+    ok: bool,
 }
 
 fn doc_url(base: &Url, key: &str) -> Url {
@@ -78,6 +149,38 @@ fn doc_url(base: &Url, key: &str) -> Url {
     url
 }
 
+fn lease_url(base: &Url, key: &str) -> Url {
+    let mut url = base.clone();
+    url.path_segments_mut()
+        .expect("couchdb base url cannot be a base")
+        .pop_if_empty()
+        .push("_local")
+        .push(key);
+    url
+}
+
+/// Trust the CA certificate in this PEM file, on top of the usual ones.
+#[cfg(any(feature = "couchdb-native-tls", feature = "couchdb-rustls"))]
+fn add_root_certificate(
+    client: ClientBuilder,
+    path: &str,
+) -> Result<ClientBuilder, CuttlestoreError> {
+    let pem = std::fs::read(path).map_err(|err| {
+        CuttlestoreError::InvalidConnectionString(format!("unable to read {path}: {err}"))
+    })?;
+    Ok(client.add_root_certificate(reqwest::Certificate::from_pem(&pem)?))
+}
+
+#[cfg(not(any(feature = "couchdb-native-tls", feature = "couchdb-rustls")))]
+fn add_root_certificate(
+    _client: ClientBuilder,
+    _path: &str,
+) -> Result<ClientBuilder, CuttlestoreError> {
+    Err(CuttlestoreError::InvalidConnectionString(
+        "tls_ca needs the backend-couchdb-rustls or backend-couchdb-native-tls feature".into(),
+    ))
+}
+
 /// Find the first occurrence of `needle` in `haystack` starting at `from`.
 fn find_subseq(haystack: &[u8], needle: &[u8], from: usize) -> Option<usize> {
     if needle.is_empty() || from > haystack.len() {
@@ -186,20 +289,195 @@ fn build_put_body(value: &[u8], rev: Option<&str>, live_until: Option<u64>) -> V
 }
 
 impl CouchdbBackend {
-    async fn open(url: Url) -> Result<Box<Self>, CuttlestoreError> {
-        let client = Client::builder().build()?;
-        // Ensure the database exists. CouchDB returns 201 on create and 412 if
-        // it already exists; either is acceptable. We deliberately ignore
-        // other status codes (e.g. 401) here — a missing DB will surface
-        // clearly on the first real operation.
-        let _ = client.put(url.clone()).send().await;
-        Ok(Box::new(CouchdbBackend { client, base: url }))
+    async fn open(url: Url, options: CouchdbOptions) -> Result<Box<Self>, CuttlestoreError> {
+        let mut client = Client::builder();
+        if let Some(timeout) = options.timeout {
+            client = client.timeout(timeout);
+        }
+        if let Some(timeout) = options.connect_timeout {
+            client = client.connect_timeout(timeout);
+        }
+        if let Some(path) = &options.tls_ca {
+            client = add_root_certificate(client, path)?;
+        }
+        let creation_query = options.creation_query();
+        let backend = CouchdbBackend {
+            client: client.build()?,
+            base: url,
+            auth: options.auth,
+            session: Mutex::new(None),
+            purged_since_compaction: AtomicU64::new(0),
+        };
+        // Log in now, so bad credentials are reported right away
+        if let Auth::Cookie { username, password } = &backend.auth {
+            backend.log_in(username, password).await?;
+        }
+        backend.create_database(&creation_query).await?;
+        // Without the index the cleaner still works, but CouchDB has to look
+        // through every document to find the expired ones.
+        if let Err(_err) = backend.create_index().await {
+            #[cfg(feature = "logging-log")]
+            log::warn!("Unable to create the CouchDB index on live_until: {_err:?}");
+            #[cfg(feature = "logging-tracing")]
+            tracing::warn!("Unable to create the CouchDB index on live_until: {_err:?}");
+        }
+        Ok(Box::new(backend))
+    }
+
+    /// Create the database in case it is missing.
+    async fn create_database(
+        &self,
+        query: &[(&'static str, String)],
+    ) -> Result<(), CuttlestoreError> {
+        let resp = self
+            .send(self.client.put(self.base.clone()).query(query))
+            .await?;
+        match resp.status() {
+            // Created, or it already existed
+            s if s.is_success() || s == StatusCode::PRECONDITION_FAILED => Ok(()),
+            // Only server admins can create databases, but other users can
+            // still use one that exists.
+            s @ (StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN) => {
+                let exists = self.send(self.client.head(self.base.clone())).await?;
+                if exists.status().is_success() {
+                    Ok(())
+                } else {
+                    Err(CuttlestoreError::CouchdbError(format!(
+                        "unable to create the database, PUT returned {s}"
+                    )))
+                }
+            }
+            s => Err(CuttlestoreError::CouchdbError(format!(
+                "unable to create the database, PUT returned {s}"
+            ))),
+        }
+    }
+
+    /// Send the request with the credentials. With `auth=cookie`, if the
+    /// session has expired this logs in again and retries the request once.
+    async fn send(&self, request: RequestBuilder) -> Result<Response, CuttlestoreError> {
+        let (username, password) = match &self.auth {
+            Auth::None => return Ok(request.send().await?),
+            Auth::Basic { username, password } => {
+                return Ok(request
+                    .basic_auth(username, password.as_ref())
+                    .send()
+                    .await?)
+            }
+            Auth::Jwt { token } => return Ok(request.bearer_auth(token).send().await?),
+            Auth::Cookie { username, password } => (username, password),
+        };
+        let retry = request.try_clone();
+        let session = self.session();
+        let resp = match session {
+            Some(cookie) => request.header(COOKIE, cookie).send().await?,
+            None => request.send().await?,
+        };
+        match (resp.status(), retry) {
+            (StatusCode::UNAUTHORIZED, Some(retry)) => {
+                let cookie = self.log_in(username, password).await?;
+                Ok(retry.header(COOKIE, cookie).send().await?)
+            }
+            _ => Ok(resp),
+        }
+    }
+
+    fn session(&self) -> Option<String> {
+        self.session
+            .lock()
+            .expect("The CouchDB session lock is poisoned")
+            .clone()
+    }
+
+    /// Start a new session through `_session`, returning the session cookie.
+    async fn log_in(&self, username: &str, password: &str) -> Result<String, CuttlestoreError> {
+        // The server endpoints are next to the database
+        let mut url = self.base.clone();
+        url.path_segments_mut()
+            .expect("couchdb base url cannot be a base")
+            .pop_if_empty()
+            .pop()
+            .push("_session");
+        let resp = self
+            .client
+            .post(url)
+            .json(&serde_json::json!({ "name": username, "password": password }))
+            .send()
+            .await?;
+        if !resp.status().is_success() {
+            return Err(CuttlestoreError::CouchdbError(format!(
+                "unable to log in, _session returned {}",
+                resp.status()
+            )));
+        }
+        let cookie = resp
+            .headers()
+            .get_all(SET_COOKIE)
+            .iter()
+            .filter_map(|header| header.to_str().ok())
+            .filter_map(|header| header.split(';').next())
+            .find(|cookie| cookie.starts_with("AuthSession="))
+            .ok_or_else(|| {
+                CuttlestoreError::CouchdbError("_session didn't return a session cookie".into())
+            })?
+            .to_string();
+        *self
+            .session
+            .lock()
+            .expect("The CouchDB session lock is poisoned") = Some(cookie.clone());
+        Ok(cookie)
+    }
+
+    /// Create the Mango index the cleaner uses to find expired documents.
+    /// CouchDB does nothing if the index already exists.
+    async fn create_index(&self) -> Result<(), CuttlestoreError> {
+        let _: serde_json::Value = self
+            .post_json(
+                "_index",
+                &serde_json::json!({
+                    "index": { "fields": ["live_until"] },
+                    "ddoc": INDEX_DDOC,
+                    "name": INDEX_NAME,
+                    "type": "json",
+                    // The cleaner looks through every partition at once
+                    "partitioned": false,
+                }),
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// Compact the database and the index once enough documents have been
+    /// deleted, so the space taken by the deleted documents is reclaimed.
+    ///
+    /// CouchDB compacts in the background, so this doesn't wait for the
+    /// compaction to finish. Compacting needs an admin user, so if it fails
+    /// the cleaner carries on without it.
+    async fn compact_if_needed(&self, purged: u64) {
+        let total = self
+            .purged_since_compaction
+            .fetch_add(purged, Ordering::Relaxed)
+            + purged;
+        if total < COMPACT_AFTER {
+            return;
+        }
+        self.purged_since_compaction.store(0, Ordering::Relaxed);
+        for endpoint in ["_compact".to_string(), format!("_compact/{INDEX_DDOC}")] {
+            let result: Result<serde_json::Value, _> =
+                self.post_json(&endpoint, &serde_json::json!({})).await;
+            if let Err(_err) = result {
+                #[cfg(feature = "logging-log")]
+                log::warn!("Unable to compact the CouchDB database: {_err:?}");
+                #[cfg(feature = "logging-tracing")]
+                tracing::warn!("Unable to compact the CouchDB database: {_err:?}");
+            }
+        }
     }
 
     /// Fetch just the document metadata (no attachment payload). Used for
     /// looking up `_rev` before deletes and conflicting puts.
     async fn get_metadata(&self, key: &str) -> Result<Option<StoredDoc>, CuttlestoreError> {
-        let resp = self.client.get(doc_url(&self.base, key)).send().await?;
+        let resp = self.send(self.client.get(doc_url(&self.base, key))).await?;
         match resp.status() {
             StatusCode::OK => Ok(Some(resp.json().await?)),
             StatusCode::NOT_FOUND => Ok(None),
@@ -216,10 +494,7 @@ impl CouchdbBackend {
         let mut url = doc_url(&self.base, key);
         url.query_pairs_mut().append_pair("attachments", "true");
         let resp = self
-            .client
-            .get(url)
-            .header(ACCEPT, "multipart/related")
-            .send()
+            .send(self.client.get(url).header(ACCEPT, "multipart/related"))
             .await?;
         match resp.status() {
             StatusCode::OK => {}
@@ -269,45 +544,135 @@ impl CouchdbBackend {
         value: &[u8],
         live_until: Option<u64>,
     ) -> Result<(), CuttlestoreError> {
-        let mut rev: Option<String> = None;
         // Fast path: try without rev. On conflict, pick up the existing rev
         // and retry once. This avoids a round trip on the common case of
         // creating new keys.
-        for attempt in 0..2 {
-            let body = build_put_body(value, rev.as_deref(), live_until);
-            let resp = self
-                .client
-                .put(doc_url(&self.base, key))
-                .header(
-                    CONTENT_TYPE,
-                    format!("multipart/related; boundary=\"{PUT_BOUNDARY}\""),
-                )
-                .body(body)
-                .send()
-                .await?;
-            match resp.status() {
-                s if s.is_success() => return Ok(()),
-                StatusCode::CONFLICT if attempt == 0 => {
-                    let existing = self.get_metadata(key).await?;
-                    rev = existing.and_then(|d| d.rev);
-                    if rev.is_none() {
-                        return Err(CuttlestoreError::CouchdbError(
-                            "PUT conflict but no existing document found".into(),
-                        ));
-                    }
-                }
-                s => {
-                    return Err(CuttlestoreError::CouchdbError(format!(
-                        "PUT {key} returned {s}"
-                    )))
-                }
-            }
+        if self.try_put_doc(key, value, None, live_until).await? {
+            return Ok(());
+        }
+        let rev = self.get_metadata(key).await?.and_then(|d| d.rev);
+        if rev.is_none() {
+            return Err(CuttlestoreError::CouchdbError(
+                "PUT conflict but no existing document found".into(),
+            ));
+        }
+        if self
+            .try_put_doc(key, value, rev.as_deref(), live_until)
+            .await?
+        {
+            return Ok(());
         }
         Err(CuttlestoreError::CouchdbError(
             "PUT exceeded retry attempts".into(),
         ))
     }
 
+    /// PUT the document over the revision `rev`, or create it if there is no
+    /// revision. Returns false if the document was changed since `rev`.
+    async fn try_put_doc(
+        &self,
+        key: &str,
+        value: &[u8],
+        rev: Option<&str>,
+        live_until: Option<u64>,
+    ) -> Result<bool, CuttlestoreError> {
+        let body = build_put_body(value, rev, live_until);
+        let request = self
+            .client
+            .put(doc_url(&self.base, key))
+            .header(
+                CONTENT_TYPE,
+                format!("multipart/related; boundary=\"{PUT_BOUNDARY}\""),
+            )
+            .body(body);
+        let resp = self.send(request).await?;
+        match resp.status() {
+            s if s.is_success() => Ok(true),
+            StatusCode::CONFLICT => Ok(false),
+            s => Err(CuttlestoreError::CouchdbError(format!(
+                "PUT {key} returned {s}"
+            ))),
+        }
+    }
+
+    /// List a page of the documents in the database, without their
+    /// attachments. The page starts after the document `after`, or at the
+    /// first document if there is none.
+    async fn all_docs_page(
+        &self,
+        after: Option<&str>,
+    ) -> Result<Vec<AllDocsRow>, CuttlestoreError> {
+        let mut url = self.base.clone();
+        url.path_segments_mut()
+            .expect("couchdb base url cannot be a base")
+            .pop_if_empty()
+            .push("_all_docs");
+        url.query_pairs_mut().append_pair("include_docs", "true");
+        if let Some(after) = after {
+            // The page starts at `after` itself, so ask for one more row to
+            // drop it. We can't use `skip` for this: if `after` was deleted
+            // in the meantime, that would skip the next document instead.
+            url.query_pairs_mut()
+                .append_pair("startkey", &serde_json::to_string(after)?)
+                .append_pair("limit", &(SCAN_PAGE + 1).to_string());
+        } else {
+            url.query_pairs_mut()
+                .append_pair("limit", &SCAN_PAGE.to_string());
+        }
+
+        let resp = self.send(self.client.get(url)).await?;
+        if !resp.status().is_success() {
+            return Err(CuttlestoreError::CouchdbError(format!(
+                "_all_docs returned {}",
+                resp.status()
+            )));
+        }
+        let body: AllDocsResponse = resp.json().await?;
+        let mut rows = body.rows;
+        if after.is_some_and(|after| rows.first().is_some_and(|row| row.id == after)) {
+            rows.remove(0);
+        }
+        Ok(rows)
+    }
+
+    /// Download just the value of a document.
+    async fn get_attachment(&self, key: &str) -> Result<Option<Vec<u8>>, CuttlestoreError> {
+        let mut url = doc_url(&self.base, key);
+        url.path_segments_mut()
+            .expect("couchdb base url cannot be a base")
+            .push(ATTACHMENT_NAME);
+        let resp = self.send(self.client.get(url)).await?;
+        match resp.status() {
+            StatusCode::OK => Ok(Some(resp.bytes().await?.to_vec())),
+            StatusCode::NOT_FOUND => Ok(None),
+            s => Err(CuttlestoreError::CouchdbError(format!(
+                "GET {key}/{ATTACHMENT_NAME} returned {s}"
+            ))),
+        }
+    }
+
+    /// POST a JSON body to one of the database endpoints, like `_find` or
+    /// `_compact/ddoc`.
+    async fn post_json<T: Serialize + ?Sized, R: serde::de::DeserializeOwned>(
+        &self,
+        endpoint: &str,
+        body: &T,
+    ) -> Result<R, CuttlestoreError> {
+        let mut url = self.base.clone();
+        url.path_segments_mut()
+            .expect("couchdb base url cannot be a base")
+            .pop_if_empty()
+            .extend(endpoint.split('/'));
+        let resp = self.send(self.client.post(url).json(body)).await?;
+        if !resp.status().is_success() {
+            return Err(CuttlestoreError::CouchdbError(format!(
+                "{endpoint} returned {}",
+                resp.status()
+            )));
+        }
+        Ok(resp.json().await?)
+    }
+
     async fn delete_doc(&self, key: &str) -> Result<(), CuttlestoreError> {
         let rev = match self.get_metadata(key).await? {
             Some(doc) => doc.rev,
@@ -319,7 +684,7 @@ impl CouchdbBackend {
         };
         let mut url = doc_url(&self.base, key);
         url.query_pairs_mut().append_pair("rev", &rev);
-        let resp = self.client.delete(url).send().await?;
+        let resp = self.send(self.client.delete(url)).await?;
         match resp.status() {
             s if s.is_success() => Ok(()),
             // If something else updated the doc between our GET and DELETE,
@@ -339,7 +704,7 @@ impl CuttleBackend for CouchdbBackend {
         let (_, secure, rest) = regex_captures!(r#"^couchdb(s)?://(.+)"#, conn)?;
         let scheme = if secure.is_empty() { "http" } else { "https" };
         let url_str = format!("{scheme}://{rest}");
-        let url = match Url::parse(&url_str) {
+        let mut url = match Url::parse(&url_str) {
             Ok(u) => u,
             Err(e) => {
                 return Some(Err(CuttlestoreError::CouchdbError(format!(
@@ -352,7 +717,11 @@ impl CuttleBackend for CouchdbBackend {
                 "connection string must include a database name".into(),
             )));
         }
-        Some(CouchdbBackend::open(url).await)
+        let options = match CouchdbOptions::parse(&mut url, !secure.is_empty()) {
+            Ok(options) => options,
+            Err(err) => return Some(Err(err)),
+        };
+        Some(CouchdbBackend::open(url, options).await)
     }
 
     fn requires_cleaner(&self) -> bool {
@@ -394,51 +763,159 @@ impl CuttleBackend for CouchdbBackend {
     async fn scan(
         &self,
     ) -> Result<BoxStream<Result<(String, Vec<u8>), CuttlestoreError>>, CuttlestoreError> {
-        let mut url = self.base.clone();
-        url.path_segments_mut()
-            .expect("couchdb base url cannot be a base")
-            .pop_if_empty()
-            .push("_all_docs");
-
-        let resp = self.client.get(url).send().await?;
-        if !resp.status().is_success() {
-            return Err(CuttlestoreError::CouchdbError(format!(
-                "_all_docs returned {}",
-                resp.status()
-            )));
-        }
-        let body: AllDocsResponse = resp.json().await?;
-
-        // Clone what the stream needs so it does not borrow `self`.
-        let client = self.client.clone();
-        let base = self.base.clone();
+        let first = self.all_docs_page(None).await?;
 
         Ok(Box::pin(try_stream! {
-            for row in body.rows {
-                // CouchDB's _all_docs lists design and local documents alongside
-                // user data. They start with `_` so we skip them outright.
-                if row.id.starts_with('_') {
-                    continue;
-                }
-                let backend = CouchdbBackend { client: client.clone(), base: base.clone() };
-                let (doc, value) = match backend.get_with_attachment(&row.id).await? {
-                    Some(pair) => pair,
-                    // Doc was deleted between _all_docs and the GET — skip it.
-                    None => continue,
-                };
-                if let Some(live_until) = doc.live_until {
-                    if live_until < get_system_time() {
-                        // Best effort: try to delete the expired document. We
-                        // ignore errors here so a single failing delete does
-                        // not abort the entire scan.
-                        let _ = backend.delete_doc(&row.id).await;
+            let mut rows = first;
+            loop {
+                let last_page = rows.len() < SCAN_PAGE;
+                let after = rows.last().map(|row| row.id.clone());
+
+                let now = get_system_time();
+                let mut live = Vec::with_capacity(rows.len());
+                let mut expired = Vec::new();
+                for row in rows {
+                    // CouchDB's _all_docs lists design documents alongside
+                    // user data. They start with `_` so we skip them outright.
+                    if row.id.starts_with('_') {
                         continue;
                     }
+                    match row.doc {
+                        Some(StoredDoc {
+                            live_until: Some(live_until),
+                            rev: Some(rev),
+                            ..
+                        }) if live_until < now => expired.push((row.id, rev)),
+                        _ => live.push(row.id),
+                    }
+                }
+                if !expired.is_empty() {
+                    let deletions: Vec<DeletedDoc> = expired
+                        .iter()
+                        .map(|(id, rev)| DeletedDoc {
+                            id,
+                            rev,
+                            deleted: true,
+                        })
+                        .collect();
+                    // Best effort: a failed delete shouldn't abort the entire
+                    // scan, the cleaner will get to it later.
+                    let _: Result<Vec<BulkDocsResult>, _> = self
+                        .post_json("_bulk_docs", &serde_json::json!({ "docs": deletions }))
+                        .await;
+                }
+
+                let mut values = futures::stream::iter(live)
+                    .map(|id| async move {
+                        let value = self.get_attachment(&id).await?;
+                        Ok::<_, CuttlestoreError>(value.map(|value| (id, value)))
+                    })
+                    .buffered(SCAN_CONCURRENCY);
+                while let Some(pair) = values.next().await {
+                    // The document was deleted after it was listed
+                    if let Some(pair) = pair? {
+                        yield pair;
+                    }
+                }
+
+                match after {
+                    Some(after) if !last_page => rows = self.all_docs_page(Some(&after)).await?,
+                    _ => break,
                 }
-                yield (row.id, value);
             }
         }))
     }
+
+    async fn purge_expired(&self, limit: Option<u64>) -> Result<u64, CuttlestoreError> {
+        let now = get_system_time();
+        let mut removed = 0;
+        loop {
+            let batch = match limit {
+                Some(limit) if removed >= limit => break,
+                Some(limit) => PURGE_BATCH.min((limit - removed) as usize),
+                None => PURGE_BATCH,
+            };
+            // Only the ids and revisions of the expired documents come back,
+            // not their attachments.
+            let found: FindResponse = self
+                .post_json(
+                    "_find",
+                    &serde_json::json!({
+                        "selector": { "live_until": { "$lt": now } },
+                        "fields": ["_id", "_rev"],
+                        "limit": batch,
+                        "use_index": [INDEX_DDOC, INDEX_NAME],
+                    }),
+                )
+                .await?;
+            if found.docs.is_empty() {
+                break;
+            }
+
+            let deletions: Vec<DeletedDoc> = found
+                .docs
+                .iter()
+                .map(|doc| DeletedDoc {
+                    id: &doc.id,
+                    rev: &doc.rev,
+                    deleted: true,
+                })
+                .collect();
+            let results: Vec<BulkDocsResult> = self
+                .post_json("_bulk_docs", &serde_json::json!({ "docs": deletions }))
+                .await?;
+            // Documents that were changed since they were found fail with a
+            // conflict, and are left for the next sweep.
+            let deleted = results.iter().filter(|result| result.ok).count();
+            removed += deleted as u64;
+            if found.docs.len() < batch || deleted == 0 {
+                break;
+            }
+        }
+        self.compact_if_needed(removed).await;
+        Ok(removed)
+    }
+
+    async fn take_lease<'a>(
+        &self,
+        key: Cow<'a, str>,
+        holder: &'a str,
+        ttl: u64,
+    ) -> Result<bool, CuttlestoreError> {
+        let now = get_system_time();
+        let url = lease_url(&self.base, &key);
+        let resp = self.send(self.client.get(url.clone())).await?;
+        let rev = match resp.status() {
+            StatusCode::OK => {
+                let lease: LeaseDoc = resp.json().await?;
+                if lease.live_until >= now && lease.holder != holder {
+                    return Ok(false);
+                }
+                lease.rev
+            }
+            StatusCode::NOT_FOUND => None,
+            s => {
+                return Err(CuttlestoreError::CouchdbError(format!(
+                    "GET _local/{key} returned {s}"
+                )))
+            }
+        };
+        // The write fails with a conflict if anyone else wrote the lease since
+        // we read it, in which case they have it.
+        let lease = LeaseDoc {
+            rev,
+            holder: holder.to_string(),
+            live_until: now + ttl,
+        };
+        let resp = self.send(self.client.put(url).json(&lease)).await?;
+        match resp.status() {
+            s if s.is_success() => Ok(true),
+            StatusCode::CONFLICT => Ok(false),
+            s => Err(CuttlestoreError::CouchdbError(format!(
+                "PUT _local/{key} returned {s}"
+            ))),
+        }
+    }
 }
 
 #[cfg(test)]
@@ -0,0 +1,259 @@
+//! The options that can be given after the `?` in a DynamoDB connection string.
+
+use std::time::Duration;
+
+use crate::common::CuttlestoreError;
+
+/// The names of the attributes the values are stored in.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Schema {
+    pub(crate) key: String,
+    pub(crate) value: String,
+    pub(crate) ttl: String,
+    /// The partition key and this store's value for it, when the table is
+    /// shared between stores. The key is then the sort key of the table.
+    pub(crate) partition: Option<(String, String)>,
+}
+
+impl Schema {
+    /// The partition key of the table.
+    pub(crate) fn hash_key(&self) -> &str {
+        match &self.partition {
+            Some((attribute, _)) => attribute,
+            None => &self.key,
+        }
+    }
+
+    /// The sort key of the table, if it has one.
+    pub(crate) fn range_key(&self) -> Option<&str> {
+        self.partition.as_ref().map(|_| self.key.as_str())
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct DynamodbOptions {
+    pub(crate) endpoint: Option<String>,
+    pub(crate) credentials: Option<(String, String)>,
+    pub(crate) schema: Schema,
+    /// The read and write capacity units for a new table. The table is
+    /// created with on-demand billing if these aren't set.
+    pub(crate) capacity: Option<(i64, i64)>,
+    pub(crate) create_table: bool,
+    /// How long to wait for the table to become active.
+    pub(crate) active_timeout: Duration,
+}
+
+impl DynamodbOptions {
+    pub(crate) fn parse(args: &str) -> Result<Self, CuttlestoreError> {
+        let mut access_key = None;
+        let mut secret_key = None;
+        let mut partition_attribute = None;
+        let mut partition = None;
+        let mut read_capacity = None;
+        let mut write_capacity = None;
+        let mut options = DynamodbOptions {
+            endpoint: None,
+            credentials: None,
+            schema: Schema {
+                key: "key".to_string(),
+                value: "value".to_string(),
+                ttl: "live_until".to_string(),
+                partition: None,
+            },
+            capacity: None,
+            create_table: true,
+            active_timeout: Duration::from_secs(120),
+        };
+
+        for (name, value) in args
+            .split('&')
+            .filter(|p| !p.is_empty())
+            .flat_map(|pair| pair.split_once('='))
+        {
+            let value = value.to_string();
+            match name {
+                "endpoint" => options.endpoint = Some(value),
+                "access_key" => access_key = Some(value),
+                "secret_key" => secret_key = Some(value),
+                "key_attribute" => options.schema.key = attribute(name, value)?,
+                "value_attribute" => options.schema.value = attribute(name, value)?,
+                "ttl_attribute" => options.schema.ttl = attribute(name, value)?,
+                "partition_attribute" => partition_attribute = Some(attribute(name, value)?),
+                "partition" => partition = Some(attribute(name, value)?),
+                "read_capacity" => read_capacity = Some(positive(name, &value)?),
+                "write_capacity" => write_capacity = Some(positive(name, &value)?),
+                "create_table" => {
+                    options.create_table = match value.as_str() {
+                        "true" => true,
+                        "false" => false,
+                        _ => {
+                            return Err(invalid(format!(
+                                "create_table must be true or false, got {value}"
+                            )))
+                        }
+                    }
+                }
+                "active_timeout" => options.active_timeout = seconds(name, &value)?,
+                _ => return Err(invalid(format!("unknown DynamoDB option {name}"))),
+            }
+        }
+
+        options.credentials = match (access_key, secret_key) {
+            (Some(access), Some(secret)) => Some((access, secret)),
+            (None, None) => None,
+            _ => {
+                return Err(invalid(
+                    "access_key and secret_key must be given together".to_string(),
+                ))
+            }
+        };
+        options.schema.partition = match (partition_attribute, partition) {
+            (attribute, Some(partition)) => {
+                Some((attribute.unwrap_or_else(|| "store".to_string()), partition))
+            }
+            (Some(_), None) => {
+                return Err(invalid(
+                    "partition_attribute needs a partition for this store".to_string(),
+                ))
+            }
+            (None, None) => None,
+        };
+        options.capacity = match (read_capacity, write_capacity) {
+            (Some(read), Some(write)) => Some((read, write)),
+            (None, None) => None,
+            _ => {
+                return Err(invalid(
+                    "read_capacity and write_capacity must be given together".to_string(),
+                ))
+            }
+        };
+
+        let schema = &options.schema;
+        let mut names = vec![&schema.key, &schema.value, &schema.ttl];
+        if let Some((attribute, _)) = &schema.partition {
+            names.push(attribute);
+        }
+        names.sort();
+        if let Some(name) = names.windows(2).find(|pair| pair[0] == pair[1]) {
+            return Err(invalid(format!(
+                "the attribute {} is used for more than one thing",
+                name[0]
+            )));
+        }
+        Ok(options)
+    }
+}
+
+fn invalid(message: String) -> CuttlestoreError {
+    CuttlestoreError::InvalidConnectionString(message)
+}
+
+fn attribute(name: &str, value: String) -> Result<String, CuttlestoreError> {
+    if value.is_empty() {
+        return Err(invalid(format!("{name} can't be empty")));
+    }
+    Ok(value)
+}
+
+fn positive(name: &str, value: &str) -> Result<i64, CuttlestoreError> {
+    match value.parse::<i64>() {
+        Ok(number) if number > 0 => Ok(number),
+        _ => Err(invalid(format!(
+            "{name} must be a positive number, got {value}"
+        ))),
+    }
+}
+
+/// A timeout in seconds, which can have a fraction like `0.5`.
+fn seconds(name: &str, value: &str) -> Result<Duration, CuttlestoreError> {
+    value
+        .parse::<f64>()
+        .ok()
+        .filter(|seconds| *seconds > 0.0)
+        .and_then(|seconds| Duration::try_from_secs_f64(seconds).ok())
+        .ok_or_else(|| {
+            invalid(format!(
+                "{name} must be a positive number of seconds, got {value}"
+            ))
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(args: &str) -> String {
+        match DynamodbOptions::parse(args) {
+            Err(CuttlestoreError::InvalidConnectionString(message)) => message,
+            other => panic!("expected an invalid connection string, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn defaults_to_one_store_per_table() {
+        let options = DynamodbOptions::parse("endpoint=http://localhost:8000").unwrap();
+        assert_eq!(options.endpoint.as_deref(), Some("http://localhost:8000"));
+        assert_eq!(options.schema.hash_key(), "key");
+        assert_eq!(options.schema.range_key(), None);
+        assert_eq!(options.schema.value, "value");
+        assert_eq!(options.schema.ttl, "live_until");
+        assert_eq!(options.capacity, None);
+        assert!(options.create_table);
+    }
+
+    #[test]
+    fn parses_options() {
+        let options = DynamodbOptions::parse(
+            "access_key=AK&secret_key=SK&key_attribute=sk&value_attribute=v&ttl_attribute=expires&partition_attribute=pk&partition=sessions&read_capacity=5&write_capacity=10&create_table=false&active_timeout=30",
+        )
+        .unwrap();
+        assert_eq!(
+            options.credentials,
+            Some(("AK".to_string(), "SK".to_string()))
+        );
+        assert_eq!(options.schema.hash_key(), "pk");
+        assert_eq!(options.schema.range_key(), Some("sk"));
+        assert_eq!(
+            options.schema.partition,
+            Some(("pk".to_string(), "sessions".to_string()))
+        );
+        assert_eq!(options.schema.value, "v");
+        assert_eq!(options.schema.ttl, "expires");
+        assert_eq!(options.capacity, Some((5, 10)));
+        assert!(!options.create_table);
+        assert_eq!(options.active_timeout, Duration::from_secs(30));
+
+        let options = DynamodbOptions::parse("partition=sessions").unwrap();
+        assert_eq!(options.schema.hash_key(), "store");
+        assert_eq!(options.schema.range_key(), Some("key"));
+    }
+
+    #[test]
+    fn rejects_bad_options() {
+        assert_eq!(error("tabel=a"), "unknown DynamoDB option tabel");
+        assert_eq!(
+            error("access_key=AK"),
+            "access_key and secret_key must be given together"
+        );
+        assert_eq!(
+            error("partition_attribute=pk"),
+            "partition_attribute needs a partition for this store"
+        );
+        assert_eq!(
+            error("read_capacity=5"),
+            "read_capacity and write_capacity must be given together"
+        );
+        assert_eq!(
+            error("read_capacity=0&write_capacity=1"),
+            "read_capacity must be a positive number, got 0"
+        );
+        assert_eq!(
+            error("create_table=no"),
+            "create_table must be true or false, got no"
+        );
+        assert_eq!(
+            error("value_attribute=key"),
+            "the attribute key is used for more than one thing"
+        );
+    }
+}
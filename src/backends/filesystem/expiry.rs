@@ -0,0 +1,107 @@
+//! An index of when files expire, so that cleaning up the store doesn't
+//! require reading every single file.
+//!
+//! The index is a plain text file, where each line holds the time a file
+//! expires and the name of the file. Whenever a value with a TTL is written, a
+//! line is appended to the index. The index is allowed to be stale: a file may
+//! have been overwritten with a different TTL or deleted since, so the file
+//! itself is always checked before anything is removed.
+//!
+//! To sweep the store, the index is moved aside and replaced with an empty one
+//! so that writes can carry on while the sweep goes through the old index.
+//! Entries that aren't due yet are then added back to the new index.
+//!
+//! Stores created by older versions don't have an index. The index is built
+//! with a full scan of the store the first time it is swept.
+
+use std::{collections::HashMap, path::Path};
+
+use tokio::io::AsyncWriteExt;
+
+/// The index of expiry times.
+pub(crate) const INDEX: &str = ".expiry";
+/// The index is moved here while a sweep is going through it.
+pub(crate) const SWEEPING: &str = ".expiry-sweeping";
+/// Exists while the index is being rebuilt with a full scan.
+pub(crate) const REBUILDING: &str = ".expiry-rebuilding";
+
+/// Parse the lines of the index, skipping any that are malformed. A line
+/// could be cut short if the program crashed while appending it.
+pub(crate) fn parse(contents: &str) -> Vec<(u64, String)> {
+    contents
+        .lines()
+        .filter_map(|line| {
+            let (live_until, file_name) = line.split_once(' ')?;
+            Some((live_until.parse().ok()?, file_name.to_string()))
+        })
+        .collect()
+}
+
+/// Add entries to the index, if it exists.
+///
+/// If the index doesn't exist, it is going to be rebuilt with a full scan
+/// anyway, so the entries are dropped.
+pub(crate) async fn append(
+    index: &Path,
+    entries: impl IntoIterator<Item = (u64, &str)>,
+) -> std::io::Result<()> {
+    let mut lines = String::new();
+    for (live_until, file_name) in entries {
+        lines.push_str(&format!("{live_until} {file_name}\n"));
+    }
+    if lines.is_empty() {
+        return Ok(());
+    }
+    let mut file = match tokio::fs::OpenOptions::new().append(true).open(index).await {
+        Ok(file) => file,
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(error) => return Err(error),
+    };
+    file.write_all(lines.as_bytes()).await?;
+    file.flush().await
+}
+
+/// Collects the entries that are kept for the next sweep, keeping only the
+/// earliest time for each file.
+#[derive(Default)]
+pub(crate) struct Remaining {
+    entries: HashMap<String, u64>,
+}
+
+impl Remaining {
+    pub(crate) fn add(&mut self, live_until: u64, file_name: String) {
+        self.entries
+            .entry(file_name)
+            .and_modify(|existing| *existing = (*existing).min(live_until))
+            .or_insert(live_until);
+    }
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (u64, &str)> {
+        self.entries
+            .iter()
+            .map(|(file_name, live_until)| (*live_until, file_name.as_str()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_skips_broken_lines() {
+        let entries = parse("12 foo\nbar\n34 baz\n5");
+        assert_eq!(
+            entries,
+            vec![(12, "foo".to_string()), (34, "baz".to_string())]
+        );
+    }
+
+    #[test]
+    fn remaining_keeps_the_earliest() {
+        let mut remaining = Remaining::default();
+        remaining.add(20, "foo".to_string());
+        remaining.add(10, "foo".to_string());
+        remaining.add(30, "foo".to_string());
+        assert_eq!(remaining.iter().collect::<Vec<_>>(), vec![(10, "foo")]);
+    }
+}
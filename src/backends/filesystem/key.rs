@@ -0,0 +1,166 @@
+//! Encoding keys into file names.
+//!
+//! Keys can contain anything, but file names can't: `/` would create
+//! subfolders, `..` escapes the store folder, NUL is rejected outright, and
+//! Windows refuses names like `CON` or `aux.txt`. Keys are percent-encoded so
+//! that the resulting file name only contains ASCII letters, digits, `-`, `_`
+//! and `.`, which is safe on every filesystem we care about.
+//!
+//! The encoding is reversible, which lets `scan` recover the key from the file
+//! name. The exception is keys that are too long to fit in a file name. These
+//! are truncated and suffixed with a hash, and since that can't be reversed the
+//! key is stored inside the file instead.
+//!
+//! File names starting with `.` are never produced by the encoding, so they are
+//! reserved for the backend's own bookkeeping.
+
+use crate::common::fnv1a;
+
+/// Encoded names longer than this are hashed instead. Most filesystems limit
+/// names to 255 bytes, this leaves some headroom for temporary suffixes.
+const MAX_NAME_LEN: usize = 200;
+/// How much of the encoded key is kept at the start of a hashed name. This is
+/// only there to make the files easier to recognize.
+const HASHED_PREFIX_LEN: usize = 100;
+/// Marks file names that contain a hash, rather than an encoded key. `~` is
+/// always escaped by the encoding, so this can't clash with an encoded key.
+const HASHED_MARKER: char = '~';
+
+/// Names that Windows reserves, regardless of case or extension.
+const RESERVED_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Convert a key into the file name it is stored under.
+pub(crate) fn encode_key(key: &str) -> String {
+    let bytes = key.as_bytes();
+    let mut name = String::with_capacity(bytes.len());
+    for (i, byte) in bytes.iter().enumerate() {
+        let is_safe = match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' => true,
+            // Leading dots would hide the file, and clash with `.` and `..`.
+            // Windows strips trailing dots.
+            b'.' => i != 0 && i != bytes.len() - 1,
+            _ => false,
+        };
+        if is_safe {
+            name.push(*byte as char);
+        } else {
+            name.push_str(&format!("%{byte:02X}"));
+        }
+    }
+
+    if is_reserved(&name) {
+        // Escaping the first character is enough to make it a regular name.
+        name = format!("%{:02X}{}", bytes[0], &name[1..]);
+    }
+
+    if name.is_empty() || name.len() > MAX_NAME_LEN {
+        name = hashed_name(&name, key);
+    }
+    name
+}
+
+/// Convert a file name back into a key.
+///
+/// Returns `None` if the name is not one produced by `encode_key`, or if it is
+/// a hashed name that can't be decoded.
+pub(crate) fn decode_file_name(name: &str) -> Option<String> {
+    if name.starts_with('.') || is_hashed(name) {
+        return None;
+    }
+    let mut bytes = Vec::with_capacity(name.len());
+    let mut chars = name.bytes();
+    while let Some(byte) = chars.next() {
+        if byte == b'%' {
+            let high = chars.next()?;
+            let low = chars.next()?;
+            let hex = [high, low];
+            let hex = std::str::from_utf8(&hex).ok()?;
+            bytes.push(u8::from_str_radix(hex, 16).ok()?);
+        } else {
+            bytes.push(byte);
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+/// Checks if this is a hashed file name, which can't be decoded.
+pub(crate) fn is_hashed(name: &str) -> bool {
+    name.starts_with(HASHED_MARKER)
+}
+
+fn is_reserved(name: &str) -> bool {
+    let stem = name.split('.').next().unwrap_or(name);
+    RESERVED_NAMES
+        .iter()
+        .any(|reserved| reserved.eq_ignore_ascii_case(stem))
+}
+
+fn hashed_name(encoded: &str, key: &str) -> String {
+    // The encoded name is ASCII, so any byte offset is a char boundary.
+    let prefix = &encoded[..encoded.len().min(HASHED_PREFIX_LEN)];
+    format!(
+        "{HASHED_MARKER}{prefix}{HASHED_MARKER}{:016x}",
+        fnv1a(key.as_bytes())
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn safe_keys_are_unchanged() {
+        assert_eq!(encode_key("hello-World_42.txt"), "hello-World_42.txt");
+    }
+
+    #[test]
+    fn unsafe_keys_are_escaped() {
+        assert_eq!(encode_key("foo:bar/baz"), "foo%3Abar%2Fbaz");
+        assert_eq!(encode_key(".."), "%2E%2E");
+        assert_eq!(encode_key("a\0b"), "a%00b");
+        assert_eq!(encode_key("100%"), "100%25");
+        assert_eq!(encode_key("~"), "%7E");
+    }
+
+    #[test]
+    fn reserved_names_are_escaped() {
+        assert_eq!(encode_key("CON"), "%43ON");
+        assert_eq!(encode_key("aux.txt"), "%61ux.txt");
+        assert_eq!(encode_key("console"), "console");
+    }
+
+    #[test]
+    fn round_trip() {
+        for key in [
+            "hello",
+            "foo:bar/baz",
+            "..",
+            ".hidden",
+            "trailing.",
+            "a\0b",
+            "100%",
+            "nul",
+            "emoji 🦑",
+        ] {
+            assert_eq!(decode_file_name(&encode_key(key)).as_deref(), Some(key));
+        }
+    }
+
+    #[test]
+    fn long_keys_are_hashed() {
+        let key = "k".repeat(1000);
+        let name = encode_key(&key);
+        assert!(name.len() <= MAX_NAME_LEN);
+        assert!(is_hashed(&name));
+        assert_eq!(decode_file_name(&name), None);
+        assert_ne!(name, encode_key(&"k".repeat(1001)));
+    }
+
+    #[test]
+    fn empty_key_is_hashed() {
+        assert!(is_hashed(&encode_key("")));
+    }
+}
@@ -0,0 +1,79 @@
+//! How the files are laid out inside the store folder.
+
+use std::path::{Path, PathBuf};
+
+use crate::common::fnv1a;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Layout {
+    /// All files are placed directly inside the store folder.
+    Flat,
+    /// Files are spread out over two levels of folders, named after the hash
+    /// of the file name, like `3f/a0/<file name>`. Most filesystems slow down
+    /// once a single folder holds a few hundred thousand files, this keeps the
+    /// folders small even for very large stores.
+    Sharded,
+}
+
+impl Layout {
+    pub(crate) fn parse(name: &str) -> Option<Self> {
+        match name {
+            "flat" => Some(Layout::Flat),
+            "sharded" => Some(Layout::Sharded),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            Layout::Flat => "flat",
+            Layout::Sharded => "sharded",
+        }
+    }
+
+    /// The path of the file, relative to the store folder.
+    pub(crate) fn path(&self, base_folder: &Path, file_name: &str) -> PathBuf {
+        match self {
+            Layout::Flat => base_folder.join(file_name),
+            Layout::Sharded => {
+                let hash = format!("{:016x}", fnv1a(file_name.as_bytes()));
+                base_folder
+                    .join(&hash[0..2])
+                    .join(&hash[2..4])
+                    .join(file_name)
+            }
+        }
+    }
+}
+
+/// Checks if this is the name of one of the folders used by the sharded
+/// layout.
+pub(crate) fn is_shard_folder(name: &str) -> bool {
+    name.len() == 2
+        && name
+            .bytes()
+            .all(|byte| matches!(byte, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sharded_paths_use_two_levels() {
+        let path = Layout::Sharded.path(Path::new("store"), "foo");
+        let components: Vec<_> = path.iter().map(|c| c.to_string_lossy()).collect();
+        assert_eq!(components.len(), 4);
+        assert!(is_shard_folder(&components[1]));
+        assert!(is_shard_folder(&components[2]));
+        assert_eq!(components[3], "foo");
+    }
+
+    #[test]
+    fn flat_paths_are_direct() {
+        assert_eq!(
+            Layout::Flat.path(Path::new("store"), "foo"),
+            Path::new("store/foo")
+        );
+    }
+}
@@ -0,0 +1,157 @@
+//! Advisory file locks, so multiple processes can safely share a store.
+//!
+//! Writes are already atomic, so two processes writing the same key can't
+//! corrupt it. What locks protect against is a value being removed right after
+//! another process replaced it. For example, one process's cleaner reads an
+//! expired value and decides to delete it, but another process puts a fresh
+//! value under the same key before the delete goes through.
+//!
+//! Rather than one lock file for every key, keys are spread over a fixed set of
+//! lock files. The locks are only held for the duration of a single rename or
+//! delete, so the occasional contention between unrelated keys is cheap.
+
+use std::{
+    fs::{File, OpenOptions, TryLockError},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use tokio::sync::{Mutex, OwnedMutexGuard};
+
+use crate::common::{fnv1a, CuttlestoreError};
+
+/// The lock files are kept in this folder.
+const LOCKS_FOLDER: &str = ".locks";
+/// The store-wide lock file.
+const STORE_LOCK: &str = ".lock";
+/// The number of lock files keys are spread over.
+const STRIPES: u64 = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum LockMode {
+    /// No locking at all. Only safe if a single process uses the store.
+    None,
+    /// Lock keys while they are being changed, so multiple processes can
+    /// share the store.
+    Shared,
+    /// Lock keys like `Shared`, but also refuse to open the store if any other
+    /// process has it open.
+    Exclusive,
+}
+
+impl LockMode {
+    pub(crate) fn parse(name: &str) -> Option<Self> {
+        match name {
+            "none" => Some(LockMode::None),
+            "shared" => Some(LockMode::Shared),
+            "exclusive" => Some(LockMode::Exclusive),
+            _ => None,
+        }
+    }
+}
+
+/// A lock on the entire store, held for as long as the store is open.
+///
+/// Every process takes a shared lock, except ones opened in exclusive mode
+/// which take an exclusive lock. This way an exclusive process can't open the
+/// store while anyone else has it open, and vice versa.
+pub(crate) struct StoreLock {
+    file: File,
+    path: PathBuf,
+}
+
+impl StoreLock {
+    pub(crate) fn open(base_folder: &Path, mode: LockMode) -> Result<Self, CuttlestoreError> {
+        let path = base_folder.join(STORE_LOCK);
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&path)?;
+        let lock = StoreLock { file, path };
+        match mode {
+            LockMode::Exclusive => lock.try_exclusive()?,
+            _ => lock.try_shared()?,
+        }
+        Ok(lock)
+    }
+
+    /// Take an exclusive lock, failing if anyone else has the store open.
+    fn try_exclusive(&self) -> Result<(), CuttlestoreError> {
+        self.check(self.file.try_lock())
+    }
+
+    /// Take a shared lock, failing if anyone else has the store open
+    /// exclusively.
+    ///
+    /// This can also switch an exclusive lock to a shared one, but not
+    /// atomically: the exclusive lock is released first, and another process
+    /// may take the lock before the shared lock is taken.
+    pub(crate) fn try_shared(&self) -> Result<(), CuttlestoreError> {
+        self.check(self.file.try_lock_shared())
+    }
+
+    fn check(&self, result: Result<(), TryLockError>) -> Result<(), CuttlestoreError> {
+        match result {
+            Ok(()) => Ok(()),
+            Err(TryLockError::WouldBlock) => Err(CuttlestoreError::StoreLocked(
+                self.path.to_string_lossy().to_string(),
+            )),
+            Err(TryLockError::Error(error)) => Err(error)?,
+        }
+    }
+}
+
+/// The locks for individual keys.
+pub(crate) struct KeyLocks {
+    // The file locks are held by open file, so tasks within the same process
+    // would not block each other. The mutex makes them take turns too.
+    stripes: Vec<Arc<Mutex<File>>>,
+}
+
+impl KeyLocks {
+    pub(crate) fn open(base_folder: &Path) -> Result<Self, CuttlestoreError> {
+        let folder = base_folder.join(LOCKS_FOLDER);
+        std::fs::create_dir_all(&folder)?;
+        let stripes = (0..STRIPES)
+            .map(|stripe| {
+                let file = OpenOptions::new()
+                    .create(true)
+                    .truncate(false)
+                    .write(true)
+                    .open(folder.join(format!("{stripe:02x}")))?;
+                Ok(Arc::new(Mutex::new(file)))
+            })
+            .collect::<Result<_, CuttlestoreError>>()?;
+        Ok(KeyLocks { stripes })
+    }
+
+    /// Lock the key stored in this file. The lock is released when the
+    /// returned guard is dropped.
+    pub(crate) async fn lock(&self, file_name: &str) -> Result<KeyLockGuard, CuttlestoreError> {
+        let stripe = &self.stripes[(fnv1a(file_name.as_bytes()) % STRIPES) as usize];
+        let guard = stripe.clone().lock_owned().await;
+        let guard = match guard.try_lock() {
+            Ok(()) => guard,
+            // Another process holds it, wait for it without blocking the
+            // runtime.
+            Err(TryLockError::WouldBlock) => {
+                tokio::task::spawn_blocking(move || guard.lock().map(|_| guard))
+                    .await
+                    .expect("Waiting for a file lock panicked")?
+            }
+            Err(TryLockError::Error(error)) => Err(error)?,
+        };
+        Ok(KeyLockGuard { guard })
+    }
+}
+
+pub(crate) struct KeyLockGuard {
+    guard: OwnedMutexGuard<File>,
+}
+
+impl Drop for KeyLockGuard {
+    fn drop(&mut self) {
+        self.guard.unlock().ok();
+    }
+}
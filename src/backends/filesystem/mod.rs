@@ -1,85 +1,753 @@
 use async_stream::try_stream;
 use async_trait::async_trait;
-use futures::stream::BoxStream;
+use futures::{stream::BoxStream, StreamExt};
 use lazy_regex::regex_captures;
 use serde::{Deserialize, Serialize};
-use std::{borrow::Cow, ffi::OsString, io::ErrorKind, path::PathBuf, str::FromStr};
-use tokio::io::AsyncWriteExt;
-use tokio_stream::wrappers::ReadDirStream;
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    ffi::OsString,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+use tokio::{
+    io::AsyncWriteExt,
+    sync::{Mutex, MutexGuard},
+};
 
 use crate::{
-    backend_api::{CuttleBackend, PutOptions},
+    backend_api::{prefixed, CuttleBackend, PutOptions, RawEntry},
     common::{get_system_time, CuttlestoreError},
 };
 
+mod expiry;
+mod key;
+mod layout;
+mod lock;
+
+use layout::Layout;
+use lock::{KeyLockGuard, KeyLocks, LockMode, StoreLock};
+
 #[derive(Debug, Serialize, Deserialize)]
 struct StoredValue<'t> {
     payload: &'t [u8],
     live_until: Option<u64>,
 }
 
+/// Keys that are too long get a hashed file name, which can't be decoded back
+/// into the key. The key is stored along with the value for these instead.
+#[derive(Debug, Serialize, Deserialize)]
+struct StoredValueWithKey<'t> {
+    key: &'t str,
+    payload: &'t [u8],
+    live_until: Option<u64>,
+}
+
+/// This file marks that the store uses encoded file names. Stores created
+/// before keys were encoded don't have it, and get migrated when opened.
+///
+/// The file also records the layout of the store, as `layout=<name>`.
+const FORMAT_MARKER: &str = ".cuttlestore";
+/// Legacy files are moved here during the migration, so they can't clash with
+/// the encoded names.
+const MIGRATION_FOLDER: &str = ".migrating";
+/// Marks that all legacy files have been moved into the migration folder.
+const MIGRATION_MOVED_MARKER: &str = ".moved";
+/// The leases of coordinated cleaners are kept in this folder, apart from the
+/// values.
+const LEASES_FOLDER: &str = ".leases";
+/// Values are written to temporary files starting with this, before being
+/// moved into place.
+const TEMP_FILE_PREFIX: &str = ".tmp-";
+/// Temporary files older than this are assumed to be left over from a crash.
+const STALE_TEMP_FILE_AGE: Duration = Duration::from_secs(60 * 60);
+/// Keeps the temporary file names unique within the process.
+static TEMP_FILE_COUNTER: AtomicU64 = AtomicU64::new(0);
+
 pub(crate) struct FilesystemBackend {
     base_folder: PathBuf,
+    layout: Layout,
+    /// Locks for keys that are being changed, unless locking is disabled.
+    key_locks: Option<KeyLocks>,
+    /// Held while the store is open, so other processes know it's in use.
+    #[allow(dead_code)]
+    store_lock: Option<StoreLock>,
+    /// Guards changes to the expiry index within this process.
+    expiry_lock: Mutex<()>,
 }
 
 impl FilesystemBackend {
-    async fn new(base_folder: &str) -> Result<Box<FilesystemBackend>, CuttlestoreError> {
+    async fn new(
+        base_folder: &str,
+        args: HashMap<&str, &str>,
+    ) -> Result<Box<FilesystemBackend>, CuttlestoreError> {
+        let layout = match args.get("layout") {
+            Some(name) => Layout::parse(name).ok_or_else(|| {
+                CuttlestoreError::InvalidConnectionString(format!(
+                    "unknown filesystem layout {name}"
+                ))
+            })?,
+            None => Layout::Flat,
+        };
+        let lock_mode = match args.get("lock") {
+            Some(name) => LockMode::parse(name).ok_or_else(|| {
+                CuttlestoreError::InvalidConnectionString(format!(
+                    "unknown filesystem lock mode {name}"
+                ))
+            })?,
+            None => LockMode::Shared,
+        };
+
         tokio::fs::create_dir_all(base_folder).await?;
-        Ok(Box::new(FilesystemBackend {
-            base_folder: PathBuf::from_str(base_folder).expect("Unable to get the file path"),
-        }))
+        let base_folder = PathBuf::from_str(base_folder).expect("Unable to get the file path");
+        // Start out assuming the store is flat, until we know the store has
+        // been converted to the requested layout.
+        let mut backend = FilesystemBackend {
+            base_folder,
+            layout: Layout::Flat,
+            key_locks: None,
+            store_lock: None,
+            expiry_lock: Mutex::new(()),
+        };
+
+        // Files get moved around if the store has to be converted, nobody
+        // else can be using the store while that happens. A shared lock can't
+        // be upgraded atomically, another process could take the lock in
+        // between, so the lock is exclusive from the start if a conversion
+        // looks necessary.
+        let mut exclusive =
+            lock_mode == LockMode::Exclusive || backend.read_format_marker().await? != Some(layout);
+        let current_layout = loop {
+            if lock_mode != LockMode::None {
+                let mode = if exclusive {
+                    LockMode::Exclusive
+                } else {
+                    LockMode::Shared
+                };
+                // Replacing the lock releases the old one first
+                backend.store_lock = None;
+                backend.store_lock = Some(StoreLock::open(&backend.base_folder, mode)?);
+            }
+            // Check again now that we have the lock, another process may
+            // have converted the store in the meantime.
+            let current_layout = backend.read_format_marker().await?;
+            if current_layout == Some(layout) || exclusive || lock_mode == LockMode::None {
+                break current_layout;
+            }
+            exclusive = true;
+        };
+        if current_layout != Some(layout) {
+            if current_layout.is_none() {
+                backend.migrate_legacy_names().await?;
+            }
+            if current_layout.unwrap_or(Layout::Flat) != layout {
+                backend.convert_layout(layout).await?;
+            }
+        }
+        if let (Some(store_lock), LockMode::Shared, true) =
+            (&backend.store_lock, lock_mode, exclusive)
+        {
+            // This isn't atomic either. If another process takes the lock in
+            // between, this fails with `StoreLocked` rather than both
+            // processes using the store.
+            store_lock.try_shared()?;
+        }
+        backend.layout = layout;
+        if lock_mode != LockMode::None {
+            backend.key_locks = Some(KeyLocks::open(&backend.base_folder)?);
+        }
+        backend.remove_stale_temp_files().await?;
+        Ok(Box::new(backend))
     }
 
-    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, CuttlestoreError> {
-        match tokio::fs::read(self.base_folder.join(key)).await {
-            Ok(read) => {
-                let value: StoredValue = match bincode::serde::borrow_decode_from_slice(
+    /// Read the layout recorded in the format marker. Returns `None` if the
+    /// marker is missing.
+    async fn read_format_marker(&self) -> Result<Option<Layout>, CuttlestoreError> {
+        match tokio::fs::read_to_string(self.base_folder.join(FORMAT_MARKER)).await {
+            Ok(contents) => Ok(Some(
+                contents
+                    .lines()
+                    .find_map(|line| line.strip_prefix("layout="))
+                    .and_then(Layout::parse)
+                    // Markers written before layouts were added are empty
+                    .unwrap_or(Layout::Flat),
+            )),
+            Err(error) if error.kind() == ErrorKind::NotFound => Ok(None),
+            Err(error) => Err(error)?,
+        }
+    }
+
+    async fn write_format_marker(&self, layout: Layout) -> Result<(), CuttlestoreError> {
+        tokio::fs::write(
+            self.base_folder.join(FORMAT_MARKER),
+            format!("layout={}\n", layout.name()),
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Move all the files in the store into the given layout.
+    ///
+    /// Files are picked up from both the flat and sharded locations, so if
+    /// a conversion gets interrupted it can simply be run again. The format
+    /// marker is only updated once every file has been moved.
+    async fn convert_layout(&self, layout: Layout) -> Result<(), CuttlestoreError> {
+        let mut files: Vec<(PathBuf, String)> = Vec::new();
+        let mut shard_folders: Vec<PathBuf> = Vec::new();
+        let mut entries = tokio::fs::read_dir(&self.base_folder).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let name = os_string_to_string(&entry.file_name());
+            if name.starts_with('.') {
+                continue;
+            }
+            let file_type = entry.file_type().await?;
+            if file_type.is_file() {
+                files.push((entry.path(), name));
+            } else if file_type.is_dir() && layout::is_shard_folder(&name) {
+                let mut shards = tokio::fs::read_dir(entry.path()).await?;
+                while let Some(shard) = shards.next_entry().await? {
+                    let mut shard_entries = tokio::fs::read_dir(shard.path()).await?;
+                    while let Some(shard_entry) = shard_entries.next_entry().await? {
+                        files.push((
+                            shard_entry.path(),
+                            os_string_to_string(&shard_entry.file_name()),
+                        ));
+                    }
+                    shard_folders.push(shard.path());
+                }
+                shard_folders.push(entry.path());
+            }
+        }
+
+        for (path, file_name) in files {
+            let target = layout.path(&self.base_folder, &file_name);
+            if path == target {
+                continue;
+            }
+            if let Some(parent) = target.parent() {
+                tokio::fs::create_dir_all(parent).await?;
+            }
+            tokio::fs::rename(&path, &target).await?;
+        }
+
+        if layout == Layout::Flat {
+            // The inner folders come before the outer ones, so they are empty
+            // by the time the outer ones get removed.
+            for folder in shard_folders {
+                tokio::fs::remove_dir(folder).await.ok();
+            }
+        }
+
+        self.write_format_marker(layout).await
+    }
+
+    /// Stores created by older versions used the keys as file names directly.
+    /// Rename these files to use the encoded names.
+    ///
+    /// The migration is done in two phases so that it can be resumed if it is
+    /// interrupted: the legacy files are first moved into a separate folder,
+    /// then moved back under their encoded names. Because the encoding is
+    /// unique for each key, moving the files back can't overwrite anything.
+    async fn migrate_legacy_names(&self) -> Result<(), CuttlestoreError> {
+        let migration_folder = self.base_folder.join(MIGRATION_FOLDER);
+        let moved_marker = migration_folder.join(MIGRATION_MOVED_MARKER);
+
+        if !exists(&moved_marker).await? {
+            tokio::fs::create_dir_all(&migration_folder).await?;
+            let mut entries = tokio::fs::read_dir(&self.base_folder).await?;
+            while let Some(entry) = entries.next_entry().await? {
+                let legacy_key = os_string_to_string(&entry.file_name());
+                if legacy_key.starts_with('.') || !entry.file_type().await?.is_file() {
+                    continue;
+                }
+                if key::encode_key(&legacy_key) != legacy_key {
+                    tokio::fs::rename(entry.path(), migration_folder.join(&legacy_key)).await?;
+                }
+            }
+            tokio::fs::write(&moved_marker, b"").await?;
+        }
+
+        let mut entries = tokio::fs::read_dir(&migration_folder).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let legacy_key = os_string_to_string(&entry.file_name());
+            if legacy_key == MIGRATION_MOVED_MARKER {
+                continue;
+            }
+            let file_name = key::encode_key(&legacy_key);
+            if key::is_hashed(&file_name) {
+                // The hashed name loses the key, so it has to be written into
+                // the file instead.
+                let read = tokio::fs::read(entry.path()).await?;
+                match bincode::serde::borrow_decode_from_slice::<StoredValue, _>(
                     &read,
                     bincode::config::legacy(),
                 ) {
-                    Ok((val, _)) => val,
-                    Err(err) => {
-                        // Decoding failed. This likely suggests data corruption,
-                        // such as power loss while file was being written out.
+                    Ok((value, _)) => {
+                        self.write_file(&file_name, &legacy_key, value.payload, value.live_until)
+                            .await?;
+                    }
+                    Err(_err) => {
                         #[cfg(feature = "logging-log")]
-                        log::error!("Found potential data corruption for key {key}: {err:?}");
+                        log::error!(
+                            "Dropping corrupt value for key {legacy_key} during migration: {_err:?}"
+                        );
                         #[cfg(feature = "logging-tracing")]
-                        tracing::error!("Found potential data corruption for key {key}: {err:?}");
-                        self.delete(key).await?;
-                        return Ok(None);
-                    }
-                };
-
-                // If ttl is enabled, check in case it is expired
-                if let Some(live_until) = value.live_until {
-                    if live_until < get_system_time() {
-                        // If the value we got is expired, discard it
-                        self.delete(key).await?;
-                        return Ok(None);
+                        tracing::error!(
+                            "Dropping corrupt value for key {legacy_key} during migration: {_err:?}"
+                        );
                     }
                 }
-
-                Ok(Some(value.payload.to_owned()))
+                tokio::fs::remove_file(entry.path()).await?;
+            } else {
+                tokio::fs::rename(entry.path(), self.base_folder.join(&file_name)).await?;
             }
+        }
+
+        // Write the marker before cleaning up, so the migration can't run a
+        // second time on files that have already been migrated.
+        self.write_format_marker(Layout::Flat).await?;
+        tokio::fs::remove_dir_all(&migration_folder).await?;
+        Ok(())
+    }
+
+    /// Read the file holding an entry, without discarding corrupt entries.
+    ///
+    /// For hashed file names, this also returns the key that was stored in the
+    /// file.
+    async fn read_file(
+        &self,
+        file_name: &str,
+    ) -> Result<Option<(Option<String>, RawEntry)>, CuttlestoreError> {
+        let read = match tokio::fs::read(self.path(file_name)).await {
+            Ok(read) => read,
             Err(error) => match error.kind() {
                 // A not found IO error just means the key is missing, it's not a real error
-                ErrorKind::NotFound => Ok(None),
+                ErrorKind::NotFound => return Ok(None),
                 _ => Err(error)?,
             },
+        };
+
+        let decoded = decode_file(file_name, &read);
+        let (stored_key, payload, live_until) = match decoded {
+            Ok(decoded) => decoded,
+            Err(err) => {
+                return Ok(Some((
+                    None,
+                    RawEntry::Corrupt {
+                        raw: read,
+                        error: err.into(),
+                    },
+                )))
+            }
+        };
+        let stored_key = stored_key.map(|key| key.to_string());
+
+        // If ttl is enabled, check in case it is expired
+        if let Some(live_until) = live_until {
+            if live_until < get_system_time() {
+                // If the value we got is expired, discard it
+                self.remove_file_if_unchanged(file_name, &read).await?;
+                return Ok(Some((stored_key, RawEntry::Expired)));
+            }
+        }
+
+        Ok(Some((stored_key, RawEntry::Live(payload.to_owned()))))
+    }
+
+    /// Read the entry for a key, without discarding corrupt entries.
+    async fn read_entry(&self, key: &str) -> Result<Option<RawEntry>, CuttlestoreError> {
+        match self.read_file(&key::encode_key(key)).await? {
+            // A hashed name could in theory belong to a different key, if the
+            // hashes collide.
+            Some((Some(stored_key), _)) if stored_key != key => Ok(None),
+            Some((_, entry)) => Ok(Some(entry)),
+            None => Ok(None),
+        }
+    }
+
+    /// Read the entry from a file found during a scan, along with its key.
+    async fn read_scanned_file(
+        &self,
+        file_name: &str,
+    ) -> Result<Option<(String, RawEntry)>, CuttlestoreError> {
+        // Skip files that aren't ours
+        if file_name.starts_with('.') {
+            return Ok(None);
+        }
+        let decoded_key = key::decode_file_name(file_name);
+        match self.read_file(file_name).await? {
+            Some((Some(key), entry)) => Ok(Some((key, entry))),
+            Some((None, entry)) => match (decoded_key, entry) {
+                (Some(key), entry) => Ok(Some((key, entry))),
+                (None, RawEntry::Corrupt { .. }) => {
+                    // A hashed file that's corrupt, we can't tell what key it
+                    // belonged to. It's left in place so it can be looked at.
+                    #[cfg(feature = "logging-log")]
+                    log::error!("Found potential data corruption in file {file_name}");
+                    #[cfg(feature = "logging-tracing")]
+                    tracing::error!("Found potential data corruption in file {file_name}");
+                    Ok(None)
+                }
+                (None, _) => Ok(None),
+            },
+            None => Ok(None),
+        }
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, CuttlestoreError> {
+        match self.get_entry(key).await? {
+            Some(RawEntry::Live(payload)) => Ok(Some(payload)),
+            Some(RawEntry::Corrupt { .. } | RawEntry::Expired) | None => Ok(None),
+        }
+    }
+
+    /// Read the entry for a key, reporting a corrupt one. Corrupt files are
+    /// left in place, it's up to the store to quarantine them.
+    async fn get_entry(&self, key: &str) -> Result<Option<RawEntry>, CuttlestoreError> {
+        let entry = self.read_entry(key).await?;
+        if let Some(RawEntry::Corrupt { error: _error, .. }) = &entry {
+            // Decoding failed. This likely suggests data corruption,
+            // such as power loss while file was being written out.
+            #[cfg(feature = "logging-log")]
+            log::error!("Found potential data corruption for key {key}: {_error:?}");
+            #[cfg(feature = "logging-tracing")]
+            tracing::error!("Found potential data corruption for key {key}: {_error:?}");
+        }
+        Ok(entry)
+    }
+
+    async fn write_file(
+        &self,
+        file_name: &str,
+        key: &str,
+        payload: &[u8],
+        live_until: Option<u64>,
+    ) -> Result<(), CuttlestoreError> {
+        let encoded_value = if key::is_hashed(file_name) {
+            bincode::serde::encode_to_vec(
+                StoredValueWithKey {
+                    key,
+                    payload,
+                    live_until,
+                },
+                bincode::config::legacy(),
+            )?
+        } else {
+            bincode::serde::encode_to_vec(
+                StoredValue {
+                    payload,
+                    live_until,
+                },
+                bincode::config::legacy(),
+            )?
+        };
+
+        // Write the value out to a temporary file first, then move it over
+        // the actual file. The rename is atomic, so readers either see the old
+        // value or the new one, and a crash midway through can't leave a
+        // partially written value behind.
+        let temp_path = self.base_folder.join(format!(
+            "{TEMP_FILE_PREFIX}{}-{}-{file_name}",
+            std::process::id(),
+            TEMP_FILE_COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        let path = self.path(file_name);
+        let result = async {
+            let mut file = tokio::fs::File::create(&temp_path).await?;
+            file.write_all(&encoded_value[..]).await?;
+            file.sync_all().await?;
+            let _lock = self.lock(file_name).await?;
+            match tokio::fs::rename(&temp_path, &path).await {
+                // The shard folder may not have been created yet
+                Err(error)
+                    if error.kind() == ErrorKind::NotFound && self.layout == Layout::Sharded =>
+                {
+                    if let Some(parent) = path.parent() {
+                        tokio::fs::create_dir_all(parent).await?;
+                    }
+                    tokio::fs::rename(&temp_path, &path).await?;
+                }
+                result => result?,
+            }
+            Ok::<(), CuttlestoreError>(())
+        }
+        .await;
+        if let Err(error) = result {
+            tokio::fs::remove_file(&temp_path).await.ok();
+            return Err(error);
+        }
+        // The rename is only durable once the folder itself is synced.
+        if let Some(parent) = path.parent() {
+            sync_folder(parent).await?;
+        }
+
+        if let Some(live_until) = live_until {
+            let _lock = self.lock_expiry_index().await?;
+            expiry::append(
+                &self.base_folder.join(expiry::INDEX),
+                [(live_until, file_name)],
+            )
+            .await?;
         }
+
+        Ok(())
+    }
+
+    /// Lock the expiry index, so it can be changed.
+    async fn lock_expiry_index(
+        &self,
+    ) -> Result<(MutexGuard<'_, ()>, Option<KeyLockGuard>), CuttlestoreError> {
+        let guard = self.expiry_lock.lock().await;
+        let lock = self.lock(expiry::INDEX).await?;
+        Ok((guard, lock))
+    }
+
+    /// Delete the expired files, using the expiry index to find them.
+    ///
+    /// Once `limit` files have been removed, the rest are put back into the
+    /// index for the next sweep. The limit doesn't apply while the index is
+    /// being rebuilt, since every file has to be checked for that.
+    async fn purge_expired_files(&self, limit: Option<u64>) -> Result<u64, CuttlestoreError> {
+        let index = self.base_folder.join(expiry::INDEX);
+        let sweeping = self.base_folder.join(expiry::SWEEPING);
+        let rebuilding = self.base_folder.join(expiry::REBUILDING);
+
+        // Pick out the entries that need to be checked. If there is no index,
+        // then every file needs to be checked.
+        let entries = {
+            let _lock = self.lock_expiry_index().await?;
+            if !exists(&index).await? || exists(&rebuilding).await? {
+                tokio::fs::write(&rebuilding, b"").await?;
+                tokio::fs::write(&index, b"").await?;
+                tokio::fs::remove_file(&sweeping).await.ok();
+                None
+            } else {
+                // If a previous sweep was interrupted, finish that one first.
+                if !exists(&sweeping).await? {
+                    tokio::fs::rename(&index, &sweeping).await?;
+                    tokio::fs::write(&index, b"").await?;
+                }
+                Some(expiry::parse(&tokio::fs::read_to_string(&sweeping).await?))
+            }
+        };
+
+        let mut remaining = expiry::Remaining::default();
+        let mut due = Vec::new();
+        match &entries {
+            Some(entries) => {
+                let now = get_system_time();
+                for (live_until, file_name) in entries {
+                    if *live_until < now {
+                        due.push((Some(*live_until), file_name.clone()));
+                    } else {
+                        remaining.add(*live_until, file_name.clone());
+                    }
+                }
+            }
+            None => {
+                let mut file_names = self.file_names();
+                while let Some(file_name) = file_names.next().await {
+                    let file_name = file_name?;
+                    if !file_name.starts_with('.') {
+                        due.push((None, file_name));
+                    }
+                }
+            }
+        }
+
+        let mut removed = 0;
+        for (indexed, file_name) in due {
+            if let (Some(live_until), Some(limit)) = (indexed, limit) {
+                if removed >= limit {
+                    remaining.add(live_until, file_name);
+                    continue;
+                }
+            }
+            match self.check_expiry(&file_name).await? {
+                Expiry::Removed => removed += 1,
+                Expiry::LiveUntil(live_until) => remaining.add(live_until, file_name),
+                Expiry::Never => {}
+            }
+        }
+
+        let _lock = self.lock_expiry_index().await?;
+        expiry::append(&index, remaining.iter()).await?;
+        match entries {
+            Some(_) => tokio::fs::remove_file(&sweeping).await?,
+            None => tokio::fs::remove_file(&rebuilding).await?,
+        }
+        Ok(removed)
+    }
+
+    /// Check when the file expires, deleting it if it already has.
+    async fn check_expiry(&self, file_name: &str) -> Result<Expiry, CuttlestoreError> {
+        let read = match tokio::fs::read(self.path(file_name)).await {
+            Ok(read) => read,
+            Err(error) if error.kind() == ErrorKind::NotFound => return Ok(Expiry::Never),
+            Err(error) => Err(error)?,
+        };
+        match decode_file(file_name, &read) {
+            Ok((_, _, Some(live_until))) if live_until < get_system_time() => {
+                self.remove_file_if_unchanged(file_name, &read).await?;
+                Ok(Expiry::Removed)
+            }
+            Ok((_, _, Some(live_until))) => Ok(Expiry::LiveUntil(live_until)),
+            // Corrupt files are left for `get` and `scan` to deal with
+            Ok((_, _, None)) | Err(_) => Ok(Expiry::Never),
+        }
+    }
+
+    /// Remove temporary files that were left behind, if the program crashed
+    /// while writing a value.
+    ///
+    /// Only old files are removed, in case another process is writing to the
+    /// same folder right now.
+    async fn remove_stale_temp_files(&self) -> Result<(), CuttlestoreError> {
+        let mut entries = tokio::fs::read_dir(&self.base_folder).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            if !os_string_to_string(&entry.file_name()).starts_with(TEMP_FILE_PREFIX) {
+                continue;
+            }
+            let is_stale = entry
+                .metadata()
+                .await?
+                .modified()?
+                .elapsed()
+                .map(|age| age > STALE_TEMP_FILE_AGE)
+                .unwrap_or(false);
+            if is_stale {
+                tokio::fs::remove_file(entry.path()).await.ok();
+            }
+        }
+        Ok(())
     }
 
     async fn delete(&self, key: &str) -> Result<(), CuttlestoreError> {
-        tokio::fs::remove_file(self.base_folder.join(key)).await?;
+        self.remove_file(&key::encode_key(key)).await
+    }
+
+    /// Take or renew the lease, see `CuttleBackend::take_lease`. The lease is
+    /// read and replaced while holding the lock of its file.
+    async fn take_lease(
+        &self,
+        key: &str,
+        holder: &str,
+        ttl: u64,
+    ) -> Result<bool, CuttlestoreError> {
+        let file_name = format!("{LEASES_FOLDER}/{}", key::encode_key(key));
+        let _lock = self.lock(&file_name).await?;
+        let path = self.base_folder.join(&file_name);
+        let now = get_system_time();
+        match tokio::fs::read_to_string(&path).await {
+            // The lease is its expiration time followed by the holder. A lease
+            // that can't be parsed, for example because a crash cut the write
+            // short, is up for grabs.
+            Ok(lease) => {
+                let held = lease
+                    .split_once(' ')
+                    .and_then(|(live_until, current)| {
+                        Some((live_until.parse::<u64>().ok()?, current))
+                    })
+                    .is_some_and(|(live_until, current)| live_until >= now && current != holder);
+                if held {
+                    return Ok(false);
+                }
+            }
+            Err(error) if error.kind() == ErrorKind::NotFound => {
+                tokio::fs::create_dir_all(self.base_folder.join(LEASES_FOLDER)).await?;
+            }
+            Err(error) => Err(error)?,
+        }
+        tokio::fs::write(&path, format!("{} {holder}", now + ttl)).await?;
+        Ok(true)
+    }
+
+    async fn remove_file(&self, file_name: &str) -> Result<(), CuttlestoreError> {
+        let _lock = self.lock(file_name).await?;
+        tokio::fs::remove_file(self.path(file_name)).await?;
         Ok(())
     }
+
+    /// Remove the file, unless it has been changed since it was read.
+    ///
+    /// This is used when discarding expired values, so that if
+    /// someone puts a new value in the meantime the new value is kept.
+    async fn remove_file_if_unchanged(
+        &self,
+        file_name: &str,
+        read: &[u8],
+    ) -> Result<(), CuttlestoreError> {
+        let _lock = self.lock(file_name).await?;
+        let path = self.path(file_name);
+        match tokio::fs::read(&path).await {
+            Ok(current) if current == read => tokio::fs::remove_file(&path).await?,
+            Ok(_) => {}
+            Err(error) if error.kind() == ErrorKind::NotFound => {}
+            Err(error) => Err(error)?,
+        }
+        Ok(())
+    }
+
+    /// Lock the key stored in this file, if locking is enabled.
+    async fn lock(&self, file_name: &str) -> Result<Option<KeyLockGuard>, CuttlestoreError> {
+        match &self.key_locks {
+            Some(key_locks) => Ok(Some(key_locks.lock(file_name).await?)),
+            None => Ok(None),
+        }
+    }
+
+    /// The path to the file with this name, based on the store's layout.
+    fn path(&self, file_name: &str) -> PathBuf {
+        self.layout.path(&self.base_folder, file_name)
+    }
+
+    /// Stream the names of all the files in the store.
+    ///
+    /// This includes the files the backend uses internally, which start with
+    /// `.` and need to be skipped.
+    fn file_names(&self) -> BoxStream<'_, Result<String, CuttlestoreError>> {
+        Box::pin(try_stream! {
+            let mut entries = tokio::fs::read_dir(&self.base_folder).await?;
+            while let Some(entry) = entries.next_entry().await? {
+                let name = os_string_to_string(&entry.file_name());
+                match self.layout {
+                    Layout::Flat => {
+                        if entry.file_type().await?.is_file() {
+                            yield name;
+                        }
+                    }
+                    Layout::Sharded => {
+                        if !layout::is_shard_folder(&name) {
+                            continue;
+                        }
+                        let mut shards = tokio::fs::read_dir(entry.path()).await?;
+                        while let Some(shard) = shards.next_entry().await? {
+                            let mut shard_entries = tokio::fs::read_dir(shard.path()).await?;
+                            while let Some(shard_entry) = shard_entries.next_entry().await? {
+                                yield os_string_to_string(&shard_entry.file_name());
+                            }
+                        }
+                    }
+                }
+            }
+        })
+    }
 }
 
 #[async_trait]
 impl CuttleBackend for FilesystemBackend {
     async fn new(conn: &str) -> Option<Result<Box<Self>, CuttlestoreError>> {
-        if let Some((_, base_folder)) = regex_captures!(r#"^filesystem://(.+)"#, conn) {
-            Some(FilesystemBackend::new(base_folder).await)
+        if let Some((_, base_folder, args)) =
+            regex_captures!(r#"^filesystem://([^?]+)[?]?(.*)"#, conn)
+        {
+            let arg_pairs: HashMap<&str, &str> = args
+                .split('&')
+                .flat_map(|pair| pair.split_once('='))
+                .collect();
+            Some(FilesystemBackend::new(base_folder, arg_pairs).await)
         } else {
             None
         }
@@ -97,25 +765,24 @@ impl CuttleBackend for FilesystemBackend {
         Ok(self.get(key.as_ref()).await?)
     }
 
+    async fn get_entry_in<'a>(
+        &self,
+        prefix: Option<&'a str>,
+        key: &'a str,
+    ) -> Result<Option<RawEntry>, CuttlestoreError> {
+        self.get_entry(&prefixed(prefix, key)).await
+    }
+
     async fn put<'a>(
         &self,
         key: Cow<'a, str>,
         value: &[u8],
         options: PutOptions,
     ) -> Result<(), CuttlestoreError> {
-        let encoded_value = bincode::serde::encode_to_vec(
-            StoredValue {
-                payload: value,
-                live_until: options.ttl.map(|v| v + get_system_time()),
-            },
-            bincode::config::legacy(),
-        )?;
-
-        let mut file = tokio::fs::File::create(self.base_folder.join(key.as_ref())).await?;
-        file.write_all(&encoded_value[..]).await?;
-        file.sync_data().await?;
-
-        Ok(())
+        let key = key.as_ref();
+        let live_until = options.ttl.map(|v| v + get_system_time());
+        self.write_file(&key::encode_key(key), key, value, live_until)
+            .await
     }
 
     async fn delete<'a>(&self, key: Cow<'a, str>) -> Result<(), CuttlestoreError> {
@@ -123,26 +790,110 @@ impl CuttleBackend for FilesystemBackend {
         Ok(())
     }
 
+    async fn purge_expired(&self, limit: Option<u64>) -> Result<u64, CuttlestoreError> {
+        self.purge_expired_files(limit).await
+    }
+
+    async fn take_lease<'a>(
+        &self,
+        key: Cow<'a, str>,
+        holder: &'a str,
+        ttl: u64,
+    ) -> Result<bool, CuttlestoreError> {
+        self.take_lease(key.as_ref(), holder, ttl).await
+    }
+
     async fn scan(
         &self,
     ) -> Result<BoxStream<Result<(String, Vec<u8>), CuttlestoreError>>, CuttlestoreError> {
-        let read = tokio::fs::read_dir(&self.base_folder).await?;
-        let dir_entries = ReadDirStream::new(read);
+        let file_names = self.file_names();
+
+        Ok(Box::pin(try_stream! {
+          for await file_name in file_names {
+            let file_name = file_name?;
+
+            match self.read_scanned_file(&file_name).await? {
+                Some((key, RawEntry::Live(value))) => yield (key, value),
+                // Left in place, `scan_entries` reports it so the store can
+                // quarantine it.
+                Some((_key, RawEntry::Corrupt { error: _error, .. })) => {
+                    #[cfg(feature = "logging-log")]
+                    log::error!("Found potential data corruption for key {_key}: {_error:?}");
+                    #[cfg(feature = "logging-tracing")]
+                    tracing::error!("Found potential data corruption for key {_key}: {_error:?}");
+                }
+                Some((_, RawEntry::Expired)) | None => {}
+            }
+          }
+        }))
+    }
+
+    async fn scan_entries(
+        &self,
+    ) -> Result<BoxStream<Result<(String, RawEntry), CuttlestoreError>>, CuttlestoreError> {
+        let file_names = self.file_names();
 
         Ok(Box::pin(try_stream! {
-          for await entry in dir_entries {
-            let entry = entry?;
-            let key = os_string_to_string(&entry.file_name());
+          for await file_name in file_names {
+            let file_name = file_name?;
 
-            let value = self.get(&key).await?;
-            if let Some(value) = value {
-                yield (key, value);
+            if let Some((key, entry)) = self.read_scanned_file(&file_name).await? {
+                yield (key, entry);
             }
           }
         }))
     }
 }
 
+/// When a file is going to expire, see `check_expiry`.
+enum Expiry {
+    /// The file had expired, and it was deleted.
+    Removed,
+    /// The file will expire at this time.
+    LiveUntil(u64),
+    /// The file is not going to expire, or does not exist.
+    Never,
+}
+
+/// Decode the contents of a file, returning the key stored in it (for hashed
+/// file names), the payload, and the time it expires.
+#[allow(clippy::type_complexity)]
+fn decode_file<'t>(
+    file_name: &str,
+    read: &'t [u8],
+) -> Result<(Option<&'t str>, &'t [u8], Option<u64>), bincode::error::DecodeError> {
+    if key::is_hashed(file_name) {
+        bincode::serde::borrow_decode_from_slice::<StoredValueWithKey, _>(
+            read,
+            bincode::config::legacy(),
+        )
+        .map(|(value, _)| (Some(value.key), value.payload, value.live_until))
+    } else {
+        bincode::serde::borrow_decode_from_slice::<StoredValue, _>(read, bincode::config::legacy())
+            .map(|(value, _)| (None, value.payload, value.live_until))
+    }
+}
+
+/// Checks if a file or folder exists.
+async fn exists(path: &Path) -> Result<bool, CuttlestoreError> {
+    match tokio::fs::metadata(path).await {
+        Ok(_) => Ok(true),
+        Err(error) if error.kind() == ErrorKind::NotFound => Ok(false),
+        Err(error) => Err(error)?,
+    }
+}
+
+/// Flush changes to the folder (such as renames) out to the disk.
+async fn sync_folder(folder: &Path) -> Result<(), CuttlestoreError> {
+    // Windows does not support opening folders as files. Renames are
+    // journaled by NTFS anyway.
+    #[cfg(unix)]
+    tokio::fs::File::open(folder).await?.sync_all().await?;
+    #[cfg(not(unix))]
+    let _ = folder;
+    Ok(())
+}
+
 /// Makes a lossy conversion from an OS string to a regular string.
 ///
 /// The lossiness is not a problem for us, because we wouldn't have created a
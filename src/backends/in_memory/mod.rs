@@ -89,18 +89,78 @@ impl CuttleBackend for InMemoryBackend {
     async fn scan(
         &self,
     ) -> Result<BoxStream<Result<(String, Vec<u8>), CuttlestoreError>>, CuttlestoreError> {
-        Ok(Box::pin(futures::stream::iter(
-            self.map
-                .iter()
-                .filter(|v| {
-                    // Filter out expired pairs
-                    if let Some(live_until) = v.live_until {
-                        return live_until > get_system_time();
-                    }
-                    true
-                })
-                // Copy out the data
-                .map(|v| Ok((v.key().to_string(), v.payload.clone()))),
-        )))
+        // Collect the pairs up front. Iterating the map holds locks on it, so
+        // holding onto the iterator would deadlock any put or delete made
+        // while the stream is being consumed.
+        let pairs: Vec<_> = self
+            .map
+            .iter()
+            .filter(|v| {
+                // Filter out expired pairs
+                if let Some(live_until) = v.live_until {
+                    return live_until > get_system_time();
+                }
+                true
+            })
+            // Copy out the data
+            .map(|v| Ok((v.key().to_string(), v.payload.clone())))
+            .collect();
+        Ok(Box::pin(futures::stream::iter(pairs)))
+    }
+
+    async fn purge_expired(&self, limit: Option<u64>) -> Result<u64, CuttlestoreError> {
+        let now = get_system_time();
+        let mut removed = 0;
+        self.map.retain(|_, value| {
+            let expired = matches!(value.live_until, Some(live_until) if live_until < now)
+                && limit.is_none_or(|limit| removed < limit);
+            if expired {
+                removed += 1;
+            }
+            !expired
+        });
+        Ok(removed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn purge_expired_counts_removed() {
+        let backend = InMemoryBackend {
+            map: DashMap::new(),
+        };
+        let now = get_system_time();
+        for (key, live_until) in [
+            ("expired", Some(now - 10)),
+            ("also-expired", Some(now - 1)),
+            ("live", Some(now + 60)),
+            ("forever", None),
+        ] {
+            backend.map.insert(
+                key.to_string(),
+                StoredValue {
+                    payload: Vec::new(),
+                    live_until,
+                },
+            );
+        }
+
+        assert_eq!(
+            CuttleBackend::purge_expired(&backend, Some(1))
+                .await
+                .unwrap(),
+            1
+        );
+        assert_eq!(backend.map.len(), 3);
+        assert_eq!(
+            CuttleBackend::purge_expired(&backend, None).await.unwrap(),
+            1
+        );
+        assert_eq!(backend.map.len(), 2);
+        assert!(backend.map.contains_key("live"));
+        assert!(backend.map.contains_key("forever"));
     }
 }
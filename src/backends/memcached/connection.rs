@@ -0,0 +1,137 @@
+//! A minimal client for the memcached text protocol, which only covers the
+//! handful of commands we need.
+
+use std::io::{Error, ErrorKind, Result};
+
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufStream},
+    net::TcpStream,
+};
+
+pub(crate) struct Connection {
+    stream: BufStream<TcpStream>,
+    /// Set if a command failed midway. The responses could be out of sync with
+    /// the requests, so the connection can't be used anymore.
+    broken: bool,
+}
+
+impl Connection {
+    pub(crate) async fn connect(address: &str) -> Result<Self> {
+        let stream = TcpStream::connect(address).await?;
+        stream.set_nodelay(true)?;
+        Ok(Connection {
+            stream: BufStream::new(stream),
+            broken: false,
+        })
+    }
+
+    pub(crate) fn is_broken(&self) -> bool {
+        self.broken
+    }
+
+    pub(crate) async fn get(&mut self, key: &str) -> Result<Option<Vec<u8>>> {
+        self.broken = true;
+        self.write_line(&format!("get {key}")).await?;
+        let line = self.read_line().await?;
+        if line == "END" {
+            self.broken = false;
+            return Ok(None);
+        }
+        // VALUE <key> <flags> <bytes>
+        let len = match line
+            .strip_prefix("VALUE ")
+            .map(|rest| rest.split(' ').nth(2))
+        {
+            Some(Some(len)) => len
+                .parse::<usize>()
+                .map_err(|_| unexpected_response(&line))?,
+            _ => return Err(response_error(&line)),
+        };
+        // The value is followed by a line break
+        let mut value = vec![0; len + 2];
+        self.stream.read_exact(&mut value).await?;
+        value.truncate(len);
+        let end = self.read_line().await?;
+        if end != "END" {
+            return Err(unexpected_response(&end));
+        }
+        self.broken = false;
+        Ok(Some(value))
+    }
+
+    pub(crate) async fn set(&mut self, key: &str, exptime: i64, value: &[u8]) -> Result<()> {
+        self.broken = true;
+        self.write_line(&format!("set {key} 0 {exptime} {}", value.len()))
+            .await?;
+        self.stream.write_all(value).await?;
+        self.write_line("").await?;
+        match self.read_line().await?.as_str() {
+            "STORED" => {
+                self.broken = false;
+                Ok(())
+            }
+            line => Err(response_error(line)),
+        }
+    }
+
+    pub(crate) async fn delete(&mut self, key: &str) -> Result<()> {
+        self.broken = true;
+        self.write_line(&format!("delete {key}")).await?;
+        match self.read_line().await?.as_str() {
+            "DELETED" | "NOT_FOUND" => {
+                self.broken = false;
+                Ok(())
+            }
+            line => Err(response_error(line)),
+        }
+    }
+
+    pub(crate) async fn version(&mut self) -> Result<String> {
+        self.broken = true;
+        self.write_line("version").await?;
+        let line = self.read_line().await?;
+        match line.strip_prefix("VERSION ") {
+            Some(version) => {
+                self.broken = false;
+                Ok(version.to_string())
+            }
+            None => Err(response_error(&line)),
+        }
+    }
+
+    async fn write_line(&mut self, line: &str) -> Result<()> {
+        self.stream.write_all(line.as_bytes()).await?;
+        self.stream.write_all(b"\r\n").await?;
+        self.stream.flush().await
+    }
+
+    async fn read_line(&mut self) -> Result<String> {
+        let mut line = String::new();
+        if self.stream.read_line(&mut line).await? == 0 {
+            return Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                "memcached closed the connection",
+            ));
+        }
+        Ok(line.trim_end_matches(['\r', '\n']).to_string())
+    }
+}
+
+/// Turn an error response from memcached into an error.
+fn response_error(line: &str) -> Error {
+    if let Some(message) = line
+        .strip_prefix("SERVER_ERROR ")
+        .or_else(|| line.strip_prefix("CLIENT_ERROR "))
+    {
+        Error::other(format!("memcached returned an error: {message}"))
+    } else {
+        unexpected_response(line)
+    }
+}
+
+fn unexpected_response(line: &str) -> Error {
+    Error::new(
+        ErrorKind::InvalidData,
+        format!("unexpected response from memcached: {line}"),
+    )
+}
@@ -0,0 +1,246 @@
+use std::borrow::Cow;
+
+use async_trait::async_trait;
+use bb8::{ManageConnection, Pool, RunError};
+use futures::stream::BoxStream;
+use lazy_regex::regex_captures;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    backend_api::{CuttleBackend, PutOptions},
+    common::{fnv1a, get_system_time, CuttlestoreError},
+};
+
+mod connection;
+
+use connection::Connection;
+
+const DEFAULT_PORT: u16 = 11211;
+/// Memcached refuses keys longer than this.
+const MAX_KEY_LEN: usize = 250;
+/// Expiration times longer than this are treated by memcached as a unix
+/// timestamp, rather than a number of seconds from now.
+const MAX_RELATIVE_EXPTIME: u64 = 60 * 60 * 24 * 30;
+
+/// Keys that are too long are hashed, and the key is stored along with the
+/// value so that hash collisions can be detected.
+#[derive(Debug, Serialize, Deserialize)]
+struct StoredValueWithKey<'t> {
+    key: &'t str,
+    payload: &'t [u8],
+}
+
+struct ConnectionManager {
+    address: String,
+}
+
+impl ManageConnection for ConnectionManager {
+    type Connection = Connection;
+    type Error = std::io::Error;
+
+    async fn connect(&self) -> Result<Connection, std::io::Error> {
+        Connection::connect(&self.address).await
+    }
+
+    async fn is_valid(&self, connection: &mut Connection) -> Result<(), std::io::Error> {
+        connection.version().await.map(|_| ())
+    }
+
+    fn has_broken(&self, connection: &mut Connection) -> bool {
+        connection.is_broken()
+    }
+}
+
+pub(crate) struct MemcachedBackend {
+    /// One pool per server. Keys are spread over the servers by their hash.
+    pools: Vec<Pool<ConnectionManager>>,
+}
+
+impl MemcachedBackend {
+    async fn new(servers: &str) -> Result<Box<Self>, CuttlestoreError> {
+        let mut pools = Vec::new();
+        for server in servers.split(',').filter(|server| !server.is_empty()) {
+            let address = if server.contains(':') {
+                server.to_string()
+            } else {
+                format!("{server}:{DEFAULT_PORT}")
+            };
+            let pool = Pool::builder()
+                .build(ConnectionManager { address })
+                .await
+                .map_err(memcached_error)?;
+            pools.push(pool);
+        }
+        if pools.is_empty() {
+            return Err(CuttlestoreError::InvalidConnectionString(
+                "no memcached servers given".to_string(),
+            ));
+        }
+        Ok(Box::new(MemcachedBackend { pools }))
+    }
+
+    async fn connection(
+        &self,
+        key: &str,
+    ) -> Result<bb8::PooledConnection<'_, ConnectionManager>, CuttlestoreError> {
+        let pool = &self.pools[(fnv1a(key.as_bytes()) % self.pools.len() as u64) as usize];
+        pool.get().await.map_err(|error| match error {
+            RunError::User(error) => memcached_error(error),
+            RunError::TimedOut => {
+                memcached_error("timed out while connecting to memcached".to_string())
+            }
+        })
+    }
+}
+
+#[async_trait]
+impl CuttleBackend for MemcachedBackend {
+    async fn new(conn: &str) -> Option<Result<Box<Self>, CuttlestoreError>> {
+        let (_, servers) = regex_captures!(r#"^memcached://(.+)$"#, conn)?;
+        Some(MemcachedBackend::new(servers).await)
+    }
+
+    fn requires_cleaner(&self) -> bool {
+        false
+    }
+
+    fn name(&self) -> &'static str {
+        "memcached"
+    }
+
+    async fn get<'a>(&self, key: Cow<'a, str>) -> Result<Option<Vec<u8>>, CuttlestoreError> {
+        let encoded = encode_key(&key);
+        let value = self
+            .connection(&encoded)
+            .await?
+            .get(&encoded)
+            .await
+            .map_err(memcached_error)?;
+        match value {
+            Some(value) if is_hashed(&encoded) => {
+                let (stored, _): (StoredValueWithKey, _) =
+                    bincode::serde::borrow_decode_from_slice(&value, bincode::config::legacy())?;
+                // A different key that happens to have the same hash
+                if stored.key != key {
+                    return Ok(None);
+                }
+                Ok(Some(stored.payload.to_vec()))
+            }
+            value => Ok(value),
+        }
+    }
+
+    async fn put<'a>(
+        &self,
+        key: Cow<'a, str>,
+        value: &[u8],
+        options: PutOptions,
+    ) -> Result<(), CuttlestoreError> {
+        let encoded = encode_key(&key);
+        let value = if is_hashed(&encoded) {
+            Cow::Owned(bincode::serde::encode_to_vec(
+                StoredValueWithKey {
+                    key: &key,
+                    payload: value,
+                },
+                bincode::config::legacy(),
+            )?)
+        } else {
+            Cow::Borrowed(value)
+        };
+        self.connection(&encoded)
+            .await?
+            .set(&encoded, exptime(options.ttl), &value)
+            .await
+            .map_err(memcached_error)
+    }
+
+    async fn delete<'a>(&self, key: Cow<'a, str>) -> Result<(), CuttlestoreError> {
+        let encoded = encode_key(&key);
+        self.connection(&encoded)
+            .await?
+            .delete(&encoded)
+            .await
+            .map_err(memcached_error)
+    }
+
+    async fn scan(
+        &self,
+    ) -> Result<BoxStream<Result<(String, Vec<u8>), CuttlestoreError>>, CuttlestoreError> {
+        // Memcached has no way to list the keys it holds
+        Err(CuttlestoreError::Unsupported(self.name(), "scanning"))
+    }
+}
+
+fn memcached_error(error: impl ToString) -> CuttlestoreError {
+    CuttlestoreError::MemcachedError(error.to_string())
+}
+
+/// Convert a ttl into a memcached expiration time.
+fn exptime(ttl: Option<u64>) -> i64 {
+    match ttl {
+        None => 0,
+        // 0 means the value never expires, a negative time expires it
+        // immediately.
+        Some(0) => -1,
+        Some(ttl) if ttl <= MAX_RELATIVE_EXPTIME => ttl as i64,
+        // Anything longer has to be given as an absolute time
+        Some(ttl) => (get_system_time() + ttl) as i64,
+    }
+}
+
+/// Memcached keys can't contain spaces or control characters, and are limited
+/// to 250 bytes. Those characters are percent-encoded, and keys that are still
+/// too long are hashed.
+fn encode_key(key: &str) -> String {
+    let mut encoded = String::with_capacity(key.len());
+    for char in key.chars() {
+        if char.is_ascii_control() || char == ' ' || char == '%' {
+            encoded.push_str(&format!("%{:02X}", char as u8));
+        } else {
+            encoded.push(char);
+        }
+    }
+    if encoded.is_empty() || encoded.len() > MAX_KEY_LEN || encoded.starts_with('~') {
+        // `~` marks hashed keys, so escape keys that start with it too
+        format!("~{:016x}", fnv1a(key.as_bytes()))
+    } else {
+        encoded
+    }
+}
+
+fn is_hashed(encoded: &str) -> bool {
+    encoded.starts_with('~')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn short_ttls_are_relative() {
+        assert_eq!(exptime(None), 0);
+        assert_eq!(exptime(Some(0)), -1);
+        assert_eq!(exptime(Some(60)), 60);
+        assert_eq!(
+            exptime(Some(MAX_RELATIVE_EXPTIME)),
+            MAX_RELATIVE_EXPTIME as i64
+        );
+    }
+
+    #[test]
+    fn long_ttls_are_absolute() {
+        let ttl = MAX_RELATIVE_EXPTIME + 1;
+        assert!(exptime(Some(ttl)) >= (get_system_time() + ttl) as i64);
+    }
+
+    #[test]
+    fn keys_are_encoded() {
+        assert_eq!(encode_key("foo:bar"), "foo:bar");
+        assert_eq!(encode_key("foo bar\n"), "foo%20bar%0A");
+        assert_eq!(encode_key("100%"), "100%25");
+        assert!(is_hashed(&encode_key(&"k".repeat(300))));
+        assert!(is_hashed(&encode_key("")));
+        assert!(is_hashed(&encode_key("~tilde")));
+    }
+}
@@ -1,3 +1,5 @@
+#[cfg(feature = "backend-log")]
+pub(crate) mod append_log;
 #[cfg(feature = "backend-couchdb-core")]
 pub(crate) mod couchdb;
 #[cfg(feature = "backend-dynamodb")]
@@ -6,6 +8,14 @@ pub(crate) mod dynamodb;
 pub(crate) mod filesystem;
 #[cfg(feature = "backend-in-memory")]
 pub(crate) mod in_memory;
+#[cfg(feature = "backend-memcached")]
+pub(crate) mod memcached;
+#[cfg(feature = "backend-mysql-core")]
+pub(crate) mod mysql;
+#[cfg(feature = "backend-postgres-core")]
+pub(crate) mod postgres;
+#[cfg(feature = "backend-redb")]
+pub(crate) mod redb;
 #[cfg(feature = "backend-redis")]
 pub(crate) mod redis;
 #[cfg(feature = "backend-sqlite-core")]
@@ -0,0 +1,214 @@
+use std::{borrow::Cow, collections::HashMap, str::FromStr};
+
+use async_trait::async_trait;
+use futures::{stream::BoxStream, TryStreamExt};
+use lazy_regex::{regex_captures, regex_is_match};
+use sqlx::mysql::{MySqlConnectOptions, MySqlPool};
+
+use crate::{
+    backend_api::{CuttleBackend, PutOptions},
+    common::{get_system_time, CuttlestoreError},
+};
+
+/// These connection string options are handled by Cuttlestore, everything else
+/// is passed along to the MySQL driver.
+const OPTIONS: &[&str] = &["table"];
+
+/// The longest key InnoDB can index with utf8mb4, in characters. This includes
+/// the prefix of the store.
+const MAX_KEY_LEN: usize = 768;
+
+pub(crate) struct MysqlBackend {
+    pool: MySqlPool,
+    queries: Queries,
+}
+
+/// The table name can't be a query parameter, so the queries are put together
+/// once when the store is opened.
+struct Queries {
+    get: String,
+    put: String,
+    delete: String,
+    scan: String,
+    purge_expired: String,
+    purge_expired_limited: String,
+    take_lease: String,
+    lease_holder: String,
+}
+
+impl Queries {
+    fn new(table: &str) -> Self {
+        Queries {
+            get: format!("SELECT value FROM {table} WHERE `key` = ? AND (live_until IS NULL OR live_until >= ?)"),
+            // `VALUES()` is deprecated in newer MySQL versions, but it's the
+            // only syntax that MariaDB also supports.
+            put: format!("INSERT INTO {table} (`key`, value, live_until) VALUES (?, ?, ?) ON DUPLICATE KEY UPDATE value = VALUES(value), live_until = VALUES(live_until)"),
+            delete: format!("DELETE FROM {table} WHERE `key` = ?"),
+            scan: format!("SELECT `key`, value FROM {table} WHERE live_until IS NULL OR live_until >= ?"),
+            purge_expired: format!("DELETE FROM {table} WHERE live_until < ?"),
+            purge_expired_limited: format!("DELETE FROM {table} WHERE live_until < ? ORDER BY live_until LIMIT ?"),
+            // The assignments run in order, so `live_until` is only renewed
+            // if `holder` has just been set to the new holder.
+            take_lease: format!("INSERT INTO {table}_leases (`key`, holder, live_until) VALUES (?, ?, ?) ON DUPLICATE KEY UPDATE holder = IF(holder = VALUES(holder) OR live_until < ?, VALUES(holder), holder), live_until = IF(holder = VALUES(holder), VALUES(live_until), live_until)"),
+            lease_holder: format!("SELECT holder FROM {table}_leases WHERE `key` = ?"),
+        }
+    }
+}
+
+impl MysqlBackend {
+    async fn new(url: &str, args: HashMap<&str, &str>) -> Result<Box<Self>, CuttlestoreError> {
+        let table = args.get("table").copied().unwrap_or("cuttlestore");
+        // The table name has to be inlined into the queries, so only allow
+        // plain identifiers.
+        if !regex_is_match!(r#"^[A-Za-z_][A-Za-z0-9_]*$"#, table) {
+            return Err(CuttlestoreError::InvalidConnectionString(format!(
+                "invalid MySQL table name {table}"
+            )));
+        }
+
+        let options = MySqlConnectOptions::from_str(url)?;
+        let pool = MySqlPool::connect_with(options).await?;
+
+        // Keys use a binary collation, otherwise lookups would ignore case and
+        // trailing spaces.
+        sqlx::query(&format!(
+            "CREATE TABLE IF NOT EXISTS {table} (`key` VARCHAR({MAX_KEY_LEN}) CHARACTER SET utf8mb4 COLLATE utf8mb4_bin NOT NULL PRIMARY KEY, value LONGBLOB NOT NULL, live_until BIGINT NULL, INDEX {table}_live_until (live_until))"
+        ))
+        .execute(&pool)
+        .await?;
+        sqlx::query(&format!(
+            "CREATE TABLE IF NOT EXISTS {table}_leases (`key` VARCHAR({MAX_KEY_LEN}) CHARACTER SET utf8mb4 COLLATE utf8mb4_bin NOT NULL PRIMARY KEY, holder VARCHAR(255) NOT NULL, live_until BIGINT NOT NULL)"
+        ))
+        .execute(&pool)
+        .await?;
+
+        Ok(Box::new(MysqlBackend {
+            pool,
+            queries: Queries::new(table),
+        }))
+    }
+}
+
+#[async_trait]
+impl CuttleBackend for MysqlBackend {
+    async fn new(conn: &str) -> Option<Result<Box<Self>, CuttlestoreError>> {
+        let (_, url, args) = regex_captures!(r#"^((?:mysql|mariadb)://[^?]+)[?]?(.*)"#, conn)?;
+        let (ours, theirs): (Vec<&str>, Vec<&str>) = args
+            .split('&')
+            .filter(|pair| !pair.is_empty())
+            .partition(|pair| {
+                let name = pair.split_once('=').map_or(*pair, |(name, _)| name);
+                OPTIONS.contains(&name)
+            });
+        let arg_pairs: HashMap<&str, &str> = ours
+            .into_iter()
+            .flat_map(|pair| pair.split_once('='))
+            .collect();
+        // The driver only knows the mysql scheme, but MariaDB speaks the same
+        // protocol.
+        let url = url.replacen("mariadb://", "mysql://", 1);
+        let url = if theirs.is_empty() {
+            url
+        } else {
+            format!("{url}?{}", theirs.join("&"))
+        };
+
+        Some(MysqlBackend::new(&url, arg_pairs).await)
+    }
+
+    fn requires_cleaner(&self) -> bool {
+        true
+    }
+
+    fn name(&self) -> &'static str {
+        "mysql"
+    }
+
+    async fn get<'a>(&self, key: Cow<'a, str>) -> Result<Option<Vec<u8>>, CuttlestoreError> {
+        let row: Option<(Vec<u8>,)> = sqlx::query_as(&self.queries.get)
+            .bind(key.as_ref())
+            .bind(get_system_time() as i64)
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(row.map(|(value,)| value))
+    }
+
+    async fn put<'a>(
+        &self,
+        key: Cow<'a, str>,
+        value: &[u8],
+        options: PutOptions,
+    ) -> Result<(), CuttlestoreError> {
+        // MySQL would reject it with a less helpful error, or cut the key
+        // short if strict mode is off.
+        if key.chars().count() > MAX_KEY_LEN {
+            return Err(CuttlestoreError::Unsupported(
+                "mysql",
+                "keys longer than 768 characters",
+            ));
+        }
+        let live_until = options.ttl.map(|t| (t + get_system_time()) as i64);
+        sqlx::query(&self.queries.put)
+            .bind(key.as_ref())
+            .bind(value)
+            .bind(live_until)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn delete<'a>(&self, key: Cow<'a, str>) -> Result<(), CuttlestoreError> {
+        sqlx::query(&self.queries.delete)
+            .bind(key.as_ref())
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn scan(
+        &self,
+    ) -> Result<BoxStream<Result<(String, Vec<u8>), CuttlestoreError>>, CuttlestoreError> {
+        let rows = sqlx::query_as::<_, (String, Vec<u8>)>(&self.queries.scan)
+            .bind(get_system_time() as i64)
+            .fetch(&self.pool);
+
+        Ok(Box::pin(rows.map_err(CuttlestoreError::from)))
+    }
+
+    async fn purge_expired(&self, limit: Option<u64>) -> Result<u64, CuttlestoreError> {
+        let query = match limit {
+            Some(limit) => sqlx::query(&self.queries.purge_expired_limited)
+                .bind(get_system_time() as i64)
+                .bind(limit as i64),
+            None => sqlx::query(&self.queries.purge_expired).bind(get_system_time() as i64),
+        };
+        let result = query.execute(&self.pool).await?;
+        Ok(result.rows_affected())
+    }
+
+    async fn take_lease<'a>(
+        &self,
+        key: Cow<'a, str>,
+        holder: &'a str,
+        ttl: u64,
+    ) -> Result<bool, CuttlestoreError> {
+        // The affected row count doesn't tell if the upsert replaced the
+        // lease, so it is read back. Nobody else can take it in between,
+        // since it has only just been renewed.
+        let now = get_system_time();
+        sqlx::query(&self.queries.take_lease)
+            .bind(key.as_ref())
+            .bind(holder)
+            .bind((now + ttl) as i64)
+            .bind(now as i64)
+            .execute(&self.pool)
+            .await?;
+        let current: Option<(String,)> = sqlx::query_as(&self.queries.lease_holder)
+            .bind(key.as_ref())
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(current.is_some_and(|(current,)| current == holder))
+    }
+}
@@ -0,0 +1,268 @@
+use std::{borrow::Cow, collections::HashMap, str::FromStr};
+
+use async_trait::async_trait;
+use futures::{stream::BoxStream, StreamExt, TryStreamExt};
+use lazy_regex::{regex_captures, regex_is_match};
+use sqlx::postgres::{PgConnectOptions, PgListener, PgPool};
+
+use crate::{
+    backend_api::{CuttleBackend, PutOptions},
+    common::{get_system_time, CuttlestoreError},
+    Change,
+};
+
+/// These connection string options are handled by Cuttlestore, everything else
+/// is passed along to the Postgres driver.
+const OPTIONS: &[&str] = &["table", "unlogged", "notify"];
+
+/// Postgres refuses notifications with payloads longer than 8000 bytes. Changes
+/// to keys longer than this are not reported, rather than failing the write.
+const MAX_NOTIFY_KEY_LEN: usize = 7900;
+
+pub(crate) struct PostgresBackend {
+    pool: PgPool,
+    table: String,
+    notify: bool,
+    queries: Queries,
+}
+
+/// The table name can't be a query parameter, so the queries are put together
+/// once when the store is opened.
+struct Queries {
+    get: String,
+    put: String,
+    delete: String,
+    scan: String,
+    purge_expired: String,
+    purge_expired_limited: String,
+    take_lease: String,
+}
+
+impl Queries {
+    fn new(table: &str) -> Self {
+        Queries {
+            get: format!("SELECT value FROM {table} WHERE key = $1 AND (live_until IS NULL OR live_until >= $2)"),
+            put: format!("INSERT INTO {table} (key, value, live_until) VALUES ($1, $2, $3) ON CONFLICT (key) DO UPDATE SET value = EXCLUDED.value, live_until = EXCLUDED.live_until"),
+            delete: format!("DELETE FROM {table} WHERE key = $1"),
+            scan: format!("SELECT key, value FROM {table} WHERE live_until IS NULL OR live_until >= $1"),
+            purge_expired: format!("DELETE FROM {table} WHERE live_until < $1"),
+            purge_expired_limited: format!("DELETE FROM {table} WHERE key IN (SELECT key FROM {table} WHERE live_until < $1 LIMIT $2)"),
+            take_lease: format!("INSERT INTO {table}_leases (key, holder, live_until) VALUES ($1, $2, $3) ON CONFLICT (key) DO UPDATE SET holder = EXCLUDED.holder, live_until = EXCLUDED.live_until WHERE {table}_leases.holder = EXCLUDED.holder OR {table}_leases.live_until < $4"),
+        }
+    }
+}
+
+impl PostgresBackend {
+    async fn new(url: &str, args: HashMap<&str, &str>) -> Result<Box<Self>, CuttlestoreError> {
+        let table = args.get("table").copied().unwrap_or("cuttlestore");
+        // The table name has to be inlined into the queries, so only allow
+        // plain identifiers.
+        if !regex_is_match!(r#"^[A-Za-z_][A-Za-z0-9_]*$"#, table) {
+            return Err(CuttlestoreError::InvalidConnectionString(format!(
+                "invalid Postgres table name {table}"
+            )));
+        }
+        let unlogged = parse_flag(&args, "unlogged")?;
+        let notify = parse_flag(&args, "notify")?;
+
+        let options = PgConnectOptions::from_str(url)?;
+        let pool = PgPool::connect_with(options).await?;
+
+        // Unlogged tables skip the write-ahead log, which makes them much
+        // faster but they get emptied if the server crashes.
+        let unlogged = if unlogged { "UNLOGGED" } else { "" };
+        sqlx::query(&format!(
+            "CREATE {unlogged} TABLE IF NOT EXISTS {table} (key TEXT PRIMARY KEY NOT NULL, value BYTEA NOT NULL, live_until BIGINT)"
+        ))
+        .execute(&pool)
+        .await?;
+        // Only values with a ttl are indexed, which is all the cleaner needs
+        sqlx::query(&format!(
+            "CREATE INDEX IF NOT EXISTS {table}_live_until ON {table} (live_until) WHERE live_until IS NOT NULL"
+        ))
+        .execute(&pool)
+        .await?;
+        sqlx::query(&format!(
+            "CREATE TABLE IF NOT EXISTS {table}_leases (key TEXT PRIMARY KEY NOT NULL, holder TEXT NOT NULL, live_until BIGINT NOT NULL)"
+        ))
+        .execute(&pool)
+        .await?;
+
+        if notify {
+            // A trigger catches the changes made by every client, not just
+            // Cuttlestore.
+            sqlx::query(&format!(
+                "CREATE OR REPLACE FUNCTION {table}_notify() RETURNS trigger AS $$
+                BEGIN
+                    IF TG_OP = 'DELETE' THEN
+                        IF octet_length(OLD.key) <= {MAX_NOTIFY_KEY_LEN} THEN
+                            PERFORM pg_notify('{table}', 'delete:' || OLD.key);
+                        END IF;
+                    ELSIF octet_length(NEW.key) <= {MAX_NOTIFY_KEY_LEN} THEN
+                        PERFORM pg_notify('{table}', 'put:' || NEW.key);
+                    END IF;
+                    RETURN NULL;
+                END;
+                $$ LANGUAGE plpgsql"
+            ))
+            .execute(&pool)
+            .await?;
+            sqlx::query(&format!(
+                "CREATE OR REPLACE TRIGGER {table}_notify AFTER INSERT OR UPDATE OR DELETE ON {table} FOR EACH ROW EXECUTE FUNCTION {table}_notify()"
+            ))
+            .execute(&pool)
+            .await?;
+        }
+
+        Ok(Box::new(PostgresBackend {
+            pool,
+            table: table.to_string(),
+            notify,
+            queries: Queries::new(table),
+        }))
+    }
+}
+
+fn parse_flag(args: &HashMap<&str, &str>, name: &str) -> Result<bool, CuttlestoreError> {
+    match args.get(name) {
+        None | Some(&"false") => Ok(false),
+        Some(&"true") => Ok(true),
+        Some(value) => Err(CuttlestoreError::InvalidConnectionString(format!(
+            "{name} must be true or false, got {value}"
+        ))),
+    }
+}
+
+#[async_trait]
+impl CuttleBackend for PostgresBackend {
+    async fn new(conn: &str) -> Option<Result<Box<Self>, CuttlestoreError>> {
+        let (_, url, args) = regex_captures!(r#"^(postgres(?:ql)?://[^?]+)[?]?(.*)"#, conn)?;
+        let (ours, theirs): (Vec<&str>, Vec<&str>) = args
+            .split('&')
+            .filter(|pair| !pair.is_empty())
+            .partition(|pair| {
+                let name = pair.split_once('=').map_or(*pair, |(name, _)| name);
+                OPTIONS.contains(&name)
+            });
+        let arg_pairs: HashMap<&str, &str> = ours
+            .into_iter()
+            .flat_map(|pair| pair.split_once('='))
+            .collect();
+        let url = if theirs.is_empty() {
+            url.to_string()
+        } else {
+            format!("{url}?{}", theirs.join("&"))
+        };
+
+        Some(PostgresBackend::new(&url, arg_pairs).await)
+    }
+
+    fn requires_cleaner(&self) -> bool {
+        true
+    }
+
+    fn name(&self) -> &'static str {
+        "postgres"
+    }
+
+    async fn get<'a>(&self, key: Cow<'a, str>) -> Result<Option<Vec<u8>>, CuttlestoreError> {
+        let row: Option<(Vec<u8>,)> = sqlx::query_as(&self.queries.get)
+            .bind(key.as_ref())
+            .bind(get_system_time() as i64)
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(row.map(|(value,)| value))
+    }
+
+    async fn put<'a>(
+        &self,
+        key: Cow<'a, str>,
+        value: &[u8],
+        options: PutOptions,
+    ) -> Result<(), CuttlestoreError> {
+        let live_until = options.ttl.map(|t| (t + get_system_time()) as i64);
+        sqlx::query(&self.queries.put)
+            .bind(key.as_ref())
+            .bind(value)
+            .bind(live_until)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn delete<'a>(&self, key: Cow<'a, str>) -> Result<(), CuttlestoreError> {
+        sqlx::query(&self.queries.delete)
+            .bind(key.as_ref())
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn scan(
+        &self,
+    ) -> Result<BoxStream<Result<(String, Vec<u8>), CuttlestoreError>>, CuttlestoreError> {
+        let rows = sqlx::query_as::<_, (String, Vec<u8>)>(&self.queries.scan)
+            .bind(get_system_time() as i64)
+            .fetch(&self.pool);
+
+        Ok(Box::pin(rows.map_err(CuttlestoreError::from)))
+    }
+
+    async fn watch(
+        &self,
+    ) -> Result<BoxStream<'static, Result<Change, CuttlestoreError>>, CuttlestoreError> {
+        if !self.notify {
+            return Err(CuttlestoreError::Unsupported(
+                self.name(),
+                "watching for changes without notify=true",
+            ));
+        }
+        let mut listener = PgListener::connect_with(&self.pool).await?;
+        listener.listen(&self.table).await?;
+
+        Ok(Box::pin(listener.into_stream().filter_map(
+            |notification| async move {
+                let notification = match notification {
+                    Ok(notification) => notification,
+                    Err(error) => return Some(Err(error.into())),
+                };
+                match notification.payload().split_once(':') {
+                    Some(("put", key)) => Some(Ok(Change::Put(key.to_string()))),
+                    Some(("delete", key)) => Some(Ok(Change::Delete(key.to_string()))),
+                    // Not one of ours
+                    _ => None,
+                }
+            },
+        )))
+    }
+
+    async fn purge_expired(&self, limit: Option<u64>) -> Result<u64, CuttlestoreError> {
+        let query = match limit {
+            Some(limit) => sqlx::query(&self.queries.purge_expired_limited)
+                .bind(get_system_time() as i64)
+                .bind(limit as i64),
+            None => sqlx::query(&self.queries.purge_expired).bind(get_system_time() as i64),
+        };
+        let result = query.execute(&self.pool).await?;
+        Ok(result.rows_affected())
+    }
+
+    async fn take_lease<'a>(
+        &self,
+        key: Cow<'a, str>,
+        holder: &'a str,
+        ttl: u64,
+    ) -> Result<bool, CuttlestoreError> {
+        let now = get_system_time();
+        let result = sqlx::query(&self.queries.take_lease)
+            .bind(key.as_ref())
+            .bind(holder)
+            .bind((now + ttl) as i64)
+            .bind(now as i64)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected() == 1)
+    }
+}
@@ -0,0 +1,223 @@
+use std::{borrow::Cow, sync::Arc};
+
+use async_trait::async_trait;
+use futures::stream::BoxStream;
+use lazy_regex::regex_captures;
+use redb::{Database, ReadableDatabase, TableDefinition};
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+
+use crate::{
+    backend_api::{CuttleBackend, PutOptions},
+    common::{get_system_time, CuttlestoreError},
+};
+
+/// Maps keys to their values, and the time they expire.
+const VALUES: TableDefinition<&str, (&[u8], Option<u64>)> = TableDefinition::new("cuttlestore");
+/// Keys with a ttl, ordered by the time they expire. This lets the cleaner find
+/// expired values without going through the entire store.
+const EXPIRY: TableDefinition<(u64, &str), ()> = TableDefinition::new("cuttlestore_expiry");
+/// How many pairs a scan reads ahead of the consumer.
+const SCAN_BUFFER: usize = 128;
+
+pub(crate) struct RedbBackend {
+    db: Arc<Database>,
+}
+
+impl RedbBackend {
+    async fn new(path: &str) -> Result<Box<Self>, CuttlestoreError> {
+        let path = path.to_string();
+        let db = tokio::task::spawn_blocking(move || {
+            if let Some(parent) = std::path::Path::new(&path).parent() {
+                if !parent.as_os_str().is_empty() {
+                    std::fs::create_dir_all(parent).map_err(redb::Error::from)?;
+                }
+            }
+            let db = Database::create(&path)?;
+            // Create the tables in case they are missing
+            let txn = db.begin_write()?;
+            txn.open_table(VALUES)?;
+            txn.open_table(EXPIRY)?;
+            txn.commit()?;
+            Ok::<_, redb::Error>(db)
+        })
+        .await
+        .expect("Opening the redb database panicked")?;
+        Ok(Box::new(RedbBackend { db: Arc::new(db) }))
+    }
+
+    /// Run a blocking database operation without blocking the runtime.
+    async fn run<T, F>(&self, operation: F) -> Result<T, CuttlestoreError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Database) -> Result<T, redb::Error> + Send + 'static,
+    {
+        let db = self.db.clone();
+        Ok(tokio::task::spawn_blocking(move || operation(&db))
+            .await
+            .expect("A redb operation panicked")?)
+    }
+
+    /// Stream the pairs with keys that start with the prefix, in order.
+    async fn scan_from(
+        &self,
+        prefix: String,
+    ) -> Result<BoxStream<'static, Result<(String, Vec<u8>), CuttlestoreError>>, CuttlestoreError>
+    {
+        let table = self
+            .run(|db| Ok(db.begin_read()?.open_table(VALUES)?))
+            .await?;
+        let (sender, receiver) = mpsc::channel(SCAN_BUFFER);
+        tokio::task::spawn_blocking(move || {
+            let now = get_system_time();
+            let range = match table.range(prefix.as_str()..) {
+                Ok(range) => range,
+                Err(error) => {
+                    sender
+                        .blocking_send(Err(redb::Error::from(error).into()))
+                        .ok();
+                    return;
+                }
+            };
+            for pair in range {
+                let pair =
+                    pair.map_err(|error| redb::Error::from(error).into())
+                        .map(|(key, value)| {
+                            let (payload, live_until) = value.value();
+                            (key.value().to_string(), payload.to_vec(), live_until)
+                        });
+                let pair = match pair {
+                    Ok((key, _, _)) if !key.starts_with(&prefix) => break,
+                    Ok((_, _, Some(live_until))) if live_until < now => continue,
+                    Ok((key, payload, _)) => Ok((key, payload)),
+                    Err(error) => Err(error),
+                };
+                if sender.blocking_send(pair).is_err() {
+                    // The stream was dropped, no need to read any further
+                    break;
+                }
+            }
+        });
+        Ok(Box::pin(ReceiverStream::new(receiver)))
+    }
+}
+
+#[async_trait]
+impl CuttleBackend for RedbBackend {
+    async fn new(conn: &str) -> Option<Result<Box<Self>, CuttlestoreError>> {
+        let (_, path) = regex_captures!(r#"^redb://(.+)"#, conn)?;
+        Some(RedbBackend::new(path).await)
+    }
+
+    fn requires_cleaner(&self) -> bool {
+        true
+    }
+
+    fn name(&self) -> &'static str {
+        "redb"
+    }
+
+    async fn get<'a>(&self, key: Cow<'a, str>) -> Result<Option<Vec<u8>>, CuttlestoreError> {
+        let key = key.into_owned();
+        self.run(move |db| {
+            let table = db.begin_read()?.open_table(VALUES)?;
+            let value = match table.get(key.as_str())? {
+                Some(value) => value,
+                None => return Ok(None),
+            };
+            let (payload, live_until) = value.value();
+            if live_until.is_some_and(|live_until| live_until < get_system_time()) {
+                return Ok(None);
+            }
+            Ok(Some(payload.to_vec()))
+        })
+        .await
+    }
+
+    async fn put<'a>(
+        &self,
+        key: Cow<'a, str>,
+        value: &[u8],
+        options: PutOptions,
+    ) -> Result<(), CuttlestoreError> {
+        let key = key.into_owned();
+        let value = value.to_vec();
+        let live_until = options.ttl.map(|ttl| get_system_time() + ttl);
+        self.run(move |db| {
+            let txn = db.begin_write()?;
+            {
+                let mut values = txn.open_table(VALUES)?;
+                let mut expiry = txn.open_table(EXPIRY)?;
+                let old = values.insert(key.as_str(), (value.as_slice(), live_until))?;
+                if let Some(old_live_until) = old.and_then(|old| old.value().1) {
+                    expiry.remove((old_live_until, key.as_str()))?;
+                }
+                if let Some(live_until) = live_until {
+                    expiry.insert((live_until, key.as_str()), ())?;
+                }
+            }
+            txn.commit()?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn delete<'a>(&self, key: Cow<'a, str>) -> Result<(), CuttlestoreError> {
+        let key = key.into_owned();
+        self.run(move |db| {
+            let txn = db.begin_write()?;
+            {
+                let mut values = txn.open_table(VALUES)?;
+                let mut expiry = txn.open_table(EXPIRY)?;
+                let old = values.remove(key.as_str())?;
+                if let Some(old_live_until) = old.and_then(|old| old.value().1) {
+                    expiry.remove((old_live_until, key.as_str()))?;
+                }
+            }
+            txn.commit()?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn scan(
+        &self,
+    ) -> Result<BoxStream<Result<(String, Vec<u8>), CuttlestoreError>>, CuttlestoreError> {
+        self.scan_from(String::new()).await
+    }
+
+    async fn scan_prefix<'a>(
+        &self,
+        prefix: Cow<'a, str>,
+    ) -> Result<BoxStream<Result<(String, Vec<u8>), CuttlestoreError>>, CuttlestoreError> {
+        self.scan_from(prefix.into_owned()).await
+    }
+
+    async fn purge_expired(&self, limit: Option<u64>) -> Result<u64, CuttlestoreError> {
+        self.run(move |db| {
+            let now = get_system_time();
+            let txn = db.begin_write()?;
+            let mut removed = 0;
+            {
+                let mut values = txn.open_table(VALUES)?;
+                let mut expiry = txn.open_table(EXPIRY)?;
+                // Everything in the range has expired, so the first `limit`
+                // entries are the ones to remove.
+                let mut picked = 0;
+                let expired = expiry.extract_from_if(..(now, ""), |_, _| {
+                    picked += 1;
+                    limit.is_none_or(|limit| picked <= limit)
+                })?;
+                for entry in expired {
+                    let (entry, _) = entry?;
+                    let (_, key) = entry.value();
+                    values.remove(key)?;
+                    removed += 1;
+                }
+            }
+            txn.commit()?;
+            Ok(removed)
+        })
+        .await
+    }
+}
@@ -0,0 +1,309 @@
+//! Redis can be a single server, a cluster, or a group of servers managed by
+//! Sentinel. These all end up behind the same connection type, so the backend
+//! doesn't need to care which one it's talking to.
+
+use bb8::{ManageConnection, Pool, PooledConnection};
+use redis::{
+    aio::{ConnectionLike, MultiplexedConnection},
+    cluster::ClusterClientBuilder,
+    cluster_async::ClusterConnection,
+    sentinel::{SentinelClient, SentinelClientBuilder, SentinelServerType},
+    AsyncConnectionConfig, Client, Cmd, ErrorKind, IntoConnectionInfo, Pipeline, RedisError,
+    RedisFuture, TlsMode, Value,
+};
+use tokio::sync::Mutex;
+
+use super::options::RedisOptions;
+use crate::common::CuttlestoreError;
+
+const DEFAULT_SENTINEL_PORT: u16 = 26379;
+
+#[derive(Clone)]
+pub(crate) enum Connections {
+    Single(Pool<NodeManager>),
+    Sentinel(Pool<SentinelManager>),
+    /// The cluster connection already multiplexes over connections to every
+    /// node, and reconnects as the cluster changes, so it doesn't need a pool.
+    Cluster(ClusterConnection),
+}
+
+impl Connections {
+    pub(crate) async fn single(
+        address: &str,
+        options: &RedisOptions<'_>,
+    ) -> Result<Self, CuttlestoreError> {
+        let info = address.into_connection_info()?;
+        let mut settings = info.redis_settings().clone();
+        if let Some(username) = options.username {
+            settings = settings.set_username(username);
+        }
+        if let Some(password) = options.password {
+            settings = settings.set_password(password);
+        }
+        if let Some(db) = options.db {
+            settings = settings.set_db(db);
+        }
+        let info = info.set_redis_settings(settings);
+
+        let client = match options.certificates()? {
+            Some(certificates) => Client::build_with_tls(info, certificates)?,
+            None => Client::open(info)?,
+        };
+        let manager = NodeManager {
+            client,
+            config: connection_config(options),
+            client_name: options.client_name.map(str::to_string),
+        };
+        let pool = options.pool_builder().build(manager).await?;
+        Ok(Connections::Single(pool))
+    }
+
+    /// Connect to a cluster. `nodes` is a comma separated list of some of the
+    /// nodes in the cluster, the rest are discovered automatically.
+    pub(crate) async fn cluster(
+        secure: &str,
+        nodes: &str,
+        options: &RedisOptions<'_>,
+    ) -> Result<Self, CuttlestoreError> {
+        let nodes = nodes
+            .split(',')
+            .filter(|node| !node.is_empty())
+            .map(|node| format!("redis{secure}://{node}"));
+        let mut builder = ClusterClientBuilder::new(nodes);
+        if let Some(username) = options.username {
+            builder = builder.username(username);
+        }
+        if let Some(password) = options.password {
+            builder = builder.password(password);
+        }
+        if let Some(db) = options.db {
+            builder = builder.database_id(db);
+        }
+        if let Some(certificates) = options.certificates()? {
+            builder = builder.certs(certificates);
+        }
+        if let Some(timeout) = options.connect_timeout {
+            builder = builder.connection_timeout(timeout);
+        }
+        if let Some(timeout) = options.command_timeout {
+            builder = builder.response_timeout(timeout);
+        }
+        let connection = builder.build()?.get_async_connection().await?;
+        Ok(Connections::Cluster(connection))
+    }
+
+    /// Connect to the current master through Sentinel. `address` is a comma
+    /// separated list of sentinels, followed by the name of the master like
+    /// `host1:26379,host2:26379/mymaster`.
+    pub(crate) async fn sentinel(
+        secure: &str,
+        address: &str,
+        options: &RedisOptions<'_>,
+    ) -> Result<Self, CuttlestoreError> {
+        let Some((sentinels, service_name)) = address.split_once('/') else {
+            return Err(CuttlestoreError::InvalidConnectionString(
+                "the Redis Sentinel connection string is missing the name of the master, like redis+sentinel://host:26379/mymaster".to_string(),
+            ));
+        };
+        let sentinels = sentinels
+            .split(',')
+            .filter(|sentinel| !sentinel.is_empty())
+            .map(|sentinel| {
+                let sentinel = if sentinel.contains(':') {
+                    format!("redis{secure}://{sentinel}")
+                } else {
+                    format!("redis{secure}://{sentinel}:{DEFAULT_SENTINEL_PORT}")
+                };
+                Ok(sentinel.into_connection_info()?.addr().clone())
+            })
+            .collect::<Result<Vec<_>, RedisError>>()?;
+
+        let mut builder =
+            SentinelClientBuilder::new(sentinels, service_name, SentinelServerType::Master)?;
+        if !secure.is_empty() {
+            builder = builder.set_client_to_redis_tls_mode(TlsMode::Secure);
+        }
+        if let Some(username) = options.username {
+            builder = builder.set_client_to_redis_username(username);
+        }
+        if let Some(password) = options.password {
+            builder = builder.set_client_to_redis_password(password);
+        }
+        if let Some(db) = options.db {
+            builder = builder.set_client_to_redis_db(db);
+        }
+        if let Some(username) = options.sentinel_username {
+            builder = builder.set_client_to_sentinel_username(username);
+        }
+        if let Some(password) = options.sentinel_password {
+            builder = builder.set_client_to_sentinel_password(password);
+        }
+        // The sentinels and the servers are expected to share a CA
+        if let Some(certificates) = options.certificates()? {
+            builder = builder
+                .set_client_to_redis_certificates(certificates.clone())
+                .set_client_to_sentinel_certificates(certificates);
+        }
+
+        let manager = SentinelManager {
+            client: Mutex::new(builder.build()?),
+            config: connection_config(options),
+            client_name: options.client_name.map(str::to_string),
+        };
+        let pool = options.pool_builder().build(manager).await?;
+        Ok(Connections::Sentinel(pool))
+    }
+
+    pub(crate) async fn get(&self) -> Result<RedisConnection<'_>, CuttlestoreError> {
+        Ok(match self {
+            Connections::Single(pool) => RedisConnection::Single(pool.get().await?),
+            Connections::Sentinel(pool) => RedisConnection::Sentinel(pool.get().await?),
+            Connections::Cluster(connection) => RedisConnection::Cluster(connection.clone()),
+        })
+    }
+}
+
+/// The timeouts for the connections in a pool. Anything not set keeps the
+/// defaults of the redis crate.
+fn connection_config(options: &RedisOptions<'_>) -> AsyncConnectionConfig {
+    let mut config = AsyncConnectionConfig::new();
+    if let Some(timeout) = options.connect_timeout {
+        config = config.set_connection_timeout(Some(timeout));
+    }
+    if let Some(timeout) = options.command_timeout {
+        config = config.set_response_timeout(Some(timeout));
+    }
+    config
+}
+
+/// Name a new connection, so it can be told apart in `CLIENT LIST`.
+async fn set_client_name(
+    connection: &mut MultiplexedConnection,
+    client_name: Option<&str>,
+) -> Result<(), RedisError> {
+    if let Some(name) = client_name {
+        let () = redis::cmd("CLIENT")
+            .arg("SETNAME")
+            .arg(name)
+            .query_async(connection)
+            .await?;
+    }
+    Ok(())
+}
+
+pub(crate) enum RedisConnection<'a> {
+    Single(PooledConnection<'a, NodeManager>),
+    Sentinel(PooledConnection<'a, SentinelManager>),
+    Cluster(ClusterConnection),
+}
+
+impl ConnectionLike for RedisConnection<'_> {
+    fn req_packed_command<'a>(&'a mut self, cmd: &'a Cmd) -> RedisFuture<'a, Value> {
+        match self {
+            RedisConnection::Single(connection) => connection.req_packed_command(cmd),
+            RedisConnection::Sentinel(connection) => connection.req_packed_command(cmd),
+            RedisConnection::Cluster(connection) => connection.req_packed_command(cmd),
+        }
+    }
+
+    fn req_packed_commands<'a>(
+        &'a mut self,
+        pipeline: &'a Pipeline,
+        offset: usize,
+        count: usize,
+    ) -> RedisFuture<'a, Vec<Value>> {
+        match self {
+            RedisConnection::Single(connection) => {
+                connection.req_packed_commands(pipeline, offset, count)
+            }
+            RedisConnection::Sentinel(connection) => {
+                connection.req_packed_commands(pipeline, offset, count)
+            }
+            RedisConnection::Cluster(connection) => {
+                connection.req_packed_commands(pipeline, offset, count)
+            }
+        }
+    }
+
+    fn get_db(&self) -> i64 {
+        match self {
+            RedisConnection::Single(connection) => connection.get_db(),
+            RedisConnection::Sentinel(connection) => connection.get_db(),
+            RedisConnection::Cluster(connection) => connection.get_db(),
+        }
+    }
+}
+
+/// Connects to a single server.
+pub(crate) struct NodeManager {
+    client: Client,
+    config: AsyncConnectionConfig,
+    client_name: Option<String>,
+}
+
+impl ManageConnection for NodeManager {
+    type Connection = MultiplexedConnection;
+    type Error = RedisError;
+
+    async fn connect(&self) -> Result<MultiplexedConnection, RedisError> {
+        let mut connection = self
+            .client
+            .get_multiplexed_async_connection_with_config(&self.config)
+            .await?;
+        set_client_name(&mut connection, self.client_name.as_deref()).await?;
+        Ok(connection)
+    }
+
+    async fn is_valid(&self, connection: &mut MultiplexedConnection) -> Result<(), RedisError> {
+        let _: String = redis::cmd("PING").query_async(connection).await?;
+        Ok(())
+    }
+
+    fn has_broken(&self, _: &mut MultiplexedConnection) -> bool {
+        false
+    }
+}
+
+/// Asks Sentinel for the current master whenever a new connection is needed.
+pub(crate) struct SentinelManager {
+    client: Mutex<SentinelClient>,
+    config: AsyncConnectionConfig,
+    client_name: Option<String>,
+}
+
+impl ManageConnection for SentinelManager {
+    type Connection = MultiplexedConnection;
+    type Error = RedisError;
+
+    async fn connect(&self) -> Result<MultiplexedConnection, RedisError> {
+        let mut connection = self
+            .client
+            .lock()
+            .await
+            .get_async_connection_with_config(&self.config)
+            .await?;
+        set_client_name(&mut connection, self.client_name.as_deref()).await?;
+        Ok(connection)
+    }
+
+    async fn is_valid(&self, connection: &mut MultiplexedConnection) -> Result<(), RedisError> {
+        // After a failover, the old master comes back as a replica. It would
+        // still answer a ping but refuse writes, so check the role instead.
+        let role: Vec<Value> = redis::cmd("ROLE").query_async(connection).await?;
+        match role
+            .into_iter()
+            .next()
+            .map(redis::from_redis_value::<String>)
+        {
+            Some(Ok(role)) if role == "master" => Ok(()),
+            _ => Err(RedisError::from((
+                ErrorKind::Client,
+                "the server is no longer the master",
+            ))),
+        }
+    }
+
+    fn has_broken(&self, _: &mut MultiplexedConnection) -> bool {
+        false
+    }
+}
@@ -0,0 +1,281 @@
+//! With `storage=hash`, each store with a prefix is kept in its own Redis
+//! hash, named after the prefix. The keys of the store are the fields of the
+//! hash, which lets the store be counted and cleared without a scan.
+//!
+//! Redis 7.4 added expiration times for the fields of a hash. On older
+//! versions, the expiration times are kept in a sorted set next to the hash
+//! instead, and the cleaner removes the fields once they expire.
+//!
+//! The names of the hashes are kept in a set, so that scanning or clearing
+//! the whole database only touches the hashes that hold a store, and not the
+//! other hashes in the database.
+
+use std::collections::HashSet;
+
+use redis::{AsyncCommands, Script, Value};
+use tokio::sync::mpsc::Sender;
+
+use super::{connection::RedisConnection, Connections};
+use crate::common::{get_system_time, CuttlestoreError};
+
+/// How many expired fields the cleaner removes from a hash at once.
+const PURGE_BATCH: usize = 1000;
+
+/// The set holding the names of the hashes.
+pub(crate) const REGISTRY: &str = "cuttlestore:hashes";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum FieldExpiry {
+    /// Redis expires the fields itself, with `HEXPIRE`.
+    Native,
+    /// The expiration times are kept in a sorted set next to the hash.
+    Sidecar,
+}
+
+impl FieldExpiry {
+    /// Check if the server supports `HEXPIRE`.
+    pub(crate) async fn detect(
+        connection: &mut RedisConnection<'_>,
+    ) -> Result<Self, CuttlestoreError> {
+        let info: Vec<Value> = redis::cmd("COMMAND")
+            .arg("INFO")
+            .arg("HEXPIRE")
+            .query_async(connection)
+            .await?;
+        Ok(match info.first() {
+            None | Some(Value::Nil) => FieldExpiry::Sidecar,
+            Some(_) => FieldExpiry::Native,
+        })
+    }
+}
+
+/// The sorted set holding the expiration times for the fields of `hash`. The
+/// hash tag puts it in the same cluster slot as the hash.
+fn sidecar(hash: &str) -> String {
+    format!("{{{hash}}}:expiry")
+}
+
+/// The names of all the hashes that hold a store. A hash stays in the set
+/// after it's cleared, until the whole database is cleared.
+pub(crate) async fn registered(
+    connection: &mut RedisConnection<'_>,
+) -> Result<Vec<String>, CuttlestoreError> {
+    Ok(connection.smembers(REGISTRY).await?)
+}
+
+/// The scores in the sidecar below this have expired.
+fn expired_before() -> String {
+    format!("({}", get_system_time())
+}
+
+pub(crate) async fn get(
+    connection: &mut RedisConnection<'_>,
+    expiry: FieldExpiry,
+    hash: &str,
+    field: &str,
+) -> Result<Option<Vec<u8>>, CuttlestoreError> {
+    match expiry {
+        FieldExpiry::Native => Ok(connection.hget(hash, field).await?),
+        FieldExpiry::Sidecar => {
+            let (value, live_until): (Option<Vec<u8>>, Option<f64>) = redis::pipe()
+                .hget(hash, field)
+                .zscore(sidecar(hash), field)
+                .query_async(connection)
+                .await?;
+            if live_until.is_some_and(|live_until| live_until < get_system_time() as f64) {
+                return Ok(None);
+            }
+            Ok(value)
+        }
+    }
+}
+
+pub(crate) async fn put(
+    connection: &mut RedisConnection<'_>,
+    expiry: FieldExpiry,
+    hash: &str,
+    field: &str,
+    value: &[u8],
+    ttl: Option<u64>,
+) -> Result<(), CuttlestoreError> {
+    // The set is in a different cluster slot than the hash, so it can't be in
+    // the same transaction. It's added to first, so a hash is never missing
+    // from it.
+    let _: () = connection.sadd(REGISTRY, hash).await?;
+
+    let mut pipe = redis::pipe();
+    pipe.atomic().hset(hash, field, value).ignore();
+    match (expiry, ttl) {
+        (FieldExpiry::Native, Some(ttl)) => {
+            pipe.cmd("HEXPIRE")
+                .arg(hash)
+                .arg(ttl)
+                .arg("FIELDS")
+                .arg(1)
+                .arg(field)
+                .ignore();
+        }
+        // Overwriting a field already clears its expiration time
+        (FieldExpiry::Native, None) => {}
+        (FieldExpiry::Sidecar, Some(ttl)) => {
+            pipe.zadd(sidecar(hash), field, get_system_time() + ttl)
+                .ignore();
+        }
+        (FieldExpiry::Sidecar, None) => {
+            pipe.zrem(sidecar(hash), field).ignore();
+        }
+    }
+    let () = pipe.query_async(connection).await?;
+    Ok(())
+}
+
+pub(crate) async fn delete(
+    connection: &mut RedisConnection<'_>,
+    expiry: FieldExpiry,
+    hash: &str,
+    field: &str,
+) -> Result<(), CuttlestoreError> {
+    let mut pipe = redis::pipe();
+    pipe.atomic().hdel(hash, field).ignore();
+    if expiry == FieldExpiry::Sidecar {
+        pipe.zrem(sidecar(hash), field).ignore();
+    }
+    let () = pipe.query_async(connection).await?;
+    Ok(())
+}
+
+pub(crate) async fn count(
+    connection: &mut RedisConnection<'_>,
+    expiry: FieldExpiry,
+    hash: &str,
+) -> Result<u64, CuttlestoreError> {
+    match expiry {
+        FieldExpiry::Native => Ok(connection.hlen(hash).await?),
+        FieldExpiry::Sidecar => {
+            let (len, expired): (u64, u64) = redis::pipe()
+                .hlen(hash)
+                .zcount(sidecar(hash), "-inf", expired_before())
+                .query_async(connection)
+                .await?;
+            Ok(len.saturating_sub(expired))
+        }
+    }
+}
+
+pub(crate) async fn clear(
+    connection: &mut RedisConnection<'_>,
+    hash: &str,
+) -> Result<(), CuttlestoreError> {
+    let () = redis::cmd("UNLINK")
+        .arg(hash)
+        .arg(sidecar(hash))
+        .query_async(connection)
+        .await?;
+    Ok(())
+}
+
+/// Send all the fields of the hash through, with `hash:field` as the key. Like
+/// a scan of the keys, `HSCAN` can return a field more than once. Repeats are
+/// only dropped within a batch, so a field may still be sent twice.
+pub(crate) async fn scan(
+    connection: &mut RedisConnection<'_>,
+    expiry: FieldExpiry,
+    hash: &str,
+    count: usize,
+    tx: &Sender<Result<(String, Vec<u8>), CuttlestoreError>>,
+) -> Result<(), CuttlestoreError> {
+    let expired: HashSet<String> = match expiry {
+        FieldExpiry::Native => HashSet::new(),
+        FieldExpiry::Sidecar => {
+            let fields: Vec<String> = connection
+                .zrangebyscore(sidecar(hash), "-inf", expired_before())
+                .await?;
+            fields.into_iter().collect()
+        }
+    };
+
+    let mut cursor: u64 = 0;
+    loop {
+        let (next, pairs): (u64, Vec<(String, Vec<u8>)>) = redis::cmd("HSCAN")
+            .arg(hash)
+            .arg(cursor)
+            .arg("COUNT")
+            .arg(count)
+            .query_async(connection)
+            .await?;
+        let mut sent = HashSet::with_capacity(pairs.len());
+        for (field, value) in pairs {
+            if expired.contains(&field) || !sent.insert(field.clone()) {
+                continue;
+            }
+            if tx
+                .send(Ok((format!("{hash}:{field}"), value)))
+                .await
+                .is_err()
+            {
+                return Ok(());
+            }
+        }
+
+        if next == 0 {
+            return Ok(());
+        }
+        cursor = next;
+    }
+}
+
+/// Remove the expired fields from every hash, stopping once `limit` fields
+/// have been removed.
+pub(crate) async fn purge_expired(
+    connections: &Connections,
+    limit: Option<u64>,
+) -> Result<u64, CuttlestoreError> {
+    // The fields are removed in a script, so a field that is put again with a
+    // new expiration time can't be removed by mistake.
+    let script = Script::new(
+        r#"
+        local fields = redis.call('ZRANGEBYSCORE', KEYS[2], '-inf', ARGV[1], 'LIMIT', 0, ARGV[2])
+        if #fields > 0 then
+            redis.call('HDEL', KEYS[1], unpack(fields))
+            redis.call('ZREM', KEYS[2], unpack(fields))
+        end
+        return #fields
+        "#,
+    );
+
+    let mut removed = 0;
+    let mut connection = connections.get().await?;
+    'hashes: for hash in registered(&mut connection).await? {
+        let sidecar = sidecar(&hash);
+        loop {
+            let batch = match limit {
+                Some(limit) if removed >= limit => break 'hashes,
+                Some(limit) => (PURGE_BATCH as u64).min(limit - removed),
+                None => PURGE_BATCH as u64,
+            };
+            let purged: u64 = script
+                .key(&hash)
+                .key(&sidecar)
+                .arg(expired_before())
+                .arg(batch)
+                .invoke_async(&mut connection)
+                .await?;
+            removed += purged;
+            if purged < batch {
+                break;
+            }
+        }
+    }
+    Ok(removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sidecar_is_tagged_with_the_hash() {
+        assert_eq!(sidecar(":store"), "{:store}:expiry");
+        assert_eq!(sidecar("app:users"), "{app:users}:expiry");
+    }
+}
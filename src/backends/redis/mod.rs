@@ -1,64 +1,89 @@
-use std::{borrow::Cow, collections::HashMap, pin::Pin};
+use std::{borrow::Cow, collections::HashSet, pin::Pin};
 
 use async_trait::async_trait;
-use bb8::Pool;
-use bb8_redis::RedisConnectionManager;
-
-use futures::{stream::BoxStream, Stream};
+use futures::{future::try_join_all, stream::BoxStream, Stream};
 use lazy_regex::regex_captures;
-use redis::{AsyncCommands, IntoConnectionInfo, RedisError};
-use tokio::{sync::mpsc::Receiver, task::JoinHandle};
+use redis::{
+    cluster_async::ClusterConnection,
+    cluster_routing::{RoutingInfo, SingleNodeRoutingInfo},
+    AsyncCommands, RedisError, Script, Value,
+};
+use tokio::{
+    sync::mpsc::{Receiver, Sender},
+    task::JoinHandle,
+};
 
 use crate::{
-    backend_api::{CuttleBackend, PutOptions},
-    common::CuttlestoreError,
+    backend_api::{clear_by_scan, count_by_scan, prefixed, CuttleBackend, PutOptions},
+    common::{get_system_time, CuttlestoreError},
 };
 
+mod connection;
+mod hashes;
+mod options;
+
+use connection::Connections;
+use hashes::FieldExpiry;
+use options::{Mode, RedisOptions};
+
+/// The hash holding the leases of coordinated cleaners. Scans only fetch
+/// plain keys and the hashes of the stores, so it never shows up in a store.
+const LEASES: &str = "cuttlestore:leases";
+
 pub(crate) struct RedisBackend {
-    pool: Pool<RedisConnectionManager>,
+    connections: Connections,
+    scan_count: usize,
+    /// Set if stores with a prefix are kept in hashes, see `hashes`.
+    hashes: Option<FieldExpiry>,
 }
 
 impl RedisBackend {
-    async fn new(address: &str, args: HashMap<&str, &str>) -> Result<Box<Self>, CuttlestoreError> {
-        let info = address.into_connection_info()?;
-
-        let mut redis_settings = info.redis_settings().clone();
-        if let Some(username) = args.get("username") {
-            redis_settings = redis_settings.set_username(username);
-        }
-        if let Some(password) = args.get("password") {
-            redis_settings = redis_settings.set_password(password);
-        }
-        let info = info.set_redis_settings(redis_settings);
+    async fn new(
+        mode: &str,
+        secure: &str,
+        address: &str,
+        args: &str,
+    ) -> Result<Box<Self>, CuttlestoreError> {
+        let mode = Mode::parse(mode);
+        let options = RedisOptions::parse(mode, !secure.is_empty(), args)?;
+        let connections = match mode {
+            Mode::Cluster => Connections::cluster(secure, address, &options).await?,
+            Mode::Sentinel => Connections::sentinel(secure, address, &options).await?,
+            Mode::Single => {
+                Connections::single(&format!("redis{secure}://{address}"), &options).await?
+            }
+        };
 
-        let manager = RedisConnectionManager::new(info)?;
-        let pool = Pool::builder().build(manager).await?;
+        let hashes = if options.hash_storage {
+            Some(FieldExpiry::detect(&mut connections.get().await?).await?)
+        } else {
+            None
+        };
 
-        Ok(Box::new(RedisBackend { pool }))
+        Ok(Box::new(RedisBackend {
+            connections,
+            scan_count: options.scan_count,
+            hashes,
+        }))
     }
 }
 
 #[async_trait]
 impl CuttleBackend for RedisBackend {
     async fn new(conn: &str) -> Option<Result<Box<Self>, CuttlestoreError>> {
-        if let Some((_, secure, address, args)) =
-            regex_captures!(r#"^redis(s)?://([^?]+)[?]?(.*)"#, conn)
-        {
-            let arg_pairs: HashMap<&str, &str> = args
-                .split('&')
-                .flat_map(|pair| pair.split_once('='))
-                .collect();
-
-            let address = format!("redis{secure}://{address}");
-
-            Some(RedisBackend::new(&address, arg_pairs).await)
+        if let Some((_, secure, mode, address, args)) = regex_captures!(
+            r#"^redis(s)?(?:\+(cluster|sentinel))?://([^?]+)[?]?(.*)"#,
+            conn
+        ) {
+            Some(RedisBackend::new(mode, secure, address, args).await)
         } else {
             None
         }
     }
 
     fn requires_cleaner(&self) -> bool {
-        false
+        // Without `HEXPIRE`, nothing removes the expired fields of a hash
+        self.hashes == Some(FieldExpiry::Sidecar)
     }
 
     fn name(&self) -> &'static str {
@@ -66,7 +91,7 @@ impl CuttleBackend for RedisBackend {
     }
 
     async fn get<'a>(&self, key: Cow<'a, str>) -> Result<Option<Vec<u8>>, CuttlestoreError> {
-        let mut connection = self.pool.get().await?;
+        let mut connection = self.connections.get().await?;
         let payload: Option<Vec<u8>> = connection.get(key.as_ref()).await?;
         Ok(payload)
     }
@@ -77,7 +102,7 @@ impl CuttleBackend for RedisBackend {
         value: &[u8],
         options: PutOptions,
     ) -> Result<(), CuttlestoreError> {
-        let mut connection = self.pool.get().await?;
+        let mut connection = self.connections.get().await?;
 
         if let Some(ttl) = options.ttl {
             let _: () = connection.set_ex(key.as_ref(), value, ttl).await?;
@@ -89,7 +114,7 @@ impl CuttleBackend for RedisBackend {
     }
 
     async fn delete<'a>(&self, key: Cow<'a, str>) -> Result<(), CuttlestoreError> {
-        let mut connection = self.pool.get().await?;
+        let mut connection = self.connections.get().await?;
 
         let _: () = connection.del(key.as_ref()).await?;
 
@@ -99,7 +124,150 @@ impl CuttleBackend for RedisBackend {
     async fn scan(
         &self,
     ) -> Result<BoxStream<Result<(String, Vec<u8>), CuttlestoreError>>, CuttlestoreError> {
-        Ok(Box::pin(RedisScanStream::new(self.pool.clone()).await))
+        let target = match self.hashes {
+            Some(expiry) => ScanTarget::Everything(expiry),
+            None => ScanTarget::Keys(None),
+        };
+        Ok(Box::pin(
+            RedisScanStream::new(self.connections.clone(), target, self.scan_count).await,
+        ))
+    }
+
+    async fn scan_prefix<'a>(
+        &self,
+        prefix: Cow<'a, str>,
+    ) -> Result<BoxStream<Result<(String, Vec<u8>), CuttlestoreError>>, CuttlestoreError> {
+        let target = match (self.hashes, prefix.strip_suffix(':')) {
+            (Some(expiry), Some(hash)) => ScanTarget::Hash(hash.to_string(), expiry),
+            // Let redis skip the other keys, rather than sending them over only
+            // for them to be filtered out.
+            _ => ScanTarget::Keys(Some(prefix_pattern(&prefix))),
+        };
+        Ok(Box::pin(
+            RedisScanStream::new(self.connections.clone(), target, self.scan_count).await,
+        ))
+    }
+
+    async fn get_in<'a>(
+        &self,
+        prefix: Option<&'a str>,
+        key: &'a str,
+    ) -> Result<Option<Vec<u8>>, CuttlestoreError> {
+        match (self.hashes, prefix) {
+            (Some(expiry), Some(hash)) => {
+                let mut connection = self.connections.get().await?;
+                hashes::get(&mut connection, expiry, hash, key).await
+            }
+            _ => self.get(prefixed(prefix, key)).await,
+        }
+    }
+
+    async fn put_in<'a>(
+        &self,
+        prefix: Option<&'a str>,
+        key: &'a str,
+        value: &[u8],
+        options: PutOptions,
+    ) -> Result<(), CuttlestoreError> {
+        match (self.hashes, prefix) {
+            (Some(expiry), Some(hash)) => {
+                let mut connection = self.connections.get().await?;
+                hashes::put(&mut connection, expiry, hash, key, value, options.ttl).await
+            }
+            _ => self.put(prefixed(prefix, key), value, options).await,
+        }
+    }
+
+    async fn delete_in<'a>(
+        &self,
+        prefix: Option<&'a str>,
+        key: &'a str,
+    ) -> Result<(), CuttlestoreError> {
+        match (self.hashes, prefix) {
+            (Some(expiry), Some(hash)) => {
+                let mut connection = self.connections.get().await?;
+                hashes::delete(&mut connection, expiry, hash, key).await
+            }
+            _ => self.delete(prefixed(prefix, key)).await,
+        }
+    }
+
+    async fn count<'a>(&self, prefix: Option<&'a str>) -> Result<u64, CuttlestoreError> {
+        match (self.hashes, prefix) {
+            (Some(expiry), Some(hash)) => {
+                let mut connection = self.connections.get().await?;
+                hashes::count(&mut connection, expiry, hash).await
+            }
+            _ => count_by_scan(self, prefix).await,
+        }
+    }
+
+    async fn clear<'a>(&self, prefix: Option<&'a str>) -> Result<(), CuttlestoreError> {
+        match (self.hashes, prefix) {
+            (Some(_), Some(hash)) => {
+                let mut connection = self.connections.get().await?;
+                hashes::clear(&mut connection, hash).await
+            }
+            (Some(_), None) => {
+                // The whole store is every plain key, along with every hash of
+                // a store and its expiration times. Other hashes are left alone.
+                let keys =
+                    scan_keys(&self.connections, None, Some("string"), self.scan_count).await?;
+                let mut connection = self.connections.get().await?;
+                for key in keys {
+                    let _: () = connection.unlink(key).await?;
+                }
+                for hash in hashes::registered(&mut connection).await? {
+                    hashes::clear(&mut connection, &hash).await?;
+                }
+                let _: () = connection.unlink(hashes::REGISTRY).await?;
+                Ok(())
+            }
+            _ => clear_by_scan(self, prefix).await,
+        }
+    }
+
+    async fn purge_expired(&self, limit: Option<u64>) -> Result<u64, CuttlestoreError> {
+        match self.hashes {
+            Some(FieldExpiry::Sidecar) => hashes::purge_expired(&self.connections, limit).await,
+            // Redis expires everything else by itself
+            _ => Ok(0),
+        }
+    }
+
+    async fn take_lease<'a>(
+        &self,
+        key: Cow<'a, str>,
+        holder: &'a str,
+        ttl: u64,
+    ) -> Result<bool, CuttlestoreError> {
+        // Each lease is its expiration time followed by the holder. Scripts
+        // run atomically, so nobody can write the lease between the read and
+        // the write.
+        let script = Script::new(
+            r#"
+            local lease = redis.call('HGET', KEYS[1], ARGV[1])
+            if lease then
+                local live_until, holder = string.match(lease, '^(%d+) (.*)$')
+                if live_until and holder ~= ARGV[2] and tonumber(live_until) >= tonumber(ARGV[3]) then
+                    return 0
+                end
+            end
+            redis.call('HSET', KEYS[1], ARGV[1], ARGV[4] .. ' ' .. ARGV[2])
+            return 1
+            "#,
+        );
+        let now = get_system_time();
+        let mut connection = self.connections.get().await?;
+        let taken: u64 = script
+            .key(LEASES)
+            .arg(key.as_ref())
+            .arg(holder)
+            .arg(now)
+            .arg(now + ttl)
+            .invoke_async(&mut connection)
+            .await?;
+        Ok(taken == 1)
     }
 }
 
@@ -119,63 +287,288 @@ struct RedisScanStream {
     rx: Receiver<Result<(String, Vec<u8>), CuttlestoreError>>,
 }
 
+/// What a scan goes through.
+enum ScanTarget {
+    /// The plain keys, or only the ones matching a pattern.
+    Keys(Option<String>),
+    /// The fields of a single hash.
+    Hash(String, FieldExpiry),
+    /// The plain keys, followed by the fields of every hash of a store.
+    Everything(FieldExpiry),
+}
+
 impl RedisScanStream {
-    async fn new(pool: Pool<RedisConnectionManager>) -> Self {
+    async fn new(connections: Connections, target: ScanTarget, count: usize) -> Self {
         let (tx, rx) =
-            tokio::sync::mpsc::channel::<Result<(String, Vec<u8>), CuttlestoreError>>(10);
+            tokio::sync::mpsc::channel::<Result<(String, Vec<u8>), CuttlestoreError>>(count);
         let handle = tokio::spawn(async move {
-            // Handle the setup, send back an error and exit early if it fails.
-            let mut connection = match pool.get().await {
-                Ok(connection) => connection,
-                Err(err) => {
-                    tx.send(Err(err.into())).await.ok();
-                    return;
-                }
-            };
-            let mut keys = match connection.scan::<String>().await {
-                Ok(keys) => keys,
-                Err(err) => {
-                    tx.send(Err(err.into())).await.ok();
-                    return;
-                }
-            };
+            if let Err(err) = run_scan(&connections, target, count, &tx).await {
+                tx.send(Err(err)).await.ok();
+            }
+        });
+        Self { handle, rx }
+    }
+}
 
-            // Start putting the pairs through
-            loop {
-                let mut connection = match pool.get().await {
-                    Ok(connection) => connection,
-                    Err(err) => {
-                        tx.send(Err(err.into())).await.ok();
-                        return;
-                    }
-                };
-                if let Some(key) = keys.next_item().await {
-                    let key = match key {
-                        Ok(key) => key,
-                        Err(err) => {
-                            tx.send(Err(err.into())).await.ok();
-                            return;
-                        }
-                    };
-                    let value: Result<Option<Vec<u8>>, RedisError> = connection.get(&key).await;
-                    match value {
-                        Ok(Some(value)) => {
-                            tx.send(Ok((key, value))).await.ok();
-                        }
-                        Ok(None) => {
-                            continue;
-                        }
-                        Err(err) => {
-                            tx.send(Err(err.into())).await.ok();
-                        }
+async fn run_scan(
+    connections: &Connections,
+    target: ScanTarget,
+    count: usize,
+    tx: &Sender<Result<(String, Vec<u8>), CuttlestoreError>>,
+) -> Result<(), CuttlestoreError> {
+    match target {
+        ScanTarget::Keys(pattern) => {
+            scan_values(connections, pattern.as_deref(), None, count, tx).await
+        }
+        ScanTarget::Hash(hash, expiry) => {
+            let mut connection = connections.get().await?;
+            hashes::scan(&mut connection, expiry, &hash, count, tx).await
+        }
+        ScanTarget::Everything(expiry) => {
+            // The hashes would only be skipped by `MGET`, so leave them out
+            scan_values(connections, None, Some("string"), count, tx).await?;
+            let mut connection = connections.get().await?;
+            for hash in hashes::registered(&mut connection).await? {
+                hashes::scan(&mut connection, expiry, &hash, count, tx).await?;
+            }
+            Ok(())
+        }
+    }
+}
+
+async fn scan_values(
+    connections: &Connections,
+    pattern: Option<&str>,
+    kind: Option<&str>,
+    count: usize,
+    tx: &Sender<Result<(String, Vec<u8>), CuttlestoreError>>,
+) -> Result<(), CuttlestoreError> {
+    match connections {
+        Connections::Cluster(connection) => {
+            scan_cluster(connection.clone(), pattern, kind, count, tx).await
+        }
+        connections => scan_node(connections, pattern, kind, count, tx).await,
+    }
+}
+
+/// Scan a single server in batches, fetching the values for each batch with
+/// one `MGET`. The same connection is used for the whole scan.
+///
+/// A scan can return the same key more than once. Only the repeats within a
+/// batch are dropped, so that the scan doesn't have to remember every key it
+/// has seen, and a key may still be sent again in a later batch.
+async fn scan_node(
+    connections: &Connections,
+    pattern: Option<&str>,
+    kind: Option<&str>,
+    count: usize,
+    tx: &Sender<Result<(String, Vec<u8>), CuttlestoreError>>,
+) -> Result<(), CuttlestoreError> {
+    let mut connection = connections.get().await?;
+    let mut cursor: u64 = 0;
+    loop {
+        let (next, mut keys): (u64, Vec<String>) = scan_command(cursor, pattern, kind, count)
+            .query_async(&mut connection)
+            .await?;
+        dedup_batch(&mut keys);
+
+        if !keys.is_empty() {
+            let values: Vec<Option<Vec<u8>>> = redis::cmd("MGET")
+                .arg(&keys)
+                .query_async(&mut connection)
+                .await?;
+            if !send_batch(tx, keys, values).await {
+                return Ok(());
+            }
+        }
+
+        if next == 0 {
+            return Ok(());
+        }
+        cursor = next;
+    }
+}
+
+/// A scan only covers the node it runs on, so a cluster has to be scanned one
+/// master at a time. Like `scan_node`, repeated keys are only dropped within
+/// a batch.
+async fn scan_cluster(
+    mut connection: ClusterConnection,
+    pattern: Option<&str>,
+    kind: Option<&str>,
+    count: usize,
+    tx: &Sender<Result<(String, Vec<u8>), CuttlestoreError>>,
+) -> Result<(), CuttlestoreError> {
+    for (host, port) in cluster_masters(&mut connection).await? {
+        let mut cursor: u64 = 0;
+        loop {
+            let routing = RoutingInfo::SingleNode(SingleNodeRoutingInfo::ByAddress {
+                host: host.clone(),
+                port,
+            });
+            let response = connection
+                .route_command(scan_command(cursor, pattern, kind, count), routing)
+                .await?;
+            let (next, mut keys): (u64, Vec<String>) =
+                redis::from_redis_value(response).map_err(RedisError::from)?;
+            dedup_batch(&mut keys);
+
+            // The keys in a batch can be in different slots, which one `MGET`
+            // can't span. The `GET`s are sent all at once instead, and the
+            // cluster connection pipelines them to the nodes.
+            let values = try_join_all(keys.iter().map(|key| {
+                let mut connection = connection.clone();
+                async move { connection.get::<_, Option<Vec<u8>>>(key).await }
+            }))
+            .await?;
+            if !send_batch(tx, keys, values).await {
+                return Ok(());
+            }
+
+            if next == 0 {
+                break;
+            }
+            cursor = next;
+        }
+    }
+    Ok(())
+}
+
+fn scan_command(
+    cursor: u64,
+    pattern: Option<&str>,
+    kind: Option<&str>,
+    count: usize,
+) -> redis::Cmd {
+    let mut scan = redis::cmd("SCAN");
+    scan.arg(cursor);
+    if let Some(pattern) = pattern {
+        scan.arg("MATCH").arg(pattern);
+    }
+    scan.arg("COUNT").arg(count);
+    if let Some(kind) = kind {
+        scan.arg("TYPE").arg(kind);
+    }
+    scan
+}
+
+/// Find all the keys matching the pattern and type, on every master.
+async fn scan_keys(
+    connections: &Connections,
+    pattern: Option<&str>,
+    kind: Option<&str>,
+    count: usize,
+) -> Result<Vec<String>, CuttlestoreError> {
+    let mut found = Vec::new();
+    match connections {
+        Connections::Cluster(connection) => {
+            let mut connection = connection.clone();
+            for (host, port) in cluster_masters(&mut connection).await? {
+                let mut cursor: u64 = 0;
+                loop {
+                    let routing = RoutingInfo::SingleNode(SingleNodeRoutingInfo::ByAddress {
+                        host: host.clone(),
+                        port,
+                    });
+                    let response = connection
+                        .route_command(scan_command(cursor, pattern, kind, count), routing)
+                        .await?;
+                    let (next, keys): (u64, Vec<String>) =
+                        redis::from_redis_value(response).map_err(RedisError::from)?;
+                    found.extend(keys);
+                    if next == 0 {
+                        break;
                     }
-                } else {
-                    return;
+                    cursor = next;
                 }
             }
-        });
-        Self { handle, rx }
+        }
+        connections => {
+            let mut connection = connections.get().await?;
+            let mut cursor: u64 = 0;
+            loop {
+                let (next, keys): (u64, Vec<String>) = scan_command(cursor, pattern, kind, count)
+                    .query_async(&mut connection)
+                    .await?;
+                found.extend(keys);
+                if next == 0 {
+                    break;
+                }
+                cursor = next;
+            }
+        }
+    }
+    // A scan can return the same key more than once
+    found.sort();
+    found.dedup();
+    Ok(found)
+}
+
+/// Drop the keys that appear more than once in a batch, keeping the order of
+/// the rest.
+fn dedup_batch(keys: &mut Vec<String>) {
+    let mut seen = HashSet::with_capacity(keys.len());
+    keys.retain(|key| seen.insert(key.clone()));
+}
+
+/// Send the values that were found, skipping keys that were deleted or
+/// expired after they were scanned. Returns false if the stream was dropped.
+async fn send_batch(
+    tx: &Sender<Result<(String, Vec<u8>), CuttlestoreError>>,
+    keys: Vec<String>,
+    values: Vec<Option<Vec<u8>>>,
+) -> bool {
+    for (key, value) in keys.into_iter().zip(values) {
+        if let Some(value) = value {
+            if tx.send(Ok((key, value))).await.is_err() {
+                return false;
+            }
+        }
+    }
+    true
+}
+
+/// A `MATCH` pattern for the keys starting with `prefix`.
+fn prefix_pattern(prefix: &str) -> String {
+    let mut pattern = String::with_capacity(prefix.len() + 1);
+    for char in prefix.chars() {
+        if matches!(char, '*' | '?' | '[' | ']' | '\\') {
+            pattern.push('\\');
+        }
+        pattern.push(char);
     }
+    pattern.push('*');
+    pattern
+}
+
+/// The addresses of the masters in the cluster, from `CLUSTER SLOTS`.
+async fn cluster_masters(
+    connection: &mut ClusterConnection,
+) -> Result<Vec<(String, u16)>, CuttlestoreError> {
+    let slots: Vec<Vec<Value>> = redis::cmd("CLUSTER")
+        .arg("SLOTS")
+        .query_async(connection)
+        .await?;
+    let mut masters: Vec<(String, u16)> = Vec::new();
+    // Each entry is the start and end of a slot range, followed by the master
+    // and then the replicas for that range.
+    for range in slots {
+        let Some(master) = range.into_iter().nth(2) else {
+            continue;
+        };
+        let mut master = redis::from_redis_value::<Vec<Value>>(master)
+            .map_err(RedisError::from)?
+            .into_iter();
+        let (Some(host), Some(port)) = (master.next(), master.next()) else {
+            continue;
+        };
+        let host: String = redis::from_redis_value(host).map_err(RedisError::from)?;
+        let port: u16 = redis::from_redis_value(port).map_err(RedisError::from)?;
+        if !masters.contains(&(host.clone(), port)) {
+            masters.push((host, port));
+        }
+    }
+    Ok(masters)
 }
 
 impl Drop for RedisScanStream {
@@ -195,3 +588,14 @@ impl Stream for RedisScanStream {
         s.rx.poll_recv(cx)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prefix_pattern_escapes_globs() {
+        assert_eq!(prefix_pattern(":store:"), ":store:*");
+        assert_eq!(prefix_pattern("a*b?[c]\\"), "a\\*b\\?\\[c\\]\\\\*");
+    }
+}
@@ -0,0 +1,322 @@
+//! The options that can be given after the `?` in a Redis connection string.
+
+use std::{str::FromStr, time::Duration};
+
+use redis::{ClientTlsConfig, TlsCertificates};
+
+use crate::common::CuttlestoreError;
+
+/// How many keys a scan asks for at a time, unless `scan_count` is set.
+const DEFAULT_SCAN_COUNT: usize = 100;
+/// The most connections a pool opens, unless `pool_max_size` is set.
+const DEFAULT_POOL_MAX_SIZE: u32 = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Mode {
+    Single,
+    Cluster,
+    Sentinel,
+}
+
+impl Mode {
+    pub(crate) fn parse(mode: &str) -> Self {
+        match mode {
+            "cluster" => Mode::Cluster,
+            "sentinel" => Mode::Sentinel,
+            _ => Mode::Single,
+        }
+    }
+
+    fn scheme(self) -> &'static str {
+        match self {
+            Mode::Single => "redis://",
+            Mode::Cluster => "redis+cluster://",
+            Mode::Sentinel => "redis+sentinel://",
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct RedisOptions<'a> {
+    pub(crate) username: Option<&'a str>,
+    pub(crate) password: Option<&'a str>,
+    pub(crate) sentinel_username: Option<&'a str>,
+    pub(crate) sentinel_password: Option<&'a str>,
+    pub(crate) db: Option<i64>,
+    /// Paths to PEM files.
+    tls_ca: Option<&'a str>,
+    tls_cert: Option<&'a str>,
+    tls_key: Option<&'a str>,
+    pub(crate) pool_max_size: Option<u32>,
+    pub(crate) pool_min_idle: Option<u32>,
+    pub(crate) connect_timeout: Option<Duration>,
+    pub(crate) command_timeout: Option<Duration>,
+    pub(crate) client_name: Option<&'a str>,
+    pub(crate) scan_count: usize,
+    /// Set with `storage=hash`.
+    pub(crate) hash_storage: bool,
+}
+
+impl<'a> RedisOptions<'a> {
+    pub(crate) fn parse(mode: Mode, secure: bool, args: &'a str) -> Result<Self, CuttlestoreError> {
+        let mut options = RedisOptions {
+            scan_count: DEFAULT_SCAN_COUNT,
+            ..Default::default()
+        };
+
+        for pair in args.split('&').filter(|pair| !pair.is_empty()) {
+            let Some((name, value)) = pair.split_once('=') else {
+                return Err(invalid(format!(
+                    "the Redis option {pair} is missing a value"
+                )));
+            };
+            match name {
+                "username" => options.username = Some(value),
+                "password" => options.password = Some(value),
+                "sentinel_username" => options.sentinel_username = Some(value),
+                "sentinel_password" => options.sentinel_password = Some(value),
+                "db" => options.db = Some(number(name, value)?),
+                "tls_ca" => options.tls_ca = Some(value),
+                "tls_cert" => options.tls_cert = Some(value),
+                "tls_key" => options.tls_key = Some(value),
+                "pool_max_size" => options.pool_max_size = Some(number(name, value)?),
+                "pool_min_idle" => options.pool_min_idle = Some(number(name, value)?),
+                "connect_timeout" => options.connect_timeout = Some(seconds(name, value)?),
+                "command_timeout" => options.command_timeout = Some(seconds(name, value)?),
+                "client_name" => options.client_name = Some(value),
+                "scan_count" => {
+                    options.scan_count = match value.parse::<usize>() {
+                        Ok(count) if count > 0 => count,
+                        _ => {
+                            return Err(invalid(format!(
+                                "scan_count must be a positive number, got {value}"
+                            )))
+                        }
+                    }
+                }
+                "storage" => {
+                    options.hash_storage = match value {
+                        "keys" => false,
+                        "hash" => true,
+                        _ => {
+                            return Err(invalid(format!(
+                                "storage must be keys or hash, got {value}"
+                            )))
+                        }
+                    }
+                }
+                _ => return Err(invalid(format!("unknown Redis option {name}"))),
+            }
+        }
+
+        options.check(mode, secure)?;
+        Ok(options)
+    }
+
+    /// Reject the options that don't make sense together.
+    fn check(&self, mode: Mode, secure: bool) -> Result<(), CuttlestoreError> {
+        if mode != Mode::Sentinel {
+            if let Some(name) = first_given(&[
+                ("sentinel_username", self.sentinel_username.is_some()),
+                ("sentinel_password", self.sentinel_password.is_some()),
+            ]) {
+                return Err(invalid(format!(
+                    "{name} can only be used with redis+sentinel://"
+                )));
+            }
+        }
+        // The cluster connection manages its own connections to the nodes
+        if mode == Mode::Cluster {
+            if let Some(name) = first_given(&[
+                ("pool_max_size", self.pool_max_size.is_some()),
+                ("pool_min_idle", self.pool_min_idle.is_some()),
+                ("client_name", self.client_name.is_some()),
+            ]) {
+                return Err(invalid(format!(
+                    "{name} can't be used with {}",
+                    mode.scheme()
+                )));
+            }
+        }
+        if !secure {
+            if let Some(name) = first_given(&[
+                ("tls_ca", self.tls_ca.is_some()),
+                ("tls_cert", self.tls_cert.is_some()),
+                ("tls_key", self.tls_key.is_some()),
+            ]) {
+                return Err(invalid(format!(
+                    "{name} needs TLS, use rediss:// instead of redis://"
+                )));
+            }
+        }
+        if self.tls_cert.is_some() != self.tls_key.is_some() {
+            return Err(invalid(
+                "tls_cert and tls_key have to be given together".to_string(),
+            ));
+        }
+        if self.pool_max_size == Some(0) {
+            return Err(invalid("pool_max_size must be at least 1".to_string()));
+        }
+        if let Some(min_idle) = self.pool_min_idle {
+            let max_size = self.pool_max_size.unwrap_or(DEFAULT_POOL_MAX_SIZE);
+            if min_idle > max_size {
+                return Err(invalid(format!(
+                    "pool_min_idle ({min_idle}) can't be larger than pool_max_size ({max_size})"
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Read the certificates, if any were given.
+    pub(crate) fn certificates(&self) -> Result<Option<TlsCertificates>, CuttlestoreError> {
+        if self.tls_ca.is_none() && self.tls_cert.is_none() {
+            return Ok(None);
+        }
+        let client_tls = match (self.tls_cert, self.tls_key) {
+            (Some(cert), Some(key)) => Some(ClientTlsConfig {
+                client_cert: read_pem(cert)?,
+                client_key: read_pem(key)?,
+            }),
+            _ => None,
+        };
+        let root_cert = self.tls_ca.map(read_pem).transpose()?;
+        Ok(Some(TlsCertificates {
+            client_tls,
+            root_cert,
+        }))
+    }
+
+    /// A pool builder with the pool options applied.
+    pub(crate) fn pool_builder<M: bb8::ManageConnection>(&self) -> bb8::Builder<M> {
+        let mut builder = bb8::Pool::builder()
+            .max_size(self.pool_max_size.unwrap_or(DEFAULT_POOL_MAX_SIZE))
+            .min_idle(self.pool_min_idle);
+        if let Some(timeout) = self.connect_timeout {
+            builder = builder.connection_timeout(timeout);
+        }
+        builder
+    }
+}
+
+fn invalid(message: String) -> CuttlestoreError {
+    CuttlestoreError::InvalidConnectionString(message)
+}
+
+/// The name of the first option that was given.
+fn first_given(options: &[(&'static str, bool)]) -> Option<&'static str> {
+    options
+        .iter()
+        .find(|(_, given)| *given)
+        .map(|(name, _)| *name)
+}
+
+fn number<T: FromStr>(name: &str, value: &str) -> Result<T, CuttlestoreError> {
+    value
+        .parse()
+        .map_err(|_| invalid(format!("{name} must be a number, got {value}")))
+}
+
+/// A timeout in seconds, which can have a fraction like `0.5`.
+fn seconds(name: &str, value: &str) -> Result<Duration, CuttlestoreError> {
+    value
+        .parse::<f64>()
+        .ok()
+        .filter(|seconds| *seconds > 0.0)
+        .and_then(|seconds| Duration::try_from_secs_f64(seconds).ok())
+        .ok_or_else(|| {
+            invalid(format!(
+                "{name} must be a positive number of seconds, got {value}"
+            ))
+        })
+}
+
+fn read_pem(path: &str) -> Result<Vec<u8>, CuttlestoreError> {
+    std::fs::read(path).map_err(|err| invalid(format!("unable to read {path}: {err}")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(mode: Mode, secure: bool, args: &str) -> String {
+        match RedisOptions::parse(mode, secure, args) {
+            Err(CuttlestoreError::InvalidConnectionString(message)) => message,
+            other => panic!("expected an invalid connection string, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn parses_options() {
+        let options = RedisOptions::parse(
+            Mode::Single,
+            false,
+            "db=3&pool_max_size=20&pool_min_idle=2&connect_timeout=0.5&command_timeout=3&client_name=app",
+        )
+        .unwrap();
+        assert_eq!(options.db, Some(3));
+        assert_eq!(options.pool_max_size, Some(20));
+        assert_eq!(options.pool_min_idle, Some(2));
+        assert_eq!(options.connect_timeout, Some(Duration::from_millis(500)));
+        assert_eq!(options.command_timeout, Some(Duration::from_secs(3)));
+        assert_eq!(options.client_name, Some("app"));
+        assert_eq!(options.scan_count, DEFAULT_SCAN_COUNT);
+        assert!(!options.hash_storage);
+    }
+
+    #[test]
+    fn rejects_unknown_options() {
+        assert_eq!(
+            error(Mode::Single, false, "username=a&databse=2"),
+            "unknown Redis option databse"
+        );
+        assert_eq!(
+            error(Mode::Single, false, "db"),
+            "the Redis option db is missing a value"
+        );
+    }
+
+    #[test]
+    fn rejects_bad_values() {
+        assert_eq!(
+            error(Mode::Single, false, "db=one"),
+            "db must be a number, got one"
+        );
+        assert_eq!(
+            error(Mode::Single, false, "command_timeout=-1"),
+            "command_timeout must be a positive number of seconds, got -1"
+        );
+        assert_eq!(
+            error(Mode::Single, false, "pool_max_size=0"),
+            "pool_max_size must be at least 1"
+        );
+        assert_eq!(
+            error(Mode::Single, false, "pool_max_size=2&pool_min_idle=5"),
+            "pool_min_idle (5) can't be larger than pool_max_size (2)"
+        );
+        assert_eq!(
+            error(Mode::Single, false, "pool_min_idle=20"),
+            "pool_min_idle (20) can't be larger than pool_max_size (10)"
+        );
+    }
+
+    #[test]
+    fn rejects_options_for_other_modes() {
+        assert_eq!(
+            error(Mode::Single, false, "sentinel_password=a"),
+            "sentinel_password can only be used with redis+sentinel://"
+        );
+        assert_eq!(
+            error(Mode::Cluster, false, "pool_max_size=5"),
+            "pool_max_size can't be used with redis+cluster://"
+        );
+        assert_eq!(
+            error(Mode::Single, false, "tls_ca=ca.pem"),
+            "tls_ca needs TLS, use rediss:// instead of redis://"
+        );
+        assert_eq!(
+            error(Mode::Single, true, "tls_cert=client.pem"),
+            "tls_cert and tls_key have to be given together"
+        );
+    }
+}
@@ -1,49 +1,201 @@
-use std::borrow::Cow;
+use std::{borrow::Cow, collections::HashMap, str::FromStr, time::Duration};
 
 use async_stream::try_stream;
 use async_trait::async_trait;
 use futures::stream::BoxStream;
-use lazy_regex::regex_captures;
-use sqlx::sqlite::{SqliteConnectOptions, SqlitePool};
+use lazy_regex::{regex_captures, regex_is_match};
+use sqlx::sqlite::{
+    SqliteConnectOptions, SqliteJournalMode, SqlitePool, SqlitePoolOptions, SqliteSynchronous,
+};
 
 use crate::{
     backend_api::{CuttleBackend, PutOptions},
     common::{get_system_time, CuttlestoreError},
 };
 
+/// The connection string options. Unlike the other SQL backends there is no
+/// driver to pass the rest along to, so anything else is rejected.
+const OPTIONS: &[&str] = &[
+    "table",
+    "journal_mode",
+    "synchronous",
+    "busy_timeout",
+    "pool_max_size",
+    "shared_cache",
+];
+
+/// The path that keeps the database in memory instead of in a file.
+const MEMORY: &str = ":memory:";
+
 pub(crate) struct SqliteBackend {
     pool: SqlitePool,
+    queries: Queries,
+}
+
+/// The table name can't be a query parameter, so the queries are put together
+/// once when the store is opened.
+struct Queries {
+    get: String,
+    put: String,
+    delete: String,
+    scan: String,
+    purge_expired: String,
+    purge_expired_limited: String,
+    take_lease: String,
+}
+
+impl Queries {
+    fn new(table: &str) -> Self {
+        Queries {
+            get: format!(r#"SELECT value, live_until as "live_until?" FROM {table} WHERE key = ?"#),
+            put: format!("INSERT INTO {table} (key, value, live_until) VALUES (?, ?, ?) ON CONFLICT(key) DO UPDATE SET value = excluded.value, live_until = excluded.live_until"),
+            delete: format!("DELETE FROM {table} WHERE key = ?"),
+            scan: format!("SELECT key, value, live_until FROM {table}"),
+            purge_expired: format!("DELETE FROM {table} WHERE live_until < ?"),
+            purge_expired_limited: format!("DELETE FROM {table} WHERE key IN (SELECT key FROM {table} WHERE live_until < ? LIMIT ?)"),
+            take_lease: format!("INSERT INTO {table}_leases (key, holder, live_until) VALUES (?, ?, ?) ON CONFLICT(key) DO UPDATE SET holder = excluded.holder, live_until = excluded.live_until WHERE {table}_leases.holder = excluded.holder OR {table}_leases.live_until < ?"),
+        }
+    }
 }
 
 impl SqliteBackend {
-    async fn new(path: &str) -> Result<Box<Self>, CuttlestoreError> {
-        let options = SqliteConnectOptions::new()
-            .filename(path)
-            .create_if_missing(true)
+    async fn new(path: &str, args: HashMap<&str, &str>) -> Result<Box<Self>, CuttlestoreError> {
+        let table = args.get("table").copied().unwrap_or("cuttlestore");
+        // The table name has to be inlined into the queries, so only allow
+        // plain identifiers.
+        if !regex_is_match!(r#"^[A-Za-z_][A-Za-z0-9_]*$"#, table) {
+            return Err(CuttlestoreError::InvalidConnectionString(format!(
+                "invalid sqlite table name {table}"
+            )));
+        }
+        // Use write-ahead logging journal, with a less strict sync mode by
+        // default. This presents a small risk of data loss, but no risk of
+        // corruption.
+        let journal_mode = match args.get("journal_mode") {
+            Some(mode) => SqliteJournalMode::from_str(mode).map_err(|_| {
+                CuttlestoreError::InvalidConnectionString(format!(
+                    "unknown sqlite journal_mode {mode}"
+                ))
+            })?,
+            None => SqliteJournalMode::Wal,
+        };
+        let synchronous = match args.get("synchronous") {
+            Some(mode) => SqliteSynchronous::from_str(mode).map_err(|_| {
+                CuttlestoreError::InvalidConnectionString(format!(
+                    "unknown sqlite synchronous mode {mode}"
+                ))
+            })?,
+            None => SqliteSynchronous::Normal,
+        };
+        // shared cache with read_uncommitted drops the isolation level for
+        // better performance
+        let shared_cache = match args.get("shared_cache") {
+            None | Some(&"true") => true,
+            Some(&"false") => false,
+            Some(value) => {
+                return Err(CuttlestoreError::InvalidConnectionString(format!(
+                    "shared_cache must be true or false, got {value}"
+                )))
+            }
+        };
+        let busy_timeout = args
+            .get("busy_timeout")
+            .map(|value| {
+                value
+                    .parse::<f64>()
+                    .ok()
+                    .filter(|seconds| *seconds >= 0.0)
+                    .and_then(|seconds| Duration::try_from_secs_f64(seconds).ok())
+                    .ok_or_else(|| {
+                        CuttlestoreError::InvalidConnectionString(format!(
+                            "busy_timeout must be a number of seconds, got {value}"
+                        ))
+                    })
+            })
+            .transpose()?;
+        let pool_max_size = args
+            .get("pool_max_size")
+            .map(|value| match value.parse::<u32>() {
+                Ok(size) if size > 0 => Ok(size),
+                _ => Err(CuttlestoreError::InvalidConnectionString(format!(
+                    "pool_max_size must be a positive number, got {value}"
+                ))),
+            })
+            .transpose()?;
+
+        let in_memory = path == MEMORY;
+        let mut options = if in_memory {
+            // Every connection in the pool has to see the same database, so
+            // this gets a uniquely named database with a shared cache.
+            SqliteConnectOptions::from_str("sqlite::memory:")?
+        } else {
+            SqliteConnectOptions::new()
+                .filename(path)
+                .create_if_missing(true)
+                .shared_cache(shared_cache)
+        };
+        options = options
             .statement_cache_capacity(10)
-            // Use write-ahead logging journal, with a less strict sync
-            // mode. This presents a small risk of data loss, but no risk of
-            // corruption.
-            .journal_mode(sqlx::sqlite::SqliteJournalMode::Wal)
-            .synchronous(sqlx::sqlite::SqliteSynchronous::Normal)
-            // shared cache with read_uncommitted drops the isolation level for better performance
-            .shared_cache(true)
-            .pragma("read_uncommitted", "true");
-        let pool = SqlitePool::connect_with(options).await?;
+            .journal_mode(journal_mode)
+            .synchronous(synchronous);
+        if shared_cache || in_memory {
+            options = options.pragma("read_uncommitted", "true");
+        }
+        if let Some(timeout) = busy_timeout {
+            options = options.busy_timeout(timeout);
+        }
+
+        let mut pool = SqlitePoolOptions::new();
+        if let Some(size) = pool_max_size {
+            pool = pool.max_connections(size);
+        }
+        if in_memory {
+            // The database is gone once the last connection to it closes
+            pool = pool
+                .min_connections(1)
+                .idle_timeout(None)
+                .max_lifetime(None);
+        }
+        let pool = pool.connect_with(options).await?;
+
         // Create the table in case it is missing
-        sqlx::query("CREATE TABLE IF NOT EXISTS cuttlestore (key STRING PRIMARY KEY NOT NULL, value BLOB NOT NULL, live_until INTEGER)").execute(&pool).await?;
-        Ok(Box::new(SqliteBackend { pool }))
+        sqlx::query(&format!("CREATE TABLE IF NOT EXISTS {table} (key STRING PRIMARY KEY NOT NULL, value BLOB NOT NULL, live_until INTEGER)")).execute(&pool).await?;
+        // Only values with a ttl are indexed, which is all the cleaner needs
+        sqlx::query(&format!(
+            "CREATE INDEX IF NOT EXISTS {table}_live_until ON {table} (live_until) WHERE live_until IS NOT NULL"
+        ))
+        .execute(&pool)
+        .await?;
+        sqlx::query(&format!("CREATE TABLE IF NOT EXISTS {table}_leases (key TEXT PRIMARY KEY NOT NULL, holder TEXT NOT NULL, live_until INTEGER NOT NULL)")).execute(&pool).await?;
+
+        Ok(Box::new(SqliteBackend {
+            pool,
+            queries: Queries::new(table),
+        }))
     }
 }
 
 #[async_trait]
 impl CuttleBackend for SqliteBackend {
     async fn new(conn: &str) -> Option<Result<Box<Self>, CuttlestoreError>> {
-        if let Some((_, path)) = regex_captures!(r#"^sqlite://(.+)"#, conn) {
-            Some(SqliteBackend::new(path).await)
-        } else {
-            None
+        // Everything after the first `?` is options, so the path can't have one
+        let (_, path, args) = regex_captures!(r#"^sqlite://([^?]+)[?]?(.*)"#, conn)?;
+        let mut arg_pairs = HashMap::new();
+        for pair in args.split('&').filter(|pair| !pair.is_empty()) {
+            let Some((name, value)) = pair.split_once('=') else {
+                return Some(Err(CuttlestoreError::InvalidConnectionString(format!(
+                    "the sqlite option {pair} is missing a value"
+                ))));
+            };
+            if !OPTIONS.contains(&name) {
+                return Some(Err(CuttlestoreError::InvalidConnectionString(format!(
+                    "unknown sqlite option {name}"
+                ))));
+            }
+            arg_pairs.insert(name, value);
         }
+
+        Some(SqliteBackend::new(path, arg_pairs).await)
     }
 
     fn requires_cleaner(&self) -> bool {
@@ -55,12 +207,10 @@ impl CuttleBackend for SqliteBackend {
     }
 
     async fn get<'a>(&self, key: Cow<'a, str>) -> Result<Option<Vec<u8>>, CuttlestoreError> {
-        let row: Option<(Vec<u8>, Option<i64>)> = sqlx::query_as(
-            r#"SELECT value, live_until as "live_until?" FROM cuttlestore WHERE key = ?"#,
-        )
-        .bind(key.as_ref())
-        .fetch_optional(&self.pool)
-        .await?;
+        let row: Option<(Vec<u8>, Option<i64>)> = sqlx::query_as(&self.queries.get)
+            .bind(key.as_ref())
+            .fetch_optional(&self.pool)
+            .await?;
         match row {
             Some((value, live_until)) => {
                 if let Some(live_until) = live_until {
@@ -83,12 +233,10 @@ impl CuttleBackend for SqliteBackend {
     ) -> Result<(), CuttlestoreError> {
         // Oops, sqlite can't store i64's. Casting should be fine though for
         let live_until = options.ttl.map(|t| (t + get_system_time()) as i64);
-        sqlx::query("INSERT INTO cuttlestore (key, value, live_until) VALUES (?, ?, ?) ON CONFLICT(key) DO UPDATE SET value = ?, live_until = ?")
+        sqlx::query(&self.queries.put)
             .bind(key.as_ref())
             .bind(value)
             .bind(live_until)
-            .bind(value)
-            .bind(live_until)
             .execute(&self.pool)
             .await?;
 
@@ -96,7 +244,7 @@ impl CuttleBackend for SqliteBackend {
     }
 
     async fn delete<'a>(&self, key: Cow<'a, str>) -> Result<(), CuttlestoreError> {
-        sqlx::query("DELETE FROM cuttlestore WHERE key = ?")
+        sqlx::query(&self.queries.delete)
             .bind(key.as_ref())
             .execute(&self.pool)
             .await?;
@@ -107,24 +255,58 @@ impl CuttleBackend for SqliteBackend {
     async fn scan(
         &self,
     ) -> Result<BoxStream<Result<(String, Vec<u8>), CuttlestoreError>>, CuttlestoreError> {
-        let rows = sqlx::query_as::<_, (String, Vec<u8>, Option<i64>)>(
-            "SELECT key, value, live_until FROM cuttlestore",
-        )
-        .fetch(&self.pool);
+        let rows = sqlx::query_as::<_, (String, Vec<u8>, Option<i64>)>(&self.queries.scan)
+            .fetch(&self.pool);
 
         Ok(Box::pin(try_stream! {
+          // The rows stream holds on to a connection until it ends, so the
+          // expired pairs are deleted after that. Otherwise the deletes would
+          // wait for a connection until they time out when the pool only has
+          // one.
+          let mut expired = Vec::new();
           for await row in rows {
             let (key, value, live_until) = row?;
 
             if let Some(live_until) = live_until {
                 if live_until < get_system_time() as i64 {
-                    self.delete(Cow::Borrowed(&key)).await?;
+                    expired.push(key);
                     continue;
                   }
             }
 
             yield (key, value);
           }
+          for key in expired {
+            self.delete(Cow::Owned(key)).await?;
+          }
         }))
     }
+
+    async fn purge_expired(&self, limit: Option<u64>) -> Result<u64, CuttlestoreError> {
+        let query = match limit {
+            Some(limit) => sqlx::query(&self.queries.purge_expired_limited)
+                .bind(get_system_time() as i64)
+                .bind(limit as i64),
+            None => sqlx::query(&self.queries.purge_expired).bind(get_system_time() as i64),
+        };
+        let result = query.execute(&self.pool).await?;
+        Ok(result.rows_affected())
+    }
+
+    async fn take_lease<'a>(
+        &self,
+        key: Cow<'a, str>,
+        holder: &'a str,
+        ttl: u64,
+    ) -> Result<bool, CuttlestoreError> {
+        let now = get_system_time();
+        let result = sqlx::query(&self.queries.take_lease)
+            .bind(key.as_ref())
+            .bind(holder)
+            .bind((now + ttl) as i64)
+            .bind(now as i64)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected() == 1)
+    }
 }
@@ -3,8 +3,8 @@ use std::{borrow::Cow, collections::HashMap};
 use async_stream::try_stream;
 use async_trait::async_trait;
 use futures::stream::BoxStream;
-use lazy_regex::regex_captures;
-use serde::{Deserialize, Serialize};
+use lazy_regex::{regex_captures, regex_is_match};
+use serde::{de::IgnoredAny, Deserialize, Serialize};
 use surrealdb::{
     engine::any::{connect, Any},
     opt::auth::Root,
@@ -20,10 +20,15 @@ use crate::{
 /// Default table name used to store cuttlestore records. Can be overridden via
 /// the `table=<name>` query parameter on the connection string.
 const DEFAULT_TABLE: &str = "cuttlestore";
+/// How many records a scan reads at a time.
+const SCAN_PAGE: usize = 500;
 
 pub(crate) struct SurrealdbBackend {
     db: Surreal<Any>,
     table: String,
+    /// Whether an event deletes the expired records whenever a record is
+    /// written, instead of the cleaner.
+    expire_on_write: bool,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -37,8 +42,6 @@ struct StoredRecord {
 struct StoredRecordWithId {
     id: Thing,
     value: Vec<u8>,
-    #[serde(default)]
-    live_until: Option<u64>,
 }
 
 impl SurrealdbBackend {
@@ -49,6 +52,7 @@ impl SurrealdbBackend {
         table: String,
         username: Option<String>,
         password: Option<String>,
+        expire_on_write: bool,
     ) -> Result<Box<Self>, CuttlestoreError> {
         let db = connect(endpoint).await?;
         if let (Some(user), Some(pass)) = (username.as_deref(), password.as_deref()) {
@@ -59,7 +63,53 @@ impl SurrealdbBackend {
             .await?;
         }
         db.use_ns(namespace).use_db(database).await?;
-        Ok(Box::new(SurrealdbBackend { db, table }))
+
+        // Only records with a ttl have a `live_until`, which is all the
+        // cleaner needs to find
+        db.query(format!(
+            "DEFINE INDEX IF NOT EXISTS {table}_live_until ON TABLE {table} FIELDS live_until"
+        ))
+        .await?
+        .check()?;
+        if expire_on_write {
+            // Runs in the same transaction as the write, with the server's
+            // clock
+            db.query(format!(
+                "DEFINE EVENT IF NOT EXISTS {table}_expire ON TABLE {table} WHEN $event = 'CREATE' OR $event = 'UPDATE' THEN (DELETE {table} WHERE live_until != NONE AND live_until < time::unix())"
+            ))
+            .await?
+            .check()?;
+        }
+
+        Ok(Box::new(SurrealdbBackend {
+            db,
+            table,
+            expire_on_write,
+        }))
+    }
+
+    /// Read a page of the live records, in the order of their ids. The page
+    /// starts after the record `after`, or at the first record if there is
+    /// none.
+    async fn scan_page(
+        &self,
+        after: Option<Thing>,
+    ) -> Result<Vec<StoredRecordWithId>, CuttlestoreError> {
+        let after_clause = match after {
+            Some(_) => " AND id > $after",
+            None => "",
+        };
+        let mut response = self
+            .db
+            .query(format!(
+                "SELECT id, value FROM type::table($table) WHERE (live_until = NONE OR live_until >= $now){after_clause} ORDER BY id LIMIT {SCAN_PAGE}"
+            ))
+            .bind(("table", self.table.clone()))
+            .bind(("now", get_system_time()))
+            .bind(("after", after))
+            .await?
+            .check()?;
+        Ok(response.take(0)?)
     }
 }
 
@@ -67,26 +117,15 @@ impl SurrealdbBackend {
 impl CuttleBackend for SurrealdbBackend {
     async fn new(conn: &str) -> Option<Result<Box<Self>, CuttlestoreError>> {
         // Format: surrealdb[s]://[user:pass@]host[:port]/namespace/database[?table=...]
-        let (_, secure, rest) = regex_captures!(r#"^surrealdb(s)?://(.+)"#, conn)?;
-        let scheme = if secure.is_empty() { "ws" } else { "wss" };
-
-        // Pull out auth (user:pass@) if present.
-        let (auth, host_and_path) = match rest.split_once('@') {
-            Some((auth, host)) => (Some(auth), host),
-            None => (None, rest),
-        };
-        let (username, password) = match auth {
-            Some(auth) => match auth.split_once(':') {
-                Some((u, p)) => (Some(u.to_string()), Some(p.to_string())),
-                None => (Some(auth.to_string()), None),
-            },
-            None => (None, None),
-        };
+        // or the embedded engines, surrealdb+mem://namespace/database and
+        // surrealdb+surrealkv://path/namespace/database
+        let (_, engine, rest) =
+            regex_captures!(r#"^surrealdb(s|\+mem|\+surrealkv)?://(.+)"#, conn)?;
 
         // Split off the query string.
-        let (path_part, query_part) = match host_and_path.split_once('?') {
+        let (location, query_part) = match rest.split_once('?') {
             Some((p, q)) => (p, q),
-            None => (host_and_path, ""),
+            None => (rest, ""),
         };
         let args: HashMap<&str, &str> = if query_part.is_empty() {
             HashMap::new()
@@ -97,16 +136,58 @@ impl CuttleBackend for SurrealdbBackend {
                 .collect()
         };
 
-        // Split host from /namespace/database
-        let mut segments = path_part.splitn(3, '/');
-        let host = match segments.next() {
-            Some(h) if !h.is_empty() => h,
+        let (endpoint, path_part, username, password) = match engine {
+            "+mem" => ("mem://".to_string(), location, None, None),
+            "+surrealkv" => {
+                // The namespace and database are the last two parts of the path
+                match location.rmatch_indices('/').nth(1) {
+                    Some((split, _)) if split > 0 => (
+                        format!("surrealkv://{}", &location[..split]),
+                        &location[split + 1..],
+                        None,
+                        None,
+                    ),
+                    _ => {
+                        return Some(Err(CuttlestoreError::SurrealdbError(
+                            "connection string must include path/namespace/database".into(),
+                        )))
+                    }
+                }
+            }
             _ => {
-                return Some(Err(CuttlestoreError::SurrealdbError(
-                    "connection string must include host".into(),
-                )))
+                let scheme = if engine.is_empty() { "ws" } else { "wss" };
+                // Pull out auth (user:pass@) if present.
+                let (auth, host_and_path) = match location.split_once('@') {
+                    Some((auth, host)) => (Some(auth), host),
+                    None => (None, location),
+                };
+                let (username, password) = match auth {
+                    Some(auth) => match auth.split_once(':') {
+                        Some((u, p)) => (Some(u.to_string()), Some(p.to_string())),
+                        None => (Some(auth.to_string()), None),
+                    },
+                    None => (None, None),
+                };
+                // Split host from /namespace/database
+                let (host, path_part) = match host_and_path.split_once('/') {
+                    Some((host, path)) if !host.is_empty() => (host, path),
+                    None if !host_and_path.is_empty() => (host_and_path, ""),
+                    _ => {
+                        return Some(Err(CuttlestoreError::SurrealdbError(
+                            "connection string must include host".into(),
+                        )))
+                    }
+                };
+                (
+                    format!("{scheme}://{host}/rpc"),
+                    path_part,
+                    username,
+                    password,
+                )
             }
         };
+
+        let mut segments = path_part.splitn(2, '/');
         let namespace = match segments.next() {
             Some(n) if !n.is_empty() => n.to_string(),
             _ => {
@@ -127,16 +208,42 @@ impl CuttleBackend for SurrealdbBackend {
             .get("table")
             .map(|t| (*t).to_string())
             .unwrap_or_else(|| DEFAULT_TABLE.to_string());
+        // The table name has to be inlined into the definitions, so only
+        // allow plain identifiers.
+        if !regex_is_match!(r#"^[A-Za-z_][A-Za-z0-9_]*$"#, &table) {
+            return Some(Err(CuttlestoreError::InvalidConnectionString(format!(
+                "invalid SurrealDB table name {table}"
+            ))));
+        }
+        let expire_on_write = match args.get("expire_on_write") {
+            None | Some(&"false") => false,
+            Some(&"true") => true,
+            Some(value) => {
+                return Some(Err(CuttlestoreError::InvalidConnectionString(format!(
+                    "expire_on_write must be true or false, got {value}"
+                ))))
+            }
+        };
 
-        let endpoint = format!("{scheme}://{host}/rpc");
-
-        Some(SurrealdbBackend::open(endpoint, namespace, database, table, username, password).await)
+        Some(
+            SurrealdbBackend::open(
+                endpoint,
+                namespace,
+                database,
+                table,
+                username,
+                password,
+                expire_on_write,
+            )
+            .await,
+        )
     }
 
     fn requires_cleaner(&self) -> bool {
-        // SurrealDB does not yet have a native per-record TTL, so we rely on
-        // the external cleaner to sweep expired records.
-        true
+        // SurrealDB does not have a native per-record TTL. Unless the
+        // expired records are deleted by an event, we rely on the external
+        // cleaner to sweep them.
+        !self.expire_on_write
     }
 
     fn name(&self) -> &'static str {
@@ -187,24 +294,132 @@ impl CuttleBackend for SurrealdbBackend {
     async fn scan(
         &self,
     ) -> Result<BoxStream<Result<(String, Vec<u8>), CuttlestoreError>>, CuttlestoreError> {
-        let records: Vec<StoredRecordWithId> = self.db.select(self.table.as_str()).await?;
-        let db = self.db.clone();
-        let table = self.table.clone();
+        // Expired records are filtered out by SurrealDB, and left for the
+        // cleaner to delete.
+        let first = self.scan_page(None).await?;
 
         Ok(Box::pin(try_stream! {
-            for record in records {
-                let key = record.id.id.to_raw();
-                if let Some(live_until) = record.live_until {
-                    if live_until < get_system_time() {
-                        // Best effort cleanup; ignore errors so a single
-                        // failure does not abort the scan.
-                        let _: Result<Option<StoredRecord>, _> =
-                            db.delete((table.as_str(), key.as_str())).await;
-                        continue;
-                    }
+            let mut records = first;
+            loop {
+                let last_page = records.len() < SCAN_PAGE;
+                let after = records.last().map(|record| record.id.clone());
+                for record in records {
+                    yield (record.id.id.to_raw(), record.value);
+                }
+                match after {
+                    Some(after) if !last_page => records = self.scan_page(Some(after)).await?,
+                    _ => break,
                 }
-                yield (key, record.value);
             }
         }))
     }
+
+    async fn purge_expired(&self, limit: Option<u64>) -> Result<u64, CuttlestoreError> {
+        // Only the ids of the removed records come back, rather than the
+        // records themselves. Records without a ttl have no `live_until`.
+        let removed = match limit {
+            None => {
+                let mut response = self
+                    .db
+                    .query("DELETE type::table($table) WHERE live_until != NONE AND live_until < $now RETURN id")
+                    .bind(("table", self.table.clone()))
+                    .bind(("now", get_system_time()))
+                    .await?
+                    .check()?;
+                let removed: Vec<IgnoredAny> = response.take(0)?;
+                removed.len() as u64
+            }
+            // DELETE can't be limited, so pick the records to delete first.
+            // They may have been put again since, so check them once more.
+            Some(limit) => {
+                let mut response = self
+                    .db
+                    .query(format!(
+                        "LET $expired = SELECT VALUE id FROM type::table($table) WHERE live_until != NONE AND live_until < $now LIMIT {limit};
+                        DELETE $expired WHERE live_until != NONE AND live_until < $now RETURN id;",
+                    ))
+                    .bind(("table", self.table.clone()))
+                    .bind(("now", get_system_time()))
+                    .await?
+                    .check()?;
+                let removed: Vec<IgnoredAny> = response.take(1)?;
+                removed.len() as u64
+            }
+        };
+        Ok(removed)
+    }
+
+    async fn take_lease<'a>(
+        &self,
+        key: Cow<'a, str>,
+        holder: &'a str,
+        ttl: u64,
+    ) -> Result<bool, CuttlestoreError> {
+        // A lease that was never taken has no holder yet
+        let now = get_system_time();
+        let mut response = self
+            .db
+            .query("UPSERT type::thing($leases, $key) SET holder = $holder, live_until = $live_until WHERE holder = NONE OR holder = $holder OR live_until < $now RETURN VALUE holder")
+            .bind(("leases", format!("{}_leases", self.table)))
+            .bind(("key", key.into_owned()))
+            .bind(("holder", holder.to_string()))
+            .bind(("live_until", now + ttl))
+            .bind(("now", now))
+            .await?
+            .check()?;
+        let holders: Vec<String> = response.take(0)?;
+        Ok(!holders.is_empty())
+    }
+}
+
+#[cfg(all(test, feature = "backend-surrealdb-mem"))]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn leases_have_one_holder() {
+        let backend = *<SurrealdbBackend as CuttleBackend>::new("surrealdb+mem://test/leases")
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert!(backend.take_lease("cleaner".into(), "a", 1).await.unwrap());
+        assert!(!backend.take_lease("cleaner".into(), "b", 1).await.unwrap());
+        // The holder can renew the lease
+        assert!(backend.take_lease("cleaner".into(), "a", 1).await.unwrap());
+        // The lease isn't one of the records in the store
+        assert_eq!(backend.count(None).await.unwrap(), 0);
+
+        // Anyone can take the lease once it expires
+        tokio::time::sleep(Duration::from_millis(2100)).await;
+        assert!(backend.take_lease("cleaner".into(), "b", 1).await.unwrap());
+        assert!(!backend.take_lease("cleaner".into(), "a", 1).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn limited_purge_counts_removed_records() {
+        let backend = *<SurrealdbBackend as CuttleBackend>::new("surrealdb+mem://test/purge")
+            .await
+            .unwrap()
+            .unwrap();
+
+        for i in 0..3 {
+            backend
+                .put(format!("short{i}").into(), b"gone", PutOptions::ttl_secs(0))
+                .await
+                .unwrap();
+        }
+        backend
+            .put("long".into(), b"stays", PutOptions::default())
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+
+        assert_eq!(backend.purge_expired(Some(2)).await.unwrap(), 2);
+        assert_eq!(backend.purge_expired(Some(2)).await.unwrap(), 1);
+        assert_eq!(backend.purge_expired(Some(2)).await.unwrap(), 0);
+        assert_eq!(backend.count(None).await.unwrap(), 1);
+    }
 }
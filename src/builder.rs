@@ -6,7 +6,7 @@ use serde::{de::DeserializeOwned, Serialize};
 use crate::{
     backend_api::CuttleBackend,
     common::{
-        cleanup::{Cleaner, CleanerOptions},
+        cleanup::{Cleaner, CleanerHandle, CleanerOptions},
         CuttlestoreError,
     },
     Cuttlestore,
@@ -18,6 +18,7 @@ pub struct CuttlestoreBuilder {
     conn: String,
     cleaner: CleanerOptions,
     prefix: Option<String>,
+    quarantine: Option<String>,
 }
 
 impl CuttlestoreBuilder {
@@ -27,15 +28,16 @@ impl CuttlestoreBuilder {
             conn: conn.as_ref().to_string(),
             cleaner: CleanerOptions::default(),
             prefix: None,
+            quarantine: None,
         }
     }
 
-    /// Every period, scan the store for stale values.
+    /// Every period, sweep the store for stale values. The default is every
+    /// 10 minutes.
     ///
     /// This only works for backends that do not have built-in TTL support.
-    /// Currently this is all backends except Redis. For Redis, you'll need to
-    /// look at Redis configuration if you need to change how often stale data
-    /// is cleaned up.
+    /// For backends like Redis, you'll need to look at Redis configuration if
+    /// you need to change how often stale data is cleaned up.
     ///
     /// For other backends, this will do nothing.
     pub fn clean_every(mut self, period: Duration) -> Self {
@@ -55,6 +57,45 @@ impl CuttlestoreBuilder {
         self.clean_every(Duration::from_secs(secs))
     }
 
+    /// Put off each sweep by a random amount of time up to this much.
+    ///
+    /// If many processes share the same store, this keeps them from all
+    /// sweeping it at the same time. The default is a tenth of the period set
+    /// with [clean_every](Self::clean_every).
+    pub fn clean_jitter(mut self, jitter: Duration) -> Self {
+        self.cleaner.jitter = Some(jitter);
+        self
+    }
+
+    /// Remove at most this many stale values in a single sweep.
+    ///
+    /// Any stale values left over are removed by the next sweeps. This keeps
+    /// a sweep from putting too much load on the store at once, for example
+    /// after many values expire together.
+    pub fn clean_at_most(mut self, max_per_sweep: u64) -> Self {
+        self.cleaner.max_per_sweep = Some(max_per_sweep);
+        self
+    }
+
+    /// Have the cleaners of every process sharing this store take turns, so
+    /// only one of them sweeps it each period.
+    ///
+    /// The cleaners elect the sweeper through a lease named `lease_key`, which
+    /// all the processes must agree on. The backend keeps the lease apart
+    /// from the values, so it doesn't show up in the store and isn't removed
+    /// by `clear`. The cleaner holding the lease renews it before every sweep.
+    /// If that process stops, the lease expires after about two periods and
+    /// another cleaner takes over.
+    ///
+    /// Every backend that can be shared between processes takes the lease
+    /// atomically, so only one cleaner sweeps in each period. The backends
+    /// that only one process can open don't need to coordinate, and always
+    /// give this cleaner the lease.
+    pub fn coordinate_cleaning<C: AsRef<str>>(mut self, lease_key: C) -> Self {
+        self.cleaner.lease = Some(lease_key.as_ref().to_owned());
+        self
+    }
+
     /// Prefix every key in the store with this string.
     ///
     /// The prefix is entirely transparent to your application. It is
@@ -69,11 +110,27 @@ impl CuttlestoreBuilder {
         self
     }
 
+    /// Move entries that can't be decoded under this prefix when they are
+    /// found by [scan_entries](crate::Cuttlestore::scan_entries), or when
+    /// `get` finds that the backend's copy is corrupt.
+    ///
+    /// By default, undecodable entries are reported and left where they are.
+    /// With this option, they are moved to `<prefix>:<key>` instead so they
+    /// no longer get in the way, but can still be inspected or recovered
+    /// later. Entries under this prefix are skipped by `scan`,
+    /// `scan_entries`, `count` and `clear`.
+    pub fn quarantine_undecodable<C: AsRef<str>>(mut self, prefix: C) -> Self {
+        self.quarantine = Some(prefix.as_ref().to_owned());
+        self
+    }
+
     /// Finish configuring your Cuttlestore, finalizing it so you can use it.
     pub async fn finish<Value: Serialize + DeserializeOwned + Send + Sync>(
         self,
     ) -> Result<Cuttlestore<Value>, CuttlestoreError> {
-        Cuttlestore::make(&self.conn, self.cleaner).await
+        let mut store = Cuttlestore::make(&self.conn, self.cleaner).await?;
+        store.quarantine = self.quarantine;
+        Ok(store)
     }
 
     /// Finish configuring your Cuttlestore, opening it as a CuttleConnection so
@@ -93,15 +150,15 @@ impl CuttlestoreBuilder {
 pub struct CuttleConnection {
     /// The actual store backend.
     store: Arc<Box<dyn CuttleBackend + Send + Sync>>,
-    #[allow(dead_code)]
-    /// For backends that require it, a cleaner is created which will scan the
-    /// store periodically to drop expired entries.
+    /// For backends that require it, a cleaner is created which will sweep
+    /// the store periodically to drop expired entries.
     ///
-    /// While the cleaner property is not used directly, we need to keep the
-    /// cleaner around because it will stop when dropped.
+    /// We need to keep the cleaner around because it will stop when dropped.
     cleaner: Option<Arc<Cleaner>>,
     /// Prefix for all stores made out of this connection.
     prefix: Option<String>,
+    /// Dead-letter prefix for all stores made out of this connection.
+    quarantine: Option<String>,
 }
 
 impl CuttleConnection {
@@ -116,6 +173,7 @@ impl CuttleConnection {
             store,
             cleaner,
             prefix: builder.prefix,
+            quarantine: builder.quarantine,
         })
     }
 }
@@ -129,6 +187,14 @@ impl std::fmt::Debug for CuttleConnection {
 }
 
 impl CuttleConnection {
+    /// The cleaner that removes expired values from the store, which is shared
+    /// by all the Cuttlestores made with this connection.
+    ///
+    /// This is `None` if the backend removes expired values by itself.
+    pub fn cleaner(&self) -> Option<CleanerHandle> {
+        self.cleaner.as_ref().map(|cleaner| cleaner.handle())
+    }
+
     /// Create a new Cuttlestore using this connection. All Cuttlestores created
     /// on the same connection share the same underlying database connections.
     ///
@@ -159,6 +225,7 @@ impl CuttleConnection {
                 self.prefix.clone().unwrap_or_default(),
                 prefix.as_ref()
             )),
+            quarantine: self.quarantine.clone(),
         })
     }
 }
@@ -170,6 +237,10 @@ pub(crate) async fn find_matching_backend(
     if let Some(backend) = crate::backends::filesystem::FilesystemBackend::new(conn).await {
         return Ok(backend?);
     }
+    #[cfg(feature = "backend-log")]
+    if let Some(backend) = crate::backends::append_log::AppendLogBackend::new(conn).await {
+        return Ok(backend?);
+    }
     #[cfg(feature = "backend-in-memory")]
     if let Some(backend) = crate::backends::in_memory::InMemoryBackend::new(conn).await {
         return Ok(backend?);
@@ -178,10 +249,26 @@ pub(crate) async fn find_matching_backend(
     if let Some(backend) = crate::backends::redis::RedisBackend::new(conn).await {
         return Ok(backend?);
     }
+    #[cfg(feature = "backend-redb")]
+    if let Some(backend) = crate::backends::redb::RedbBackend::new(conn).await {
+        return Ok(backend?);
+    }
     #[cfg(feature = "backend-sqlite-core")]
     if let Some(backend) = crate::backends::sqlite::SqliteBackend::new(conn).await {
         return Ok(backend?);
     }
+    #[cfg(feature = "backend-postgres-core")]
+    if let Some(backend) = crate::backends::postgres::PostgresBackend::new(conn).await {
+        return Ok(backend?);
+    }
+    #[cfg(feature = "backend-mysql-core")]
+    if let Some(backend) = crate::backends::mysql::MysqlBackend::new(conn).await {
+        return Ok(backend?);
+    }
+    #[cfg(feature = "backend-memcached")]
+    if let Some(backend) = crate::backends::memcached::MemcachedBackend::new(conn).await {
+        return Ok(backend?);
+    }
     #[cfg(feature = "backend-dynamodb")]
     if let Some(backend) = crate::backends::dynamodb::DynamoDBBackend::new(conn).await {
         return Ok(backend?);
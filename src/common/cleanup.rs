@@ -1,13 +1,31 @@
-use std::{sync::Arc, time::Duration};
+use std::{
+    borrow::Cow,
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    sync::{Arc, Mutex, Weak},
+    time::{Duration, Instant, SystemTime},
+};
 
-use futures::StreamExt;
-use tokio::task::JoinHandle;
+use tokio::{sync::Notify, task::JoinHandle};
 
-use crate::backend_api::CuttleBackend;
+use crate::{backend_api::CuttleBackend, common::CuttlestoreError};
+
+/// A failed sweep is retried after this long at first, doubling with every
+/// failure in a row until it reaches `sweep_every`.
+const FIRST_RETRY: Duration = Duration::from_secs(5);
 
 #[derive(Debug)]
 pub(crate) struct CleanerOptions {
     pub(crate) sweep_every: Duration,
+    /// Each sweep is put off by a random amount up to this, so processes that
+    /// were started together don't all sweep at the same time. Defaults to a
+    /// tenth of `sweep_every`.
+    pub(crate) jitter: Option<Duration>,
+    /// The most expired entries to remove in a single sweep.
+    pub(crate) max_per_sweep: Option<u64>,
+    /// The key of the lease that the cleaners sharing this store take turns
+    /// holding. Only the cleaner holding the lease sweeps the store.
+    pub(crate) lease: Option<String>,
 }
 
 impl Default for CleanerOptions {
@@ -15,12 +33,120 @@ impl Default for CleanerOptions {
         Self {
             // 10 minutes
             sweep_every: Duration::from_secs(60 * 10),
+            jitter: None,
+            max_per_sweep: None,
+            lease: None,
         }
     }
 }
 
+impl CleanerOptions {
+    /// How long to wait before the next sweep.
+    fn delay(&self, failures: u32) -> Duration {
+        if failures == 0 {
+            self.sweep_every + random_up_to(self.max_jitter())
+        } else {
+            FIRST_RETRY
+                .saturating_mul(2u32.saturating_pow(failures - 1))
+                .min(self.sweep_every)
+        }
+    }
+
+    fn max_jitter(&self) -> Duration {
+        self.jitter.unwrap_or(self.sweep_every / 10)
+    }
+
+    /// How many seconds the lease lasts. The holder renews it before every
+    /// sweep, so this gives it a sweep to spare before another cleaner can
+    /// take over.
+    fn lease_ttl(&self) -> u64 {
+        let longest_delay = self.sweep_every + self.max_jitter();
+        (longest_delay * 2).as_secs_f64().ceil().max(1.0) as u64
+    }
+}
+
+/// A random duration between zero and `max`. This only spreads the sweeps
+/// out, so the random seed of `RandomState` is good enough.
+fn random_up_to(max: Duration) -> Duration {
+    if max.is_zero() {
+        return max;
+    }
+    let random = RandomState::new().build_hasher().finish();
+    max.mul_f64(random as f64 / u64::MAX as f64)
+}
+
+/// A name for this cleaner that the other cleaners sharing the lease won't
+/// have.
+fn lease_holder() -> String {
+    let random = RandomState::new().build_hasher().finish();
+    format!("{}-{random:016x}", std::process::id())
+}
+
+/// Statistics about the sweeps the cleaner has run, see
+/// [CleanerHandle::stats].
+#[derive(Debug, Clone, Default)]
+pub struct CleanerStats {
+    /// How many sweeps have finished, including the ones that failed.
+    pub sweeps: u64,
+    /// How many sweeps were skipped because another cleaner held the lease,
+    /// see [CuttlestoreBuilder::coordinate_cleaning](crate::CuttlestoreBuilder::coordinate_cleaning).
+    pub skipped: u64,
+    /// Whether this cleaner held the lease at the last sweep. This is always
+    /// false if the cleaners are not coordinated.
+    pub holds_lease: bool,
+    /// How many expired entries have been removed by all the sweeps.
+    pub total_removed: u64,
+    /// When the last sweep finished.
+    pub last_sweep: Option<SystemTime>,
+    /// How long the last sweep took.
+    pub last_duration: Option<Duration>,
+    /// How many expired entries the last sweep removed.
+    pub last_removed: u64,
+    /// The error the last sweep failed with, if it failed.
+    pub last_error: Option<String>,
+    /// How many sweeps in a row have failed.
+    pub consecutive_failures: u32,
+}
+
+#[derive(Debug, Default)]
+struct Shared {
+    stats: Mutex<CleanerStats>,
+    wake: Notify,
+}
+
+/// A handle to the cleaner that removes expired entries from the store.
+///
+/// You can get one with [Cuttlestore::cleaner](crate::Cuttlestore::cleaner),
+/// for backends that need a cleaner.
+#[derive(Debug, Clone)]
+pub struct CleanerHandle {
+    shared: Arc<Shared>,
+}
+
+impl CleanerHandle {
+    /// Start a sweep right away, rather than waiting for the next one.
+    ///
+    /// This returns immediately, without waiting for the sweep to finish. If
+    /// a sweep is already running, another one starts once it is done. If the
+    /// cleaners are coordinated, the sweep is still skipped when another
+    /// cleaner holds the lease.
+    pub fn sweep_now(&self) {
+        self.shared.wake.notify_one();
+    }
+
+    /// Statistics about the sweeps so far.
+    pub fn stats(&self) -> CleanerStats {
+        self.shared
+            .stats
+            .lock()
+            .expect("The cleaner stats lock is poisoned")
+            .clone()
+    }
+}
+
 pub(crate) struct Cleaner {
     handle: JoinHandle<()>,
+    shared: Arc<Shared>,
 }
 
 impl Cleaner {
@@ -31,25 +157,106 @@ impl Cleaner {
         store: Arc<Box<dyn CuttleBackend + Send + Sync>>,
         options: CleanerOptions,
     ) -> Self {
-        let j = tokio::spawn(async move {
-            tokio::time::sleep(options.sweep_every).await;
-            // should just log this and retry later.
-            let scan = match store.scan().await {
-                Ok(scan) => scan,
-                Err(err) => {
-                    #[cfg(feature = "logging-log")]
-                    log::error!("Unable to run the store cleaner: {err:?}");
-                    #[cfg(feature = "logging-tracing")]
-                    tracing::error!("Unable to run the store cleaner: {err:?}");
-                    return;
-                }
-            };
-            // We don't need to actually do anything to check the TTLs.
-            // The scan will automatically discard expired values.
-            scan.for_each(|_| futures::future::ready(())).await;
-        });
-        Cleaner { handle: j }
+        // The cleaner should not keep the store alive by itself, otherwise the
+        // store would only be closed once the cleaner task gets around to
+        // stopping.
+        let store = Arc::downgrade(&store);
+        let shared = Arc::new(Shared::default());
+        let j = tokio::spawn(sweep_periodically(store, options, shared.clone()));
+        Cleaner { handle: j, shared }
+    }
+
+    pub(crate) fn handle(&self) -> CleanerHandle {
+        CleanerHandle {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+async fn sweep_periodically(
+    store: Weak<Box<dyn CuttleBackend + Send + Sync>>,
+    options: CleanerOptions,
+    shared: Arc<Shared>,
+) {
+    let holder = lease_holder();
+    let mut failures = 0;
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep(options.delay(failures)) => {}
+            _ = shared.wake.notified() => {}
+        }
+        let store = match Weak::upgrade(&store) {
+            Some(store) => store,
+            None => return,
+        };
+
+        let started = Instant::now();
+        let result = sweep(store.as_ref().as_ref(), &options, &holder).await;
+        let mut stats = shared
+            .stats
+            .lock()
+            .expect("The cleaner stats lock is poisoned");
+        match result {
+            Ok(None) => {
+                stats.skipped += 1;
+                stats.holds_lease = false;
+                stats.consecutive_failures = 0;
+            }
+            Ok(Some(removed)) => {
+                #[cfg(feature = "logging-log")]
+                log::debug!(
+                    "The store cleaner removed {removed} expired entries from {}",
+                    store.name()
+                );
+                #[cfg(feature = "logging-tracing")]
+                tracing::debug!(
+                    "The store cleaner removed {removed} expired entries from {}",
+                    store.name()
+                );
+                stats.sweeps += 1;
+                stats.last_sweep = Some(SystemTime::now());
+                stats.last_duration = Some(started.elapsed());
+                stats.holds_lease = options.lease.is_some();
+                stats.total_removed += removed;
+                stats.last_removed = removed;
+                stats.last_error = None;
+                stats.consecutive_failures = 0;
+            }
+            Err(err) => {
+                // Try again later, backing off while the errors continue.
+                #[cfg(feature = "logging-log")]
+                log::error!("Unable to run the store cleaner: {err:?}");
+                #[cfg(feature = "logging-tracing")]
+                tracing::error!("Unable to run the store cleaner: {err:?}");
+                stats.sweeps += 1;
+                stats.last_sweep = Some(SystemTime::now());
+                stats.last_duration = Some(started.elapsed());
+                stats.last_removed = 0;
+                stats.last_error = Some(err.to_string());
+                stats.consecutive_failures += 1;
+            }
+        }
+        failures = stats.consecutive_failures;
+    }
+}
+
+/// Sweep the store once, returning how many entries were removed. If the
+/// cleaners are coordinated and another cleaner holds the lease, nothing is
+/// swept and this returns `None`.
+async fn sweep(
+    store: &(dyn CuttleBackend + Send + Sync),
+    options: &CleanerOptions,
+    holder: &str,
+) -> Result<Option<u64>, CuttlestoreError> {
+    if let Some(lease) = &options.lease {
+        if !store
+            .take_lease(Cow::Borrowed(lease), holder, options.lease_ttl())
+            .await?
+        {
+            return Ok(None);
+        }
     }
+    store.purge_expired(options.max_per_sweep).await.map(Some)
 }
 
 impl Drop for Cleaner {
@@ -57,3 +264,51 @@ impl Drop for Cleaner {
         self.handle.abort();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn jitter_is_added_to_the_period() {
+        let options = CleanerOptions {
+            sweep_every: Duration::from_secs(100),
+            jitter: Some(Duration::from_secs(20)),
+            ..Default::default()
+        };
+        for _ in 0..100 {
+            let delay = options.delay(0);
+            assert!(delay >= Duration::from_secs(100));
+            assert!(delay <= Duration::from_secs(120));
+        }
+    }
+
+    #[test]
+    fn failures_back_off_up_to_the_period() {
+        let options = CleanerOptions {
+            sweep_every: Duration::from_secs(60),
+            ..Default::default()
+        };
+        assert_eq!(options.delay(1), Duration::from_secs(5));
+        assert_eq!(options.delay(2), Duration::from_secs(10));
+        assert_eq!(options.delay(4), Duration::from_secs(40));
+        assert_eq!(options.delay(5), Duration::from_secs(60));
+        assert_eq!(options.delay(100), Duration::from_secs(60));
+    }
+
+    #[test]
+    fn lease_outlasts_a_late_sweep() {
+        let options = CleanerOptions {
+            sweep_every: Duration::from_secs(60),
+            ..Default::default()
+        };
+        assert_eq!(options.lease_ttl(), 132);
+
+        let options = CleanerOptions {
+            sweep_every: Duration::from_millis(100),
+            jitter: Some(Duration::ZERO),
+            ..Default::default()
+        };
+        assert_eq!(options.lease_ttl(), 1);
+    }
+}
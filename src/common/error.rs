@@ -4,6 +4,15 @@ pub enum CuttlestoreError {
     #[error("No store matching {0} is supported.")]
     NoMatchingBackend(String),
 
+    /// The connection string matched a backend, but the options in it are
+    /// invalid.
+    #[error("Invalid connection string: {0}")]
+    InvalidConnectionString(String),
+
+    /// The backend doesn't support this operation.
+    #[error("The {0} backend does not support {1}")]
+    Unsupported(&'static str, &'static str),
+
     /// An error occurred when encoding an object.
     ///
     /// Data is encoded internally to store objects. An encoding error
@@ -20,19 +29,39 @@ pub enum CuttlestoreError {
 
     /// An error happened when accessing the file system.
     ///
-    /// The filesystem backend needs to be able to read and write files inside
-    /// the configured path. It also attempts to create this folder and any
-    /// parent folder if missing. Make sure the program has write access to the
-    /// folder and disk space is available.
-    #[cfg(feature = "backend-filesystem")]
+    /// The filesystem and log backends need to be able to read and write files
+    /// inside the configured path. They also attempt to create the folder and
+    /// any parent folder if missing. Make sure the program has write access to
+    /// the folder and disk space is available.
+    #[cfg(any(feature = "backend-filesystem", feature = "backend-log"))]
     #[error("Failed to access the file system: {0}")]
     FileError(#[from] std::io::Error),
 
-    /// An error happened when opening or accessing the sqlite database.
-    #[cfg(feature = "backend-sqlite-core")]
-    #[error("Failed to access the sqlite database: {0}")]
+    /// The store is already in use by another process.
+    ///
+    /// For the filesystem backend, this happens if the store was opened in
+    /// exclusive mode while another process has it open, or if the store needs
+    /// to be migrated to a new layout while another process is using it. A log
+    /// can only be opened by one process at a time.
+    #[cfg(any(feature = "backend-filesystem", feature = "backend-log"))]
+    #[error("The store is in use by another process, locked by {0}")]
+    StoreLocked(String),
+
+    /// An error happened when opening or accessing the sqlite, Postgres or
+    /// MySQL database.
+    #[cfg(any(
+        feature = "backend-sqlite-core",
+        feature = "backend-postgres-core",
+        feature = "backend-mysql-core"
+    ))]
+    #[error("Failed to access the database: {0}")]
     DatabaseError(#[from] sqlx::Error),
 
+    /// An error happened when opening or accessing the redb database.
+    #[cfg(feature = "backend-redb")]
+    #[error("Failed to access the redb database: {0}")]
+    RedbError(#[from] redb::Error),
+
     #[cfg(feature = "backend-redis")]
     #[error("Failed to access redis: {0}")]
     RedisError(#[from] redis::RedisError),
@@ -41,6 +70,11 @@ pub enum CuttlestoreError {
     #[error("Failed to connecting to or accessing redis: {0}")]
     RedisConnectionError(#[from] bb8::RunError<redis::RedisError>),
 
+    /// An error happened when connecting to or accessing memcached.
+    #[cfg(feature = "backend-memcached")]
+    #[error("Failed to access memcached: {0}")]
+    MemcachedError(String),
+
     /// An error happened when accessing DynamoDB.
     #[cfg(feature = "backend-dynamodb")]
     #[error("Failed to access DynamoDB: {0}")]
@@ -0,0 +1,27 @@
+/// The 64 bit FNV-1a hash.
+///
+/// The standard library hasher is not guaranteed to stay the same between Rust
+/// versions. This one never changes, so it can be used for anything that gets
+/// stored or has to match between processes: hashed file names, picking a
+/// memcached server for a key, or the checksums in the log backend.
+pub(crate) fn fnv1a(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in bytes {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_the_reference_values() {
+        // Changing the hash would make existing files and logs unreadable
+        assert_eq!(fnv1a(b""), 0xcbf29ce484222325);
+        assert_eq!(fnv1a(b"a"), 0xaf63dc4c8601ec8c);
+        assert_eq!(fnv1a(b"foobar"), 0x85944171f73967e8);
+    }
+}
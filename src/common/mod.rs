@@ -1,6 +1,18 @@
 pub(crate) mod cleanup;
 mod error;
+#[cfg(any(
+    feature = "backend-filesystem",
+    feature = "backend-log",
+    feature = "backend-memcached"
+))]
+mod hash;
 pub(crate) mod time;
 
 pub use error::CuttlestoreError;
+#[cfg(any(
+    feature = "backend-filesystem",
+    feature = "backend-log",
+    feature = "backend-memcached"
+))]
+pub(crate) use hash::fnv1a;
 pub(crate) use time::get_system_time;
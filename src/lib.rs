@@ -38,7 +38,8 @@ mod backends;
 mod builder;
 mod common;
 
-pub use api::Cuttlestore;
+pub use api::{Change, Cuttlestore, ScanEntry};
 pub use backend_api::PutOptions;
 pub use builder::CuttleConnection;
 pub use builder::CuttlestoreBuilder;
+pub use common::cleanup::{CleanerHandle, CleanerStats};
@@ -1,7 +1,10 @@
 mod tests;
 use tests::suite;
 
-use cuttlestore::{Cuttlestore, CuttlestoreBuilder};
+use std::time::Duration;
+
+use cuttlestore::{CleanerHandle, CleanerStats, Cuttlestore, CuttlestoreBuilder, PutOptions};
+use futures::StreamExt;
 use tokio::test;
 
 #[test]
@@ -14,3 +17,144 @@ async fn test_in_memory_with_cleaner() {
 
     suite(&store).await;
 }
+
+#[test]
+async fn test_sqlite_with_cleaner() {
+    let store: Cuttlestore<String> = CuttlestoreBuilder::new("sqlite://:memory:")
+        .clean_every_secs(1)
+        .finish()
+        .await
+        .unwrap();
+
+    suite(&store).await;
+}
+
+/// Wait until the cleaner has finished this many sweeps.
+async fn wait_for_sweeps(cleaner: &CleanerHandle, sweeps: u64) -> CleanerStats {
+    for _ in 0..100 {
+        let stats = cleaner.stats();
+        if stats.sweeps >= sweeps {
+            return stats;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    panic!(
+        "The cleaner didn't sweep {sweeps} times: {:?}",
+        cleaner.stats()
+    );
+}
+
+#[test]
+async fn test_cleaner_sweeps_repeatedly() {
+    let store: Cuttlestore<String> = CuttlestoreBuilder::new("in-memory")
+        .clean_every(Duration::from_millis(100))
+        .clean_jitter(Duration::ZERO)
+        .finish()
+        .await
+        .unwrap();
+
+    let stats = wait_for_sweeps(&store.cleaner().unwrap(), 3).await;
+    assert!(stats.last_sweep.is_some());
+    assert!(stats.last_duration.is_some());
+    assert!(stats.last_error.is_none());
+}
+
+#[test]
+async fn test_cleaner_sweep_now() {
+    let store: Cuttlestore<String> = CuttlestoreBuilder::new("in-memory")
+        .clean_every_secs(60 * 60)
+        .clean_at_most(2)
+        .finish()
+        .await
+        .unwrap();
+    for i in 0..3 {
+        store
+            .put_with(
+                format!("short{i}"),
+                &"gone".to_string(),
+                PutOptions::ttl_secs(0),
+            )
+            .await
+            .unwrap();
+    }
+    store.put("long", &"stays".to_string()).await.unwrap();
+    // Values are only expired once the second they expire at has passed
+    tokio::time::sleep(Duration::from_millis(1100)).await;
+
+    let cleaner = store.cleaner().unwrap();
+    cleaner.sweep_now();
+    let stats = wait_for_sweeps(&cleaner, 1).await;
+    assert_eq!(stats.last_removed, 2);
+
+    cleaner.sweep_now();
+    let stats = wait_for_sweeps(&cleaner, 2).await;
+    assert_eq!(stats.last_removed, 1);
+    assert_eq!(stats.total_removed, 3);
+    assert_eq!(store.get("long").await.unwrap().unwrap(), "stays");
+}
+
+/// A connection to the shared store, with a cleaner that takes turns with the
+/// cleaners of the other connections.
+async fn coordinated_connection(path: &str) -> cuttlestore::CuttleConnection {
+    CuttlestoreBuilder::new(format!("sqlite://{path}"))
+        .clean_every(Duration::from_millis(200))
+        .clean_jitter(Duration::ZERO)
+        .coordinate_cleaning("cuttlestore-cleaner")
+        .finish_connection()
+        .await
+        .unwrap()
+}
+
+#[test]
+async fn test_coordinated_cleaners() {
+    let path = "./example-store/sqlite-lease-test";
+    for suffix in ["", "-shm", "-wal"] {
+        tokio::fs::remove_file(format!("{path}{suffix}")).await.ok();
+    }
+
+    let first = coordinated_connection(path).await;
+    let second = coordinated_connection(path).await;
+    let first_cleaner = first.cleaner().unwrap();
+    let second_cleaner = second.cleaner().unwrap();
+
+    // Both cleaners wake up every period, but only one of them sweeps
+    for _ in 0..100 {
+        let skipped = first_cleaner.stats().skipped + second_cleaner.stats().skipped;
+        if skipped >= 3 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    let (leader, follower, follower_cleaner) = if first_cleaner.stats().holds_lease {
+        (first, second_cleaner.stats(), second_cleaner)
+    } else {
+        (second, first_cleaner.stats(), first_cleaner)
+    };
+    assert_eq!(follower.sweeps, 0);
+    assert!(follower.skipped >= 3);
+    assert!(!follower.holds_lease);
+
+    // Once the leader is gone, the follower takes over when the lease expires
+    drop(leader);
+    for _ in 0..100 {
+        if follower_cleaner.stats().sweeps > 0 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    let follower = follower_cleaner.stats();
+    assert!(
+        follower.sweeps > 0,
+        "The follower never took over: {follower:?}"
+    );
+    assert!(follower.holds_lease);
+
+    // The lease is kept apart from the values in the store
+    let store: Cuttlestore<String> = Cuttlestore::new(format!("sqlite://{path}")).await.unwrap();
+    assert_eq!(store.count().await.unwrap(), 0);
+    assert_eq!(store.scan().await.unwrap().count().await, 0);
+
+    for suffix in ["", "-shm", "-wal"] {
+        tokio::fs::remove_file(format!("{path}{suffix}")).await.ok();
+    }
+}
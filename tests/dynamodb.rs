@@ -2,6 +2,8 @@ mod tests;
 use tests::suite;
 
 use cuttlestore::Cuttlestore;
+use futures::StreamExt;
+use nanoid::nanoid;
 use tokio::test;
 
 #[test]
@@ -13,3 +15,48 @@ async fn test_dynamodb() {
 
     suite(&store).await;
 }
+
+#[test]
+async fn test_dynamodb_shared_table() {
+    let table = format!("cuttlestore-shared-{}", nanoid!(8));
+    let connect = |partition: &str| {
+        format!(
+            "dynamodb://us-east-1/{table}?endpoint=http://127.0.0.1:8000&partition_attribute=pk&key_attribute=sk&value_attribute=v&ttl_attribute=expires&partition={partition}&read_capacity=1&write_capacity=1"
+        )
+    };
+    let first: Cuttlestore<String> = Cuttlestore::new(connect("first")).await.unwrap();
+    let second: Cuttlestore<String> = Cuttlestore::new(connect("second")).await.unwrap();
+
+    suite(&first).await;
+
+    first.put("shared", &"first".to_string()).await.unwrap();
+    second.put("shared", &"second".to_string()).await.unwrap();
+    assert_eq!(first.get("shared").await.unwrap().unwrap(), "first");
+    assert_eq!(second.get("shared").await.unwrap().unwrap(), "second");
+
+    let keys = second
+        .scan()
+        .await
+        .unwrap()
+        .map(|pair| pair.unwrap().0)
+        .collect::<Vec<_>>()
+        .await;
+    assert_eq!(keys, vec!["shared".to_string()]);
+
+    // The table is keyed by the partition, so it can't be used without one.
+    let unpartitioned = Cuttlestore::<String>::new(format!(
+        "dynamodb://us-east-1/{table}?endpoint=http://127.0.0.1:8000"
+    ))
+    .await;
+    assert!(unpartitioned.is_err());
+}
+
+#[test]
+async fn test_dynamodb_without_creating_the_table() {
+    let store = Cuttlestore::<String>::new(format!(
+        "dynamodb://us-east-1/cuttlestore-missing-{}?endpoint=http://127.0.0.1:8000&create_table=false",
+        nanoid!(8)
+    ))
+    .await;
+    assert!(store.is_err());
+}
@@ -1,7 +1,8 @@
 mod tests;
-use tests::suite;
+use tests::{coordinated_cleaners, suite};
 
-use cuttlestore::Cuttlestore;
+use cuttlestore::{Cuttlestore, CuttlestoreBuilder, PutOptions, ScanEntry};
+use futures::StreamExt;
 use tokio::{fs, test};
 
 #[test]
@@ -21,3 +22,342 @@ async fn test_filesystem() {
         .await
         .ok();
 }
+
+#[test]
+async fn test_filesystem_quarantine_corrupt() {
+    fs::remove_dir_all("./example-store/filesystem-quarantine-test")
+        .await
+        .ok();
+
+    let store: Cuttlestore<String> =
+        CuttlestoreBuilder::new("filesystem://./example-store/filesystem-quarantine-test")
+            .quarantine_undecodable("dead")
+            .finish()
+            .await
+            .unwrap();
+
+    // Simulate a value that was only partially written out
+    fs::write("./example-store/filesystem-quarantine-test/broken", [1u8])
+        .await
+        .unwrap();
+
+    let entries = store.scan_entries().await.unwrap();
+    let entries = entries.map(|x| x.unwrap()).collect::<Vec<_>>().await;
+    assert_eq!(entries.len(), 1);
+    assert!(matches!(
+        &entries[0],
+        (key, ScanEntry::Undecodable { raw, .. }) if key == "broken" && raw == &[1u8]
+    ));
+
+    // The corrupt file was moved rather than deleted
+    assert!(
+        fs::metadata("./example-store/filesystem-quarantine-test/dead%3Abroken")
+            .await
+            .is_ok()
+    );
+    assert!(
+        fs::metadata("./example-store/filesystem-quarantine-test/broken")
+            .await
+            .is_err()
+    );
+
+    // The quarantined entry doesn't get in the way of the other operations
+    store.put("good", &"value".to_string()).await.unwrap();
+    let pairs = store.scan().await.unwrap();
+    let pairs = pairs.map(|x| x.unwrap()).collect::<Vec<_>>().await;
+    assert_eq!(pairs, vec![("good".to_string(), "value".to_string())]);
+    assert_eq!(store.count().await.unwrap(), 1);
+    store.clear().await.unwrap();
+    assert_eq!(store.count().await.unwrap(), 0);
+    assert!(
+        fs::metadata("./example-store/filesystem-quarantine-test/dead%3Abroken")
+            .await
+            .is_ok()
+    );
+
+    fs::remove_dir_all("./example-store/filesystem-quarantine-test")
+        .await
+        .ok();
+}
+
+#[test]
+async fn test_filesystem_corrupt_get() {
+    fs::remove_dir_all("./example-store/filesystem-corrupt-get-test")
+        .await
+        .ok();
+
+    let store: Cuttlestore<String> =
+        Cuttlestore::new("filesystem://./example-store/filesystem-corrupt-get-test")
+            .await
+            .unwrap();
+    fs::write("./example-store/filesystem-corrupt-get-test/broken", [1u8])
+        .await
+        .unwrap();
+
+    // Without a quarantine, the corrupt file is skipped but left in place
+    assert!(store.get("broken").await.unwrap().is_none());
+    assert!(store.scan().await.unwrap().next().await.is_none());
+    assert!(
+        fs::metadata("./example-store/filesystem-corrupt-get-test/broken")
+            .await
+            .is_ok()
+    );
+
+    let store: Cuttlestore<String> =
+        CuttlestoreBuilder::new("filesystem://./example-store/filesystem-corrupt-get-test")
+            .quarantine_undecodable("dead")
+            .finish()
+            .await
+            .unwrap();
+    assert!(store.get("broken").await.unwrap().is_none());
+    assert!(
+        fs::metadata("./example-store/filesystem-corrupt-get-test/dead%3Abroken")
+            .await
+            .is_ok()
+    );
+    assert!(
+        fs::metadata("./example-store/filesystem-corrupt-get-test/broken")
+            .await
+            .is_err()
+    );
+
+    fs::remove_dir_all("./example-store/filesystem-corrupt-get-test")
+        .await
+        .ok();
+}
+
+#[test]
+async fn test_filesystem_unsafe_keys() {
+    fs::remove_dir_all("./example-store/filesystem-keys-test")
+        .await
+        .ok();
+
+    let store: Cuttlestore<String> =
+        Cuttlestore::new("filesystem://./example-store/filesystem-keys-test")
+            .await
+            .unwrap();
+
+    let long_key = "long".repeat(100);
+    let keys = ["../escape", "a/b", "nul\0byte", "CON", "", &long_key];
+    for key in keys {
+        store.put(key, &key.to_string()).await.unwrap();
+    }
+    for key in keys {
+        assert_eq!(store.get(key).await.unwrap().as_deref(), Some(key));
+    }
+
+    let pairs = store.scan().await.unwrap();
+    let mut pairs = pairs.map(|x| x.unwrap().0).collect::<Vec<_>>().await;
+    pairs.sort();
+    let mut expected = keys.map(|key| key.to_string()).to_vec();
+    expected.sort();
+    assert_eq!(pairs, expected);
+
+    // Nothing escaped the store folder
+    assert!(fs::metadata("./example-store/escape").await.is_err());
+
+    fs::remove_dir_all("./example-store/filesystem-keys-test")
+        .await
+        .ok();
+}
+
+#[test]
+async fn test_filesystem_migrate_legacy_names() {
+    let folder = "./example-store/filesystem-migrate-test";
+    fs::remove_dir_all(folder).await.ok();
+
+    {
+        let store: Cuttlestore<String> = Cuttlestore::new(format!("filesystem://{folder}"))
+            .await
+            .unwrap();
+        store.put("foo:bar", &"baz".to_string()).await.unwrap();
+        store.put("plain", &"value".to_string()).await.unwrap();
+    }
+
+    // Turn it back into a store from before keys were encoded
+    fs::rename(format!("{folder}/foo%3Abar"), format!("{folder}/foo:bar"))
+        .await
+        .unwrap();
+    fs::remove_file(format!("{folder}/.cuttlestore"))
+        .await
+        .unwrap();
+
+    let store: Cuttlestore<String> = Cuttlestore::new(format!("filesystem://{folder}"))
+        .await
+        .unwrap();
+    assert_eq!(store.get("foo:bar").await.unwrap().unwrap(), "baz");
+    assert_eq!(store.get("plain").await.unwrap().unwrap(), "value");
+    assert!(fs::metadata(format!("{folder}/foo%3Abar")).await.is_ok());
+    assert!(fs::metadata(format!("{folder}/foo:bar")).await.is_err());
+
+    fs::remove_dir_all(folder).await.ok();
+}
+
+#[test]
+async fn test_filesystem_no_partial_writes() {
+    let folder = "./example-store/filesystem-atomic-test";
+    fs::remove_dir_all(folder).await.ok();
+
+    let store: Cuttlestore<String> = Cuttlestore::new(format!("filesystem://{folder}"))
+        .await
+        .unwrap();
+
+    // A temporary file left behind by a crash midway through a write
+    fs::write(format!("{folder}/.tmp-0-0-foo"), [1u8])
+        .await
+        .unwrap();
+
+    for i in 0..10 {
+        store.put("foo", &format!("value {i}")).await.unwrap();
+    }
+    assert_eq!(store.get("foo").await.unwrap().unwrap(), "value 9");
+
+    // Only the crashed write's temporary file is left, and it is ignored
+    let mut entries = fs::read_dir(folder).await.unwrap();
+    let mut temp_files = 0;
+    while let Some(entry) = entries.next_entry().await.unwrap() {
+        if entry.file_name().to_string_lossy().starts_with(".tmp-") {
+            temp_files += 1;
+        }
+    }
+    assert_eq!(temp_files, 1);
+    let pairs = store.scan().await.unwrap();
+    let pairs = pairs.map(|x| x.unwrap()).collect::<Vec<_>>().await;
+    assert_eq!(pairs, vec![("foo".to_string(), "value 9".to_string())]);
+
+    fs::remove_dir_all(folder).await.ok();
+}
+
+#[test]
+async fn test_filesystem_sharded() {
+    fs::remove_dir_all("./example-store/filesystem-sharded-test")
+        .await
+        .ok();
+
+    let store: Cuttlestore<String> =
+        Cuttlestore::new("filesystem://./example-store/filesystem-sharded-test?layout=sharded")
+            .await
+            .unwrap();
+
+    suite(&store).await;
+
+    fs::remove_dir_all("./example-store/filesystem-sharded-test")
+        .await
+        .ok();
+}
+
+#[test]
+async fn test_filesystem_convert_layout() {
+    let folder = "./example-store/filesystem-convert-test";
+    fs::remove_dir_all(folder).await.ok();
+
+    {
+        let store: Cuttlestore<String> = Cuttlestore::new(format!("filesystem://{folder}"))
+            .await
+            .unwrap();
+        store.put("foo", &"bar".to_string()).await.unwrap();
+    }
+    assert!(fs::metadata(format!("{folder}/foo")).await.is_ok());
+
+    {
+        let store: Cuttlestore<String> =
+            Cuttlestore::new(format!("filesystem://{folder}?layout=sharded"))
+                .await
+                .unwrap();
+        assert_eq!(store.get("foo").await.unwrap().unwrap(), "bar");
+        let pairs = store.scan().await.unwrap();
+        let pairs = pairs.map(|x| x.unwrap()).collect::<Vec<_>>().await;
+        assert_eq!(pairs, vec![("foo".to_string(), "bar".to_string())]);
+    }
+    assert!(fs::metadata(format!("{folder}/foo")).await.is_err());
+
+    // And back again
+    let store: Cuttlestore<String> = Cuttlestore::new(format!("filesystem://{folder}"))
+        .await
+        .unwrap();
+    assert_eq!(store.get("foo").await.unwrap().unwrap(), "bar");
+    assert!(fs::metadata(format!("{folder}/foo")).await.is_ok());
+
+    fs::remove_dir_all(folder).await.ok();
+}
+
+#[test]
+async fn test_filesystem_unknown_layout() {
+    let result: Result<Cuttlestore<String>, _> =
+        Cuttlestore::new("filesystem://./example-store/filesystem-bad-layout?layout=nope").await;
+    assert!(result.is_err());
+}
+
+#[test]
+async fn test_filesystem_exclusive_lock() {
+    let folder = "./example-store/filesystem-lock-test";
+    fs::remove_dir_all(folder).await.ok();
+
+    let store: Cuttlestore<String> =
+        Cuttlestore::new(format!("filesystem://{folder}?lock=exclusive"))
+            .await
+            .unwrap();
+    store.put("foo", &"bar".to_string()).await.unwrap();
+
+    // Nobody else can open the store while it is held exclusively
+    let result: Result<Cuttlestore<String>, _> =
+        Cuttlestore::new(format!("filesystem://{folder}")).await;
+    assert!(result.is_err());
+    drop(store);
+
+    let store: Cuttlestore<String> = Cuttlestore::new(format!("filesystem://{folder}"))
+        .await
+        .unwrap();
+    assert_eq!(store.get("foo").await.unwrap().unwrap(), "bar");
+
+    // And a store that's open can't be opened exclusively
+    let result: Result<Cuttlestore<String>, _> =
+        Cuttlestore::new(format!("filesystem://{folder}?lock=exclusive")).await;
+    assert!(result.is_err());
+
+    fs::remove_dir_all(folder).await.ok();
+}
+
+#[test]
+async fn test_filesystem_expiry_index() {
+    let folder = "./example-store/filesystem-expiry-test";
+    fs::remove_dir_all(folder).await.ok();
+
+    let store: Cuttlestore<String> = CuttlestoreBuilder::new(format!("filesystem://{folder}"))
+        .clean_every_secs(2)
+        .finish()
+        .await
+        .unwrap();
+    store
+        .put_with("short", &"lived".to_string(), PutOptions::ttl_secs(1))
+        .await
+        .unwrap();
+    store.put("kept", &"value".to_string()).await.unwrap();
+
+    // The first sweep builds the index with a full scan
+    tokio::time::sleep(std::time::Duration::from_secs(4)).await;
+    assert!(fs::metadata(format!("{folder}/short")).await.is_err());
+    assert!(fs::metadata(format!("{folder}/kept")).await.is_ok());
+
+    // Values written after that are added to the index
+    store
+        .put_with("later", &"value".to_string(), PutOptions::ttl_secs(60))
+        .await
+        .unwrap();
+    let index = fs::read_to_string(format!("{folder}/.expiry"))
+        .await
+        .unwrap();
+    assert!(index.lines().any(|line| line.ends_with(" later")));
+
+    fs::remove_dir_all(folder).await.ok();
+}
+
+#[test]
+async fn test_filesystem_coordinated_cleaners() {
+    let folder = "./example-store/filesystem-lease-test";
+    fs::remove_dir_all(folder).await.ok();
+
+    coordinated_cleaners(&format!("filesystem://{folder}")).await;
+
+    fs::remove_dir_all(folder).await.ok();
+}
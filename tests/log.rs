@@ -0,0 +1,161 @@
+mod tests;
+use tests::suite;
+
+use cuttlestore::Cuttlestore;
+use tokio::{fs, test};
+
+#[test]
+async fn test_log() {
+    fs::remove_dir_all("./example-store/log-test").await.ok();
+
+    let store: Cuttlestore<String> = Cuttlestore::new("log://./example-store/log-test/store.log")
+        .await
+        .unwrap();
+
+    suite(&store).await;
+
+    fs::remove_dir_all("./example-store/log-test").await.ok();
+}
+
+#[test]
+async fn test_log_reopen() {
+    let folder = "./example-store/log-reopen-test";
+    fs::remove_dir_all(folder).await.ok();
+    let conn = format!("log://{folder}/store.log");
+
+    {
+        let store: Cuttlestore<String> = Cuttlestore::new(&conn).await.unwrap();
+        store.put("foo", &"bar".to_string()).await.unwrap();
+        store.put("baz", &"qux".to_string()).await.unwrap();
+        store.put("foo", &"updated".to_string()).await.unwrap();
+        store.delete("baz").await.unwrap();
+    }
+
+    let store: Cuttlestore<String> = Cuttlestore::new(&conn).await.unwrap();
+    assert_eq!(store.get("foo").await.unwrap().unwrap(), "updated");
+    assert_eq!(store.get("baz").await.unwrap(), None);
+
+    fs::remove_dir_all(folder).await.ok();
+}
+
+#[test]
+async fn test_log_partial_write() {
+    let folder = "./example-store/log-partial-test";
+    fs::remove_dir_all(folder).await.ok();
+    let conn = format!("log://{folder}/store.log");
+
+    {
+        let store: Cuttlestore<String> = Cuttlestore::new(&conn).await.unwrap();
+        store.put("foo", &"bar".to_string()).await.unwrap();
+        store.put("baz", &"qux".to_string()).await.unwrap();
+    }
+
+    // Simulate a crash in the middle of writing the last record
+    let log = format!("{folder}/store.log");
+    let len = fs::metadata(&log).await.unwrap().len();
+    let file = std::fs::OpenOptions::new().write(true).open(&log).unwrap();
+    file.set_len(len - 2).unwrap();
+    drop(file);
+
+    let store: Cuttlestore<String> = Cuttlestore::new(&conn).await.unwrap();
+    assert_eq!(store.get("foo").await.unwrap().unwrap(), "bar");
+    assert_eq!(store.get("baz").await.unwrap(), None);
+    // The store keeps working after the broken record is dropped
+    store.put("baz", &"again".to_string()).await.unwrap();
+    drop(store);
+
+    let store: Cuttlestore<String> = Cuttlestore::new(&conn).await.unwrap();
+    assert_eq!(store.get("baz").await.unwrap().unwrap(), "again");
+
+    fs::remove_dir_all(folder).await.ok();
+}
+
+#[test]
+async fn test_log_compaction() {
+    let folder = "./example-store/log-compaction-test";
+    fs::remove_dir_all(folder).await.ok();
+    let conn = format!("log://{folder}/store.log");
+    let log = format!("{folder}/store.log");
+
+    let store: Cuttlestore<String> = Cuttlestore::new(&conn).await.unwrap();
+    let value = "x".repeat(10_000);
+    for i in 0..200 {
+        store.put("foo", &format!("{i}{value}")).await.unwrap();
+    }
+    store.put("bar", &"baz".to_string()).await.unwrap();
+
+    // Compaction happens in the background, give it some time. Values written
+    // while the compaction is running are kept, so the log won't be minimal.
+    let written = 200 * value.len() as u64;
+    let mut compacted = false;
+    for _ in 0..50 {
+        if fs::metadata(&log).await.unwrap().len() < written * 3 / 4 {
+            compacted = true;
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    }
+    assert!(compacted);
+    assert_eq!(
+        store.get("foo").await.unwrap().unwrap(),
+        format!("199{value}")
+    );
+    assert_eq!(store.get("bar").await.unwrap().unwrap(), "baz");
+    drop(store);
+
+    let store: Cuttlestore<String> = Cuttlestore::new(&conn).await.unwrap();
+    assert_eq!(
+        store.get("foo").await.unwrap().unwrap(),
+        format!("199{value}")
+    );
+    assert_eq!(store.get("bar").await.unwrap().unwrap(), "baz");
+
+    fs::remove_dir_all(folder).await.ok();
+}
+
+#[test]
+async fn test_log_reads_during_compaction() {
+    let folder = "./example-store/log-concurrent-read-test";
+    fs::remove_dir_all(folder).await.ok();
+    let conn = format!("log://{folder}/store.log");
+
+    let store: Cuttlestore<String> = Cuttlestore::new(&conn).await.unwrap();
+    let value = "x".repeat(10_000);
+    store.put("stable", &"kept".to_string()).await.unwrap();
+
+    // The writes keep triggering compactions, which swap the file out from
+    // under the reads
+    let writes = async {
+        for i in 0..300 {
+            store.put("churn", &format!("{i}{value}")).await.unwrap();
+        }
+    };
+    let reads = futures::future::join_all((0..8).map(|_| async {
+        for _ in 0..100 {
+            assert_eq!(store.get("stable").await.unwrap().unwrap(), "kept");
+            if let Some(churn) = store.get("churn").await.unwrap() {
+                assert!(churn.ends_with(&value));
+            }
+        }
+    }));
+    tokio::join!(writes, reads);
+
+    fs::remove_dir_all(folder).await.ok();
+}
+
+#[test]
+async fn test_log_locked() {
+    let folder = "./example-store/log-lock-test";
+    fs::remove_dir_all(folder).await.ok();
+    let conn = format!("log://{folder}/store.log");
+
+    let store: Cuttlestore<String> = Cuttlestore::new(&conn).await.unwrap();
+    let result: Result<Cuttlestore<String>, _> = Cuttlestore::new(&conn).await;
+    assert!(result.is_err());
+    drop(store);
+
+    let result: Result<Cuttlestore<String>, _> = Cuttlestore::new(&conn).await;
+    assert!(result.is_ok());
+
+    fs::remove_dir_all(folder).await.ok();
+}
@@ -0,0 +1,54 @@
+// Not every test in the suite applies to memcached
+#[allow(dead_code)]
+mod tests;
+use tests::{get_missing, get_then_delete, overwrite, timeout};
+
+use cuttlestore::{Cuttlestore, PutOptions};
+use tokio::test;
+
+const MEMCACHED: &str = "memcached://127.0.0.1:11211";
+
+/// Memcached can't list its keys, so everything except scanning is tested.
+#[test]
+async fn test_memcached() {
+    let store: Cuttlestore<String> = Cuttlestore::new(MEMCACHED).await.unwrap();
+
+    get_missing(&store).await;
+    get_then_delete(&store).await;
+    overwrite(&store).await;
+    timeout(&store).await;
+}
+
+#[test]
+async fn test_memcached_scan_unsupported() {
+    let store: Cuttlestore<String> = Cuttlestore::new(MEMCACHED).await.unwrap();
+    assert!(store.scan().await.is_err());
+}
+
+#[test]
+async fn test_memcached_awkward_keys() {
+    let store: Cuttlestore<String> = Cuttlestore::new(MEMCACHED).await.unwrap();
+    let long = "k".repeat(300);
+    for key in ["with spaces", "new\nline", "100%", "~tilde", long.as_str()] {
+        store.put(key, &key.to_string()).await.unwrap();
+        assert_eq!(store.get(key).await.unwrap().unwrap(), key);
+        store.delete(key).await.unwrap();
+        assert!(store.get(key).await.unwrap().is_none());
+    }
+}
+
+#[test]
+async fn test_memcached_long_ttl() {
+    let store: Cuttlestore<String> = Cuttlestore::new(MEMCACHED).await.unwrap();
+    // Longer than 30 days, which memcached needs as an absolute time
+    store
+        .put_with(
+            "long-ttl",
+            &"value".to_string(),
+            PutOptions::ttl_secs(60 * 60 * 24 * 60),
+        )
+        .await
+        .unwrap();
+    assert_eq!(store.get("long-ttl").await.unwrap().unwrap(), "value");
+    store.delete("long-ttl").await.unwrap();
+}
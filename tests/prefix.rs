@@ -30,3 +30,27 @@ async fn test_in_memory() {
         &("bar".to_string(), "baz".to_string())
     );
 }
+
+#[test]
+async fn test_count_and_clear() {
+    let connection = CuttlestoreBuilder::new("in-memory")
+        .finish_connection()
+        .await
+        .unwrap();
+
+    let store: Cuttlestore<String> = connection.make("counted").await.unwrap();
+    let other: Cuttlestore<String> = connection.make("other").await.unwrap();
+
+    for i in 0..5 {
+        store.put(format!("key{i}"), &i.to_string()).await.unwrap();
+    }
+    other.put("key", &"other".to_string()).await.unwrap();
+
+    assert_eq!(store.count().await.unwrap(), 5);
+    assert_eq!(other.count().await.unwrap(), 1);
+
+    store.clear().await.unwrap();
+    assert_eq!(store.count().await.unwrap(), 0);
+    assert!(store.get("key0").await.unwrap().is_none());
+    assert_eq!(other.get("key").await.unwrap().unwrap(), "other");
+}
@@ -0,0 +1,84 @@
+mod tests;
+use tests::suite;
+
+use cuttlestore::{Cuttlestore, CuttlestoreBuilder, PutOptions};
+use futures::StreamExt;
+use tokio::{fs, test};
+
+#[test]
+async fn test_redb() {
+    fs::remove_dir_all("./example-store/redb-test").await.ok();
+
+    let store: Cuttlestore<String> =
+        Cuttlestore::new("redb://./example-store/redb-test/store.redb")
+            .await
+            .unwrap();
+
+    suite(&store).await;
+
+    fs::remove_dir_all("./example-store/redb-test").await.ok();
+}
+
+#[test]
+async fn test_redb_prefix_scan() {
+    let folder = "./example-store/redb-prefix-test";
+    fs::remove_dir_all(folder).await.ok();
+
+    let connection = CuttlestoreBuilder::new(format!("redb://{folder}/store.redb"))
+        .finish_connection()
+        .await
+        .unwrap();
+    let first: Cuttlestore<String> = connection.make("first").await.unwrap();
+    let second: Cuttlestore<String> = connection.make("second").await.unwrap();
+    for key in ["c", "a", "b"] {
+        first.put(key, &format!("first {key}")).await.unwrap();
+        second.put(key, &format!("second {key}")).await.unwrap();
+    }
+
+    let pairs: Vec<(String, String)> = first
+        .scan()
+        .await
+        .unwrap()
+        .map(|pair| pair.unwrap())
+        .collect()
+        .await;
+    assert_eq!(
+        pairs,
+        vec![
+            ("a".to_string(), "first a".to_string()),
+            ("b".to_string(), "first b".to_string()),
+            ("c".to_string(), "first c".to_string()),
+        ]
+    );
+
+    fs::remove_dir_all(folder).await.ok();
+}
+
+#[test]
+async fn test_redb_cleaner() {
+    let folder = "./example-store/redb-cleaner-test";
+    fs::remove_dir_all(folder).await.ok();
+
+    let store: Cuttlestore<String> = CuttlestoreBuilder::new(format!("redb://{folder}/store.redb"))
+        .clean_every_secs(2)
+        .finish()
+        .await
+        .unwrap();
+    store
+        .put_with("short", &"lived".to_string(), PutOptions::ttl_secs(1))
+        .await
+        .unwrap();
+    store.put("kept", &"value".to_string()).await.unwrap();
+
+    tokio::time::sleep(std::time::Duration::from_secs(4)).await;
+    let keys: Vec<String> = store
+        .scan()
+        .await
+        .unwrap()
+        .map(|pair| pair.unwrap().0)
+        .collect()
+        .await;
+    assert_eq!(keys, vec!["kept".to_string()]);
+
+    fs::remove_dir_all(folder).await.ok();
+}
@@ -0,0 +1,16 @@
+mod tests;
+use tests::suite;
+
+use cuttlestore::Cuttlestore;
+use tokio::test;
+
+#[test]
+async fn test_redis_cluster() {
+    let store: Cuttlestore<String> =
+        Cuttlestore::new("redis+cluster://127.0.0.1:7000,127.0.0.1:7001,127.0.0.1:7002")
+            .await
+            .unwrap();
+
+    // The suite scans for keys it put in, which are spread over the masters
+    suite(&store).await;
+}
@@ -0,0 +1,21 @@
+mod tests;
+use tests::suite;
+
+use cuttlestore::Cuttlestore;
+use tokio::test;
+
+#[test]
+async fn test_redis_sentinel() {
+    let store: Cuttlestore<String> = Cuttlestore::new("redis+sentinel://127.0.0.1:26379/mymaster")
+        .await
+        .unwrap();
+
+    suite(&store).await;
+}
+
+#[test]
+async fn test_redis_sentinel_requires_master_name() {
+    let result: Result<Cuttlestore<String>, _> =
+        Cuttlestore::new("redis+sentinel://127.0.0.1:26379").await;
+    assert!(result.is_err());
+}
@@ -1,7 +1,8 @@
 mod tests;
-use tests::suite;
+use tests::{coordinated_cleaners, suite};
 
-use cuttlestore::Cuttlestore;
+use cuttlestore::{Cuttlestore, CuttlestoreBuilder};
+use futures::StreamExt;
 use tokio::test;
 
 #[test]
@@ -10,3 +11,175 @@ async fn test_redis() {
 
     suite(&store).await;
 }
+
+#[test]
+async fn test_redis_small_scan_batches() {
+    let store: Cuttlestore<String> = Cuttlestore::new("redis://127.0.0.1?scan_count=2")
+        .await
+        .unwrap();
+
+    suite(&store).await;
+}
+
+#[test]
+async fn test_redis_invalid_scan_count() {
+    let result: Result<Cuttlestore<String>, _> =
+        Cuttlestore::new("redis://127.0.0.1?scan_count=0").await;
+    assert!(result.is_err());
+}
+
+#[test]
+async fn test_redis_prefix_scan() {
+    let connection = CuttlestoreBuilder::new("redis://127.0.0.1?scan_count=3")
+        .prefix(nanoid::nanoid!())
+        .finish_connection()
+        .await
+        .unwrap();
+    // Glob characters in the prefix must not match other stores
+    let store: Cuttlestore<String> = connection.make("st*re").await.unwrap();
+    let other: Cuttlestore<String> = connection.make("store").await.unwrap();
+
+    for i in 0..10 {
+        store.put(format!("key{i}"), &i.to_string()).await.unwrap();
+    }
+    other.put("key", &"other".to_string()).await.unwrap();
+
+    let mut keys = store
+        .scan()
+        .await
+        .unwrap()
+        .map(|pair| pair.unwrap().0)
+        .collect::<Vec<_>>()
+        .await;
+    // A scan may return a key twice, but every key must be there
+    keys.sort();
+    keys.dedup();
+    let mut expected = (0..10).map(|i| format!("key{i}")).collect::<Vec<_>>();
+    expected.sort();
+    assert_eq!(keys, expected);
+}
+
+async fn count_then_clear(conn: &str) {
+    let connection = CuttlestoreBuilder::new(conn)
+        .prefix(nanoid::nanoid!())
+        .finish_connection()
+        .await
+        .unwrap();
+    let store: Cuttlestore<String> = connection.make("counted").await.unwrap();
+    let other: Cuttlestore<String> = connection.make("other").await.unwrap();
+
+    for i in 0..5 {
+        store.put(format!("key{i}"), &i.to_string()).await.unwrap();
+    }
+    other.put("key", &"other".to_string()).await.unwrap();
+    assert_eq!(store.count().await.unwrap(), 5);
+
+    store.clear().await.unwrap();
+    assert_eq!(store.count().await.unwrap(), 0);
+    assert!(store.get("key0").await.unwrap().is_none());
+    assert_eq!(other.count().await.unwrap(), 1);
+}
+
+#[test]
+async fn test_redis_count_and_clear() {
+    count_then_clear("redis://127.0.0.1").await;
+}
+
+#[test]
+async fn test_redis_hash_storage() {
+    let connection = CuttlestoreBuilder::new("redis://127.0.0.1?storage=hash")
+        .prefix(nanoid::nanoid!())
+        .finish_connection()
+        .await
+        .unwrap();
+    let store: Cuttlestore<String> = connection.make("hashed").await.unwrap();
+
+    suite(&store).await;
+}
+
+#[test]
+async fn test_redis_hash_storage_count_and_clear() {
+    count_then_clear("redis://127.0.0.1?storage=hash").await;
+}
+
+#[test]
+async fn test_redis_hash_storage_leaves_other_hashes() {
+    // A database of its own, since the whole database is cleared
+    let mut redis = redis::Client::open("redis://127.0.0.1/5")
+        .unwrap()
+        .get_connection()
+        .unwrap();
+    let () = redis::Commands::hset(&mut redis, "other", "field", "value").unwrap();
+
+    let conn = "redis://127.0.0.1?db=5&storage=hash";
+    let connection = CuttlestoreBuilder::new(conn)
+        .finish_connection()
+        .await
+        .unwrap();
+    let hashed: Cuttlestore<String> = connection.make("hashed").await.unwrap();
+    hashed.put("key", &"hashed".to_string()).await.unwrap();
+    let store: Cuttlestore<String> = Cuttlestore::new(conn).await.unwrap();
+    store.put("plain", &"plain".to_string()).await.unwrap();
+
+    let mut keys = store
+        .scan()
+        .await
+        .unwrap()
+        .map(|pair| pair.unwrap().0)
+        .collect::<Vec<_>>()
+        .await;
+    keys.sort();
+    assert_eq!(keys, vec![":hashed:key".to_string(), "plain".to_string()]);
+
+    store.clear().await.unwrap();
+    assert_eq!(store.count().await.unwrap(), 0);
+    assert_eq!(hashed.count().await.unwrap(), 0);
+    let other: Option<String> = redis::Commands::hget(&mut redis, "other", "field").unwrap();
+    assert_eq!(other.as_deref(), Some("value"));
+    let () = redis::Commands::del(&mut redis, "other").unwrap();
+}
+
+#[test]
+async fn test_redis_invalid_storage() {
+    let result: Result<Cuttlestore<String>, _> =
+        Cuttlestore::new("redis://127.0.0.1?storage=tree").await;
+    assert!(result.is_err());
+}
+
+#[test]
+async fn test_redis_connection_options() {
+    let store: Cuttlestore<String> = Cuttlestore::new(
+        "redis://127.0.0.1?db=2&pool_max_size=4&pool_min_idle=1&connect_timeout=2&command_timeout=2&client_name=cuttlestore-test",
+    )
+    .await
+    .unwrap();
+
+    suite(&store).await;
+}
+
+#[test]
+async fn test_redis_databases_are_separate() {
+    let db3: Cuttlestore<String> = Cuttlestore::new("redis://127.0.0.1?db=3").await.unwrap();
+    let db4: Cuttlestore<String> = Cuttlestore::new("redis://127.0.0.1?db=4").await.unwrap();
+
+    db3.put("separate", &"db3".to_string()).await.unwrap();
+    assert!(db4.get("separate").await.unwrap().is_none());
+    assert_eq!(db3.get("separate").await.unwrap().unwrap(), "db3");
+    db3.delete("separate").await.unwrap();
+}
+
+#[test]
+async fn test_redis_unknown_option() {
+    let result: Result<Cuttlestore<String>, _> =
+        Cuttlestore::new("redis://127.0.0.1?pool_size=4").await;
+    assert!(result.is_err());
+}
+
+#[test]
+async fn test_redis_coordinated_cleaners() {
+    let conn = "redis://127.0.0.1?db=6";
+    let store: Cuttlestore<String> = Cuttlestore::new(conn).await.unwrap();
+    store.clear().await.unwrap();
+
+    coordinated_cleaners(conn).await;
+}
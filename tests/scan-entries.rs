@@ -0,0 +1,61 @@
+use futures::StreamExt;
+
+use cuttlestore::{Cuttlestore, CuttlestoreBuilder, ScanEntry};
+use tokio::test;
+
+#[test]
+async fn test_scan_entries_undecodable() {
+    let connection = CuttlestoreBuilder::new("in-memory")
+        .finish_connection()
+        .await
+        .unwrap();
+
+    let store_int: Cuttlestore<i64> = connection.make("shared").await.unwrap();
+    let store_str: Cuttlestore<String> = connection.make("shared").await.unwrap();
+
+    store_str.put("good", &"baz".to_string()).await.unwrap();
+    store_int.put("bad", &32).await.unwrap();
+
+    // A regular scan fails on the value that can't be decoded
+    let pairs = store_str.scan().await.unwrap();
+    let pairs = pairs.collect::<Vec<_>>().await;
+    assert!(pairs.iter().any(|pair| pair.is_err()));
+
+    let entries = store_str.scan_entries().await.unwrap();
+    let entries = entries.map(|x| x.unwrap()).collect::<Vec<_>>().await;
+    assert_eq!(entries.len(), 2);
+    for (key, entry) in entries {
+        match (key.as_str(), entry) {
+            ("good", ScanEntry::Value(value)) => assert_eq!(value, "baz"),
+            ("bad", ScanEntry::Undecodable { raw, .. }) => assert!(!raw.is_empty()),
+            (key, entry) => panic!("Unexpected entry {key}: {entry:?}"),
+        }
+    }
+
+    // Without a quarantine, the undecodable value is left in place
+    assert_eq!(store_int.get("bad").await.unwrap(), Some(32));
+}
+
+#[test]
+async fn test_scan_entries_quarantine() {
+    let connection = CuttlestoreBuilder::new("in-memory")
+        .quarantine_undecodable("dead")
+        .finish_connection()
+        .await
+        .unwrap();
+
+    let store_int: Cuttlestore<i64> = connection.make("shared").await.unwrap();
+    let store_str: Cuttlestore<String> = connection.make("shared").await.unwrap();
+
+    store_int.put("bad", &32).await.unwrap();
+
+    let entries = store_str.scan_entries().await.unwrap();
+    let entries = entries.map(|x| x.unwrap()).collect::<Vec<_>>().await;
+    assert_eq!(entries.len(), 1);
+    assert!(matches!(entries[0], (_, ScanEntry::Undecodable { .. })));
+
+    // The undecodable value was moved out of the way
+    assert!(store_int.get("bad").await.unwrap().is_none());
+    let entries = store_str.scan_entries().await.unwrap();
+    assert_eq!(entries.collect::<Vec<_>>().await.len(), 0);
+}
@@ -1,7 +1,10 @@
 mod tests;
 use tests::suite;
 
-use cuttlestore::Cuttlestore;
+use std::time::Duration;
+
+use cuttlestore::{Cuttlestore, PutOptions};
+use futures::StreamExt;
 use tokio::{fs, test};
 
 #[test]
@@ -28,3 +31,73 @@ async fn test_sqlite() {
         .await
         .ok();
 }
+
+#[test]
+async fn test_sqlite_in_memory() {
+    let store: Cuttlestore<String> = Cuttlestore::new("sqlite://:memory:").await.unwrap();
+
+    suite(&store).await;
+}
+
+#[test]
+async fn test_sqlite_options() {
+    fs::remove_file("./example-store/sqlite-options-test")
+        .await
+        .ok();
+
+    let store: Cuttlestore<String> = Cuttlestore::new(
+        "sqlite://./example-store/sqlite-options-test?table=custom_table&journal_mode=delete&synchronous=full&busy_timeout=2&pool_max_size=2&shared_cache=false",
+    )
+    .await
+    .unwrap();
+
+    suite(&store).await;
+
+    fs::remove_file("./example-store/sqlite-options-test")
+        .await
+        .ok();
+}
+
+#[test]
+async fn test_sqlite_invalid_options() {
+    for conn in [
+        "sqlite://:memory:?tabel=custom",
+        "sqlite://:memory:?table=drop table",
+        "sqlite://:memory:?journal_mode=fast",
+        "sqlite://:memory:?pool_max_size=0",
+        // A path can't have a `?` in it, the rest is read as options
+        "sqlite://./example-store/sqlite?test",
+    ] {
+        let result: Result<Cuttlestore<String>, _> = Cuttlestore::new(conn).await;
+        assert!(result.is_err(), "{conn} should be rejected");
+    }
+}
+
+#[test]
+async fn test_sqlite_single_connection() {
+    // Scans delete the expired pairs they find, which needs the connection
+    // the scan itself is using
+    let store: Cuttlestore<String> = Cuttlestore::new("sqlite://:memory:?pool_max_size=1")
+        .await
+        .unwrap();
+    store.put("stays", &"value".to_string()).await.unwrap();
+    store
+        .put_with("gone", &"value".to_string(), PutOptions::ttl_secs(0))
+        .await
+        .unwrap();
+    // Values are only expired once the second they expire at has passed
+    tokio::time::sleep(Duration::from_millis(1100)).await;
+
+    let keys: Vec<String> = tokio::time::timeout(Duration::from_secs(5), async {
+        store
+            .scan()
+            .await
+            .unwrap()
+            .map(|pair| pair.unwrap().0)
+            .collect()
+            .await
+    })
+    .await
+    .expect("the scan should not wait for a connection");
+    assert_eq!(keys, ["stays"]);
+}
@@ -23,3 +23,123 @@ async fn test_surrealdb() {
 
     suite(&store).await;
 }
+
+#[cfg(feature = "backend-surrealdb-mem")]
+#[test]
+async fn test_surrealdb_in_memory() {
+    let store: Cuttlestore<String> = Cuttlestore::new("surrealdb+mem://cuttlestore/test")
+        .await
+        .unwrap();
+
+    suite(&store).await;
+}
+
+#[cfg(feature = "backend-surrealdb-mem")]
+#[test]
+async fn test_surrealdb_purge() {
+    use cuttlestore::{CuttlestoreBuilder, PutOptions};
+    use std::time::Duration;
+
+    let store: Cuttlestore<String> = CuttlestoreBuilder::new("surrealdb+mem://cuttlestore/test")
+        .clean_every_secs(60 * 60)
+        .finish()
+        .await
+        .unwrap();
+    for i in 0..3 {
+        store
+            .put_with(
+                format!("short{i}"),
+                &"gone".to_string(),
+                PutOptions::ttl_secs(0),
+            )
+            .await
+            .unwrap();
+    }
+    store.put("long", &"stays".to_string()).await.unwrap();
+    // Values are only expired once the second they expire at has passed
+    tokio::time::sleep(Duration::from_millis(1100)).await;
+
+    let cleaner = store.cleaner().unwrap();
+    cleaner.sweep_now();
+    for _ in 0..100 {
+        if cleaner.stats().sweeps > 0 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    assert_eq!(cleaner.stats().last_removed, 3);
+    assert_eq!(store.get("long").await.unwrap().unwrap(), "stays");
+}
+
+#[cfg(feature = "backend-surrealdb-mem")]
+#[test]
+async fn test_surrealdb_scan_pages() {
+    use cuttlestore::PutOptions;
+    use futures::StreamExt;
+    use std::time::Duration;
+
+    let store: Cuttlestore<String> = Cuttlestore::new("surrealdb+mem://cuttlestore/pages")
+        .await
+        .unwrap();
+    // The scan reads 500 records at a time, so this is three pages, with
+    // expired records mixed in that the scan leaves out
+    let pairs: Vec<(String, String)> = (0..1001)
+        .map(|i| (format!("key{i:04}"), i.to_string()))
+        .collect();
+    for (key, value) in &pairs {
+        store.put(key, value).await.unwrap();
+    }
+    for i in 0..20 {
+        store
+            .put_with(
+                format!("key{:04}-expired", i * 50),
+                &"gone".to_string(),
+                PutOptions::ttl_secs(0),
+            )
+            .await
+            .unwrap();
+    }
+    tokio::time::sleep(Duration::from_millis(1100)).await;
+
+    // Every value comes back exactly once
+    let mut scanned = store
+        .scan()
+        .await
+        .unwrap()
+        .map(|pair| pair.unwrap())
+        .collect::<Vec<_>>()
+        .await;
+    scanned.sort();
+    assert_eq!(scanned, pairs);
+}
+
+#[cfg(feature = "backend-surrealdb-mem")]
+#[test]
+async fn test_surrealdb_expire_on_write() {
+    let store: Cuttlestore<String> =
+        Cuttlestore::new("surrealdb+mem://cuttlestore/test?expire_on_write=true")
+            .await
+            .unwrap();
+    // SurrealDB removes the expired values, so there is no cleaner
+    assert!(store.cleaner().is_none());
+
+    suite(&store).await;
+}
+
+#[cfg(feature = "backend-surrealdb-surrealkv")]
+#[test]
+async fn test_surrealdb_surrealkv() {
+    tokio::fs::remove_dir_all("./example-store/surrealkv-test")
+        .await
+        .ok();
+    let store: Cuttlestore<String> =
+        Cuttlestore::new("surrealdb+surrealkv://./example-store/surrealkv-test/cuttlestore/test")
+            .await
+            .unwrap();
+
+    suite(&store).await;
+
+    tokio::fs::remove_dir_all("./example-store/surrealkv-test")
+        .await
+        .ok();
+}
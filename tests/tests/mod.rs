@@ -1,6 +1,6 @@
 use std::time::Duration;
 
-use cuttlestore::{Cuttlestore, PutOptions};
+use cuttlestore::{Cuttlestore, CuttlestoreBuilder, PutOptions};
 use futures::StreamExt;
 use nanoid::nanoid;
 
@@ -87,3 +87,40 @@ pub async fn suite(store: &Cuttlestore<String>) {
         scan(store),
     );
 }
+
+/// Two connections whose cleaners take turns, so only one of them sweeps. The
+/// lease they share must not show up in the store, or be removed by `clear`.
+#[allow(dead_code)]
+pub async fn coordinated_cleaners(conn: &str) {
+    let connect = || {
+        CuttlestoreBuilder::new(conn)
+            .clean_every(Duration::from_millis(200))
+            .clean_jitter(Duration::ZERO)
+            .coordinate_cleaning("cuttlestore-cleaner")
+            .finish_connection()
+    };
+    let first = connect().await.unwrap();
+    let second = connect().await.unwrap();
+    let first_cleaner = first.cleaner().unwrap();
+    let second_cleaner = second.cleaner().unwrap();
+    for _ in 0..100 {
+        if first_cleaner.stats().skipped + second_cleaner.stats().skipped >= 3 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    let follower = if first_cleaner.stats().holds_lease {
+        &second_cleaner
+    } else {
+        &first_cleaner
+    };
+    assert!(follower.stats().skipped >= 3);
+    assert_eq!(follower.stats().sweeps, 0);
+
+    let store: Cuttlestore<String> = Cuttlestore::new(conn).await.unwrap();
+    assert_eq!(store.count().await.unwrap(), 0);
+    assert_eq!(store.scan().await.unwrap().count().await, 0);
+    store.clear().await.unwrap();
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    assert_eq!(follower.stats().sweeps, 0);
+}